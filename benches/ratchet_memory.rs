@@ -0,0 +1,62 @@
+//! Shows a session's state stays the same size however many messages it has sent and recieved
+
+#[cfg(not(target_arch = "wasm32"))]
+use futures::executor::block_on;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+#[cfg(not(target_arch = "wasm32"))]
+use awake::handshake::{Handshake, ChallengeType};
+#[cfg(not(target_arch = "wasm32"))]
+use awake::session::Session;
+#[cfg(not(target_arch = "wasm32"))]
+use awake::transitable::Transitable;
+
+#[cfg(not(target_arch = "wasm32"))]
+const CHECKPOINTS: &[usize] = &[10, 100, 1000, 10000, 100000];
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn new_sessions() -> (Session, Session) {
+    let mut handshaker_requestor = Handshake::new().await.unwrap();
+    let mut handshaker_responder = Handshake::new().await.unwrap();
+    let request = handshaker_requestor.request_with(&[]).await.unwrap();
+    let response = handshaker_responder.reponse_with(request, &[], 60, ChallengeType::None, |_| Ok(true)).await.unwrap();
+    let challenge = handshaker_requestor.challenge_response_with(response, "", |_| Ok(true)).await.unwrap();
+    let ack = handshaker_responder.acknowledge_challenge_with(challenge, |_| Ok(true)).await.unwrap();
+    handshaker_requestor.accept_acknowledgement(ack).await.unwrap();
+    (handshaker_responder.into_session().unwrap(), handshaker_requestor.into_session().unwrap())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn state_size(session:&Session) -> usize {
+    serde_json::to_vec(session).unwrap().len()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    block_on(async {
+        let (mut sender, mut reciever) = new_sessions().await;
+        let start = Instant::now();
+        let mut sent = 0;
+        let mut baseline = None;
+        println!("{:>10} {:>14} {:>16} {:>10}", "messages", "sender bytes", "reciever bytes", "seconds");
+        for checkpoint in CHECKPOINTS {
+            while sent < *checkpoint {
+                let message = sender.send(Transitable::from_readable("benchmark payload")).await.unwrap();
+                reciever.receive(message).await.unwrap();
+                sent += 1;
+            }
+            let sizes = (state_size(&sender), state_size(&reciever));
+            println!("{:>10} {:>14} {:>16} {:>10.2}", sent, sizes.0, sizes.1, start.elapsed().as_secs_f64());
+            //from 100 messages on only the widths of the counters may change the size
+            if sent >= 100 {
+                let (sender_base, reciever_base) = *baseline.get_or_insert(sizes);
+                assert!(sizes.0.abs_diff(sender_base) < 256, "sender state grew from {} to {} bytes", sender_base, sizes.0);
+                assert!(sizes.1.abs_diff(reciever_base) < 256, "reciever state grew from {} to {} bytes", reciever_base, sizes.1);
+            }
+        }
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {}
@@ -0,0 +1,152 @@
+//! Regenerates the known answer vectors in tests/vectors.json, run with
+//! cargo run --features test-vectors --example gen_vectors > tests/vectors.json
+
+#[cfg(not(target_arch = "wasm32"))]
+use futures::executor::block_on;
+#[cfg(not(target_arch = "wasm32"))]
+use serde_json::{json, Value};
+
+#[cfg(not(target_arch = "wasm32"))]
+use awake::backend::{Backend, CryptoBackend, SharedSecret};
+#[cfg(not(target_arch = "wasm32"))]
+use awake::foreign_agent::{ForeignAgent, REQUESTOR_CHAIN_INFO, RESPONDER_CHAIN_INFO};
+#[cfg(not(target_arch = "wasm32"))]
+use awake::mid_window::{REQUESTOR_MID_INFO, RESPONDER_MID_INFO, derive_mid_key, mid_for};
+#[cfg(not(target_arch = "wasm32"))]
+use awake::ratchet::Ratchet;
+#[cfg(not(target_arch = "wasm32"))]
+use awake::transitable::Transitable;
+#[cfg(not(target_arch = "wasm32"))]
+use awake::utils::*;
+
+//the keys in the vectors come from this seed so they only change if the seed does
+#[cfg(not(target_arch = "wasm32"))]
+const SEED: u64 = 2023;
+#[cfg(not(target_arch = "wasm32"))]
+const PLAINTEXTS: &[&str] = &["first message", "second message", "third message"];
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn agent(crypto:&Backend) -> Value {
+    let (public_key, private_key) = gen_key_pair(crypto, true).await.unwrap();
+    json!({
+        "private": crypto.export_private_key(&private_key).await.unwrap(),
+        "public": base64::encode(crypto.export_public_key(&public_key).await.unwrap()),
+        "did": crypto_key_to_did_key(crypto, &public_key).await.unwrap()
+    })
+}
+
+//Each link of a chain is 76 bytes of hkdf, the info is the previous link's secret or first_info for the first link
+#[cfg(not(target_arch = "wasm32"))]
+async fn links(crypto:&Backend, secret:&SharedSecret, salt:&[u8], first_info:&[u8], count:usize) -> Vec<Value> {
+    let mut links = vec![];
+    let mut info = first_info.to_vec();
+    for _ in 0..count {
+        let key_data = crypto.hkdf(secret, salt, &info, 76).await.unwrap();
+        links.push(json!({
+            "secret": base64::encode(&key_data[..32]),
+            "aes_key": base64::encode(&key_data[32..64]),
+            "iv": base64::encode(&key_data[64..76])
+        }));
+        info = key_data[..32].to_vec();
+    }
+    links
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn build_vectors() -> Value {
+    let crypto = fetch_backend().unwrap();
+    Backend::seed_rng(SEED);
+    let requestor = agent(&crypto).await;
+    let responder = agent(&crypto).await;
+    Backend::unseed_rng();
+
+    let requestor_private = crypto.import_private_key(requestor["private"].as_str().unwrap()).await.unwrap();
+    let responder_private = crypto.import_private_key(responder["private"].as_str().unwrap()).await.unwrap();
+    let responder_public = did_key_to_crypto_key(&crypto, responder["did"].as_str().unwrap()).await.unwrap();
+    let shared_secret = diffie_helman(&crypto, &requestor_private, &responder_public).await.unwrap();
+
+    //the double ratchet's message chains have no salt or info, messages are sent with gaps so skipped links are covered as well
+    let chain = links(&crypto, &shared_secret, &[], &[], 5).await;
+    let mut ratchet = Ratchet::new(shared_secret.clone(), true, vec![]).await.unwrap();
+    let mut messages = vec![];
+    for (id, plaintext) in [(0, PLAINTEXTS[0]), (1, PLAINTEXTS[1]), (4, PLAINTEXTS[2])] {
+        let ciphertext = ratchet.process_payload(id, Transitable::from_readable(plaintext)).await.unwrap();
+        messages.push(json!({"id": id, "plaintext": plaintext, "ciphertext": ciphertext.as_base64()}));
+    }
+
+    let aad = "awake associated data";
+    let aes_key = base64::decode(chain[0]["aes_key"].as_str().unwrap()).unwrap();
+    let iv = base64::decode(chain[0]["iv"].as_str().unwrap()).unwrap();
+    let aes_ciphertext = crypto.encrypt(&aes_key, &iv, aad.as_bytes(), PLAINTEXTS[0].as_bytes()).await.unwrap();
+
+    //the step 2 chains are salted with the requestor's did and labelled with the direction they carry messages in
+    let handshake_salt = requestor["did"].as_str().unwrap().as_bytes().to_vec();
+    let requestor_chain = links(&crypto, &shared_secret, &handshake_salt, REQUESTOR_CHAIN_INFO, 2).await;
+    let responder_chain = links(&crypto, &shared_secret, &handshake_salt, RESPONDER_CHAIN_INFO, 2).await;
+
+    //each direction's mids are an hmac of the message index under a key of its own
+    let mut mids = json!({
+        "salt": base64::encode(&handshake_salt),
+        "requestor_info": base64::encode(REQUESTOR_MID_INFO),
+        "responder_info": base64::encode(RESPONDER_MID_INFO)
+    });
+    for (info, direction) in [(REQUESTOR_MID_INFO, "requestor"), (RESPONDER_MID_INFO, "responder")] {
+        let key = derive_mid_key(&shared_secret, &handshake_salt, info).await.unwrap();
+        let mut ids = vec![];
+        for id in [0, 1, 7] {
+            ids.push(json!({"id": id, "mid": mid_for(&key, id).await.unwrap()}));
+        }
+        mids[direction] = json!({"key": base64::encode(&key), "mids": ids});
+    }
+
+    //the first message of a handshake is the responder's step 2 agent encrypting its ucan for the requestor
+    let mut agent = ForeignAgent::new(&responder_private, requestor["did"].as_str().unwrap(), None).await.unwrap();
+    let (handshake_mid, handshake_ciphertext) = agent.encrypt_for(Transitable::from_readable(PLAINTEXTS[0]), &[]).await.unwrap();
+
+    //every handshake message is an ES256 compact jws and the ack names its signer in kid, p256 signs deterministically
+    let jws = Transitable::from_readable(PLAINTEXTS[1]).sign(&crypto, &requestor_private, Some(requestor["did"].as_str().unwrap())).await.unwrap();
+
+    json!({
+        "seed": SEED,
+        "requestor": requestor,
+        "responder": responder,
+        "ecdh": base64::encode(shared_secret.as_bytes()),
+        "mids": mids,
+        "ratchet": {
+            "salt": "",
+            "links": chain,
+            "messages": messages
+        },
+        "aes_gcm": {
+            "key": base64::encode(&aes_key),
+            "iv": base64::encode(&iv),
+            "aad": base64::encode(aad),
+            "plaintext": PLAINTEXTS[0],
+            "ciphertext": base64::encode(aes_ciphertext)
+        },
+        "jws": {
+            "payload": PLAINTEXTS[1],
+            "kid": requestor["did"],
+            "jws": jws.as_readable()
+        },
+        "handshake": {
+            "salt": base64::encode(&handshake_salt),
+            "requestor_info": base64::encode(REQUESTOR_CHAIN_INFO),
+            "responder_info": base64::encode(RESPONDER_CHAIN_INFO),
+            "requestor_links": requestor_chain,
+            "responder_links": responder_chain,
+            "plaintext": PLAINTEXTS[0],
+            "mid": handshake_mid,
+            "ciphertext": handshake_ciphertext.as_base64()
+        }
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    let vectors = block_on(build_vectors());
+    println!("{}", serde_json::to_string_pretty(&vectors).unwrap());
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {}
@@ -0,0 +1,74 @@
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+
+use crate::error::AwakeError;
+
+//The backend is picked by feature alone, webcrypto for the browser and native everywhere else
+#[cfg(feature = "webcrypto")]
+mod webcrypto;
+#[cfg(feature = "webcrypto")]
+pub use webcrypto::WebCryptoBackend as Backend;
+
+#[cfg(all(feature = "native", not(feature = "webcrypto")))]
+mod native;
+#[cfg(all(feature = "native", not(feature = "webcrypto")))]
+pub use native::NativeBackend as Backend;
+
+#[cfg(not(any(feature = "webcrypto", feature = "native")))]
+compile_error!("awake needs a crypto backend, turn on the native feature or, when building for wasm32, the webcrypto feature");
+#[cfg(all(feature = "webcrypto", not(target_arch = "wasm32")))]
+compile_error!("the webcrypto feature only works when building for wasm32, use the native feature instead");
+
+pub type PublicKey = <Backend as CryptoBackend>::PublicKey;
+pub type PrivateKey = <Backend as CryptoBackend>::PrivateKey;
+
+//Futures only need to be Send outside of the browser, the same way ucan's KeyMaterial works
+#[cfg(not(target_arch = "wasm32"))]
+pub trait ConditionalSendSync: Send + Sync {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send + Sync> ConditionalSendSync for T {}
+#[cfg(target_arch = "wasm32")]
+pub trait ConditionalSendSync {}
+#[cfg(target_arch = "wasm32")]
+impl<T> ConditionalSendSync for T {}
+
+//The raw bits agreed on by an ECDH exchange. Kept as bytes so every backend can feed it into HKDF
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SharedSecret(Vec<u8>);
+impl SharedSecret {
+    pub fn from_bytes(bytes:&[u8]) -> SharedSecret {
+        return SharedSecret(bytes.to_vec());
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        return &self.0;
+    }
+}
+
+//Every primitive AWAKE needs. Keys are P-256, signatures are ECDSA with SHA-256 (ES256), hashes are SHA-256,
+//key derivation is HKDF-SHA256, message ids are HMAC-SHA256 and payloads are encrypted with AES-256-GCM
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+pub trait CryptoBackend: Sized + Clone + ConditionalSendSync {
+    type PublicKey: Clone + ConditionalSendSync;
+    type PrivateKey: Clone + ConditionalSendSync;
+
+    fn fetch() -> Result<Self, AwakeError>;
+    async fn gen_key_pair(&self, is_extractable:bool) -> Result<(Self::PublicKey, Self::PrivateKey), AwakeError>;
+    async fn diffie_helman(&self, private_key:&Self::PrivateKey, public_key:&Self::PublicKey) -> Result<SharedSecret, AwakeError>;
+    async fn sign(&self, private_key:&Self::PrivateKey, data:&[u8]) -> Result<Vec<u8>, AwakeError>;
+    async fn verify(&self, public_key:&Self::PublicKey, data:&[u8], signature:&[u8]) -> Result<bool, AwakeError>;
+    //ECDSA with SHA-512, how transitables were signed before they were compact JWS. Only used when those are accepted
+    async fn verify_legacy(&self, public_key:&Self::PublicKey, data:&[u8], signature:&[u8]) -> Result<bool, AwakeError>;
+    async fn hash(&self, data:&[u8]) -> Result<Vec<u8>, AwakeError>;
+    async fn hkdf(&self, secret:&SharedSecret, salt:&[u8], info:&[u8], length:usize) -> Result<Vec<u8>, AwakeError>;
+    async fn hmac(&self, key:&[u8], data:&[u8]) -> Result<Vec<u8>, AwakeError>;
+    //AES-GCM, aad is authenticated along with the payload but not encrypted. It can be empty
+    async fn encrypt(&self, key:&[u8], iv:&[u8], aad:&[u8], payload:&[u8]) -> Result<Vec<u8>, AwakeError>;
+    async fn decrypt(&self, key:&[u8], iv:&[u8], aad:&[u8], payload:&[u8]) -> Result<Vec<u8>, AwakeError>;
+    //public keys are exported as uncompressed points and imported from compressed or uncompressed ones
+    async fn import_public_key(&self, key_data:&[u8]) -> Result<Self::PublicKey, AwakeError>;
+    async fn export_public_key(&self, public_key:&Self::PublicKey) -> Result<Vec<u8>, AwakeError>;
+    //private keys are imported and exported as jwk strings so a handshake can be saved, they must be extractable
+    async fn import_private_key(&self, jwk:&str) -> Result<Self::PrivateKey, AwakeError>;
+    async fn export_private_key(&self, private_key:&Self::PrivateKey) -> Result<String, AwakeError>;
+}
@@ -0,0 +1,156 @@
+use async_trait::async_trait;
+use p256::{PublicKey, SecretKey};
+use p256::ecdh::diffie_hellman;
+use p256::ecdsa::{SigningKey, VerifyingKey, Signature};
+use p256::ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use sha2::{Sha256, Sha512, Digest};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, Payload};
+use rand_core::OsRng;
+#[cfg(feature = "test-vectors")]
+use rand_core::SeedableRng;
+#[cfg(feature = "test-vectors")]
+use rand_chacha::ChaCha20Rng;
+#[cfg(feature = "test-vectors")]
+use std::cell::RefCell;
+
+use crate::backend::{CryptoBackend, SharedSecret};
+use crate::error::AwakeError;
+
+//Pure rust implementation using the RustCrypto crates so AWAKE can run outside of the browser
+#[derive(Clone)]
+pub struct NativeBackend;
+
+#[cfg(feature = "test-vectors")]
+thread_local! {
+    //when set keys come from here instead of the os so tests can be repeated exactly
+    static SEEDED_RNG: RefCell<Option<ChaCha20Rng>> = const { RefCell::new(None) };
+}
+//Only built with the test-vectors feature so a release build can never generate keys from a known seed
+#[cfg(feature = "test-vectors")]
+impl NativeBackend {
+    //Makes every key generated on this thread come from a seeded rng, only meant for tests
+    pub fn seed_rng(seed:u64){
+        SEEDED_RNG.with(|rng| *rng.borrow_mut() = Some(ChaCha20Rng::seed_from_u64(seed)));
+    }
+    //Goes back to the os rng on this thread
+    pub fn unseed_rng(){
+        SEEDED_RNG.with(|rng| *rng.borrow_mut() = None);
+    }
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+impl CryptoBackend for NativeBackend {
+    type PublicKey = PublicKey;
+    type PrivateKey = SecretKey;
+
+    fn fetch() -> Result<NativeBackend, AwakeError> {
+        return Ok(NativeBackend);
+    }
+    async fn gen_key_pair(&self, _is_extractable:bool) -> Result<(PublicKey, SecretKey), AwakeError> {
+        let private_key = random_secret_key();
+        return Ok((private_key.public_key(), private_key));
+    }
+    async fn diffie_helman(&self, private_key:&SecretKey, public_key:&PublicKey) -> Result<SharedSecret, AwakeError> {
+        let shared_secret = diffie_hellman(private_key.to_nonzero_scalar(), public_key.as_affine());
+        return Ok(SharedSecret::from_bytes(shared_secret.raw_secret_bytes()));
+    }
+    async fn sign(&self, private_key:&SecretKey, data:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        let signing_key = SigningKey::from(private_key);
+        let signature:Signature = match signing_key.sign_prehash(&Sha256::digest(data)) {
+            Ok(x) => x,
+            Err(e) => return Err(AwakeError::Crypto(format!("could not sign: {}", e)))
+        };
+        return Ok(signature.to_bytes().to_vec());
+    }
+    async fn verify(&self, public_key:&PublicKey, data:&[u8], signature:&[u8]) -> Result<bool, AwakeError> {
+        return Ok(verify_prehash(public_key, &Sha256::digest(data), signature));
+    }
+    //WebCrypto signs P-256 keys with SHA-512 by truncating the digest, which is what verifying the prehash does
+    async fn verify_legacy(&self, public_key:&PublicKey, data:&[u8], signature:&[u8]) -> Result<bool, AwakeError> {
+        return Ok(verify_prehash(public_key, &Sha512::digest(data), signature));
+    }
+    async fn hash(&self, data:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        return Ok(Sha256::digest(data).to_vec());
+    }
+    async fn hkdf(&self, secret:&SharedSecret, salt:&[u8], info:&[u8], length:usize) -> Result<Vec<u8>, AwakeError> {
+        let mut key_data = vec![0; length];
+        return match Hkdf::<Sha256>::new(Some(salt), secret.as_bytes()).expand(info, &mut key_data) {
+            Ok(_) => Ok(key_data),
+            Err(e) => Err(AwakeError::Crypto(format!("could not derive bits: {}", e)))
+        };
+    }
+    async fn hmac(&self, key:&[u8], data:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        let mut mac = match <Hmac<Sha256> as Mac>::new_from_slice(key) {
+            Ok(x) => x,
+            Err(e) => return Err(AwakeError::Crypto(format!("could not make hmac key: {}", e)))
+        };
+        mac.update(data);
+        return Ok(mac.finalize().into_bytes().to_vec());
+    }
+    async fn encrypt(&self, key:&[u8], iv:&[u8], aad:&[u8], payload:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        let cipher = new_cipher(key, iv)?;
+        return match cipher.encrypt(Nonce::from_slice(iv), Payload{msg: payload, aad}) {
+            Ok(x) => Ok(x),
+            Err(_) => Err(AwakeError::Crypto("could not encrypt payload".to_string()))
+        };
+    }
+    async fn decrypt(&self, key:&[u8], iv:&[u8], aad:&[u8], payload:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        let cipher = new_cipher(key, iv)?;
+        return match cipher.decrypt(Nonce::from_slice(iv), Payload{msg: payload, aad}) {
+            Ok(x) => Ok(x),
+            Err(_) => Err(AwakeError::Crypto("could not decrypt payload".to_string()))
+        };
+    }
+    async fn import_public_key(&self, key_data:&[u8]) -> Result<PublicKey, AwakeError> {
+        return match PublicKey::from_sec1_bytes(key_data) {
+            Ok(x) => Ok(x),
+            Err(_) => Err(AwakeError::Crypto("public key is not a valid P-256 point".to_string()))
+        };
+    }
+    async fn export_public_key(&self, public_key:&PublicKey) -> Result<Vec<u8>, AwakeError> {
+        return Ok(public_key.to_encoded_point(false).as_bytes().to_vec());
+    }
+    async fn import_private_key(&self, jwk:&str) -> Result<SecretKey, AwakeError> {
+        return match SecretKey::from_jwk_str(jwk) {
+            Ok(x) => Ok(x),
+            Err(_) => Err(AwakeError::Crypto("private key is not a valid P-256 jwk".to_string()))
+        };
+    }
+    async fn export_private_key(&self, private_key:&SecretKey) -> Result<String, AwakeError> {
+        return Ok(private_key.to_jwk_string().to_string());
+    }
+}
+
+fn new_cipher(key:&[u8], iv:&[u8]) -> Result<Aes256Gcm, AwakeError> {
+    if iv.len() != 12 {
+        return Err(AwakeError::Crypto(format!("AES-GCM needs a 12 byte iv but got {} bytes", iv.len())));
+    }
+    return match Aes256Gcm::new_from_slice(key) {
+        Ok(x) => Ok(x),
+        Err(_) => Err(AwakeError::Crypto(format!("AES-256-GCM needs a 32 byte key but got {} bytes", key.len())))
+    };
+}
+fn verify_prehash(public_key:&PublicKey, digest:&[u8], signature:&[u8]) -> bool {
+    let signature = match Signature::from_slice(signature) {
+        Ok(x) => x,
+        Err(_) => return false
+    };
+    return VerifyingKey::from(public_key).verify_prehash(digest, &signature).is_ok();
+}
+
+#[cfg(feature = "test-vectors")]
+fn random_secret_key() -> SecretKey {
+    return SEEDED_RNG.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(seeded) => SecretKey::random(seeded),
+        None => SecretKey::random(&mut OsRng)
+    });
+}
+#[cfg(not(feature = "test-vectors"))]
+fn random_secret_key() -> SecretKey {
+    return SecretKey::random(&mut OsRng);
+}
@@ -0,0 +1,403 @@
+use async_trait::async_trait;
+use wasm_bindgen::{JsValue, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{SubtleCrypto, CryptoKey};
+use js_sys::{Object, Array, Uint8Array, ArrayBuffer, BigInt, JSON};
+
+use std::collections::HashMap;
+use std::slice::Iter;
+
+use crate::backend::{CryptoBackend, SharedSecret};
+use crate::error::AwakeError;
+
+//the P-256 field prime and the b of its curve equation y^2 = x^3 - 3x + b
+const P256_P:&str = "0xffffffff00000001000000000000000000000000ffffffffffffffffffffffff";
+const P256_B:&str = "0x5ac635d8aa3a93e7b3ebbd55769886bc651d06b0cc53b0f63bce3c3e27d2604b";
+
+//Uses the browser's SubtleCrypto api
+#[derive(Clone)]
+pub struct WebCryptoBackend {
+    crypto: SubtleCrypto
+}
+
+//WebCrypto keys only do the algorithm they were made for, so every private key is kept as an ECDH and an ECDSA copy.
+//Both are made together so neither has to be exported to make the other and they can stay non-extractable
+#[derive(Clone)]
+pub struct WebCryptoPrivateKey {
+    ecdh: CryptoKey,
+    ecdsa: CryptoKey
+}
+
+#[async_trait(?Send)]
+impl CryptoBackend for WebCryptoBackend {
+    type PublicKey = CryptoKey;
+    type PrivateKey = WebCryptoPrivateKey;
+
+    fn fetch() -> Result<WebCryptoBackend, AwakeError> {
+        let window = match web_sys::window() {
+            Some(x) => x,
+            None => return Err(AwakeError::WebCrypto("there is no window to get SubtleCrypto from".to_string()))
+        };
+        return Ok(WebCryptoBackend{crypto: window.crypto().map_err(web_crypto_error)?.subtle()});
+    }
+    //The pair is made extractable so its jwk can be imported as both copies, then only the copies are kept
+    async fn gen_key_pair(&self, is_extractable:bool) -> Result<(CryptoKey, WebCryptoPrivateKey), AwakeError> {
+        let algorithm = HashMap::from([
+            ("name".to_string(), JsValue::from_str("ECDH")),
+            ("namedCurve".to_string(), JsValue::from_str("P-256")),
+        ]);
+        let key_uses_array:Array = Array::new_with_length(2);
+        key_uses_array.set(0, JsValue::from("deriveBits"));
+        key_uses_array.set(1, JsValue::from("deriveKey"));
+        let js_algorithm = js_objectify(&algorithm)?;
+        let key_pair_promise = self.crypto.generate_key_with_object(&js_algorithm, true, &key_uses_array).map_err(web_crypto_error)?;
+        let key_pair_future = JsFuture::from(key_pair_promise);
+        let key_pair_object:Object = key_pair_future.await.map_err(web_crypto_error)?.dyn_into().map_err(web_crypto_error)?;
+        let key_pair_map = obj_to_hash_map(&key_pair_object)?;
+        let private_key:CryptoKey = key_pair_map["privateKey"].clone().dyn_into().map_err(web_crypto_error)?;
+        let jwk:Object = JsFuture::from(self.crypto.export_key("jwk", &private_key).map_err(web_crypto_error)?).await.map_err(web_crypto_error)?.dyn_into().map_err(web_crypto_error)?;
+        return Ok((
+            key_pair_map["publicKey"].clone().dyn_into().map_err(web_crypto_error)?,
+            self.import_jwk(&jwk, is_extractable).await?
+        ));
+    }
+    async fn diffie_helman(&self, private_key:&WebCryptoPrivateKey, public_key:&CryptoKey) -> Result<SharedSecret, AwakeError> {
+        let shared_secret_algorithm = HashMap::from([
+            ("name".to_string(), JsValue::from_str("ECDH")),
+            ("namedCurve".to_string(), JsValue::from_str("P-256")),
+            ("public".to_string(), JsValue::from(public_key))
+        ]);
+        let shared_secret_data_promise = self.crypto.derive_bits_with_object(
+            &js_objectify(&shared_secret_algorithm)?,
+            &private_key.ecdh,
+            256,
+        ).map_err(web_crypto_error)?;
+        let shared_secret_data = JsFuture::from(shared_secret_data_promise).await.map_err(web_crypto_error)?;
+        return Ok(SharedSecret::from_bytes(&Uint8Array::new(&shared_secret_data).to_vec()));
+    }
+    async fn sign(&self, private_key:&WebCryptoPrivateKey, data:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        let algorithm = HashMap::from([
+            ("name".to_string(), JsValue::from_str("ECDSA")),
+            ("hash".to_string(), JsValue::from_str("SHA-256")),
+        ]);
+        let signature_promise = self.crypto.sign_with_object_and_buffer_source(
+            &js_objectify(&algorithm)?,
+            &private_key.ecdsa,
+            &u8_iter_js_array(data.iter())
+        ).map_err(web_crypto_error)?;
+        let signature_js = JsFuture::from(signature_promise).await.map_err(web_crypto_error)?;
+        let signature_array = Uint8Array::new(&signature_js);
+        return Ok(signature_array.to_vec());
+    }
+    async fn verify(&self, public_key:&CryptoKey, data:&[u8], signature:&[u8]) -> Result<bool, AwakeError> {
+        return self.verify_with_hash(public_key, data, signature, "SHA-256").await;
+    }
+    async fn verify_legacy(&self, public_key:&CryptoKey, data:&[u8], signature:&[u8]) -> Result<bool, AwakeError> {
+        return self.verify_with_hash(public_key, data, signature, "SHA-512").await;
+    }
+    async fn hash(&self, data:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        let hash_promise = self.crypto.digest_with_str_and_buffer_source("SHA-256", &u8_iter_js_array(data.iter())).map_err(web_crypto_error)?;
+        return Ok(Uint8Array::new(&JsFuture::from(hash_promise).await.map_err(web_crypto_error)?).to_vec());
+    }
+    async fn hkdf(&self, secret:&SharedSecret, salt:&[u8], info:&[u8], length:usize) -> Result<Vec<u8>, AwakeError> {
+        let key_uses_array:Array = Array::new_with_length(2);
+        key_uses_array.set(0, JsValue::from("deriveBits"));
+        key_uses_array.set(1, JsValue::from("deriveKey"));
+        let secret_promise = self.crypto.import_key_with_str(
+            "raw",
+            &u8_iter_js_array(secret.as_bytes().iter()),
+            "HKDF",
+            false,
+            &key_uses_array
+        ).map_err(web_crypto_error)?;
+        let secret_key:CryptoKey = JsFuture::from(secret_promise).await.map_err(web_crypto_error)?.dyn_into().map_err(web_crypto_error)?;
+        let algorithm = HashMap::from([
+            ("name".to_string(), JsValue::from_str("HKDF")),
+            ("hash".to_string(), JsValue::from("SHA-256")),
+            ("salt".to_string(), JsValue::from(u8_iter_js_array(salt.iter()))),
+            ("info".to_string(), JsValue::from(u8_iter_js_array(info.iter())))
+        ]);
+        let key_data_promise = self.crypto.derive_bits_with_object(
+            &js_objectify(&algorithm)?,
+            &secret_key,
+            (length*8) as u32
+        ).map_err(web_crypto_error)?;
+        let key_data_js_value = JsFuture::from(key_data_promise).await.map_err(web_crypto_error)?;
+        let key_data_array_buffer:ArrayBuffer = key_data_js_value.dyn_into().map_err(web_crypto_error)?;
+        return Ok(Uint8Array::new(&key_data_array_buffer).to_vec());
+    }
+    async fn hmac(&self, key:&[u8], data:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        let algorithm = HashMap::from([
+            ("name".to_string(), JsValue::from_str("HMAC")),
+            ("hash".to_string(), JsValue::from_str("SHA-256")),
+        ]);
+        let key_uses_array:Array = Array::new_with_length(1);
+        key_uses_array.set(0, JsValue::from("sign"));
+        let key_promise = self.crypto.import_key_with_object(
+            "raw",
+            &u8_iter_js_array(key.iter()),
+            &js_objectify(&algorithm)?,
+            false,
+            &key_uses_array
+        ).map_err(web_crypto_error)?;
+        let hmac_key:CryptoKey = JsFuture::from(key_promise).await.map_err(web_crypto_error)?.dyn_into().map_err(web_crypto_error)?;
+        let mac_promise = self.crypto.sign_with_str_and_buffer_source("HMAC", &hmac_key, &u8_iter_js_array(data.iter())).map_err(web_crypto_error)?;
+        return Ok(Uint8Array::new(&JsFuture::from(mac_promise).await.map_err(web_crypto_error)?).to_vec());
+    }
+    async fn encrypt(&self, key:&[u8], iv:&[u8], aad:&[u8], payload:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        return self.aes_gcm(true, key, iv, aad, payload).await;
+    }
+    async fn decrypt(&self, key:&[u8], iv:&[u8], aad:&[u8], payload:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        return self.aes_gcm(false, key, iv, aad, payload).await;
+    }
+    async fn import_public_key(&self, key_data:&[u8]) -> Result<CryptoKey, AwakeError> {
+        let algorithm = HashMap::from([
+            ("name".to_string(), JsValue::from_str("ECDH")),
+            ("namedCurve".to_string(), JsValue::from_str("P-256")),
+        ]);
+        //not every browser can import a compressed point so it is decompressed here first
+        let key_data = match key_data.len() {
+            33 => decompress_point(key_data)?,
+            _ => key_data.to_vec()
+        };
+        let key_promise = self.crypto.import_key_with_object(
+            "raw",
+            &u8_iter_js_array(key_data.iter()),
+            &js_objectify(&algorithm)?,
+            true,
+            &Array::new_with_length(0)
+        ).map_err(web_crypto_error)?;
+        let key_js = JsFuture::from(key_promise).await.map_err(web_crypto_error)?;
+        return key_js.dyn_into().map_err(web_crypto_error);
+    }
+    async fn export_public_key(&self, public_key:&CryptoKey) -> Result<Vec<u8>, AwakeError> {
+        let key_data_promise = self.crypto.export_key("raw", public_key).map_err(web_crypto_error)?;
+        let key_data = Uint8Array::new(&JsFuture::from(key_data_promise).await.map_err(web_crypto_error)?);
+        return Ok(key_data.to_vec());
+    }
+    //Imported keys came from saved state so they stay extractable to be saved again
+    async fn import_private_key(&self, jwk:&str) -> Result<WebCryptoPrivateKey, AwakeError> {
+        let key_data:Object = JSON::parse(jwk).map_err(web_crypto_error)?.dyn_into().map_err(web_crypto_error)?;
+        return self.import_jwk(&key_data, true).await;
+    }
+    async fn export_private_key(&self, private_key:&WebCryptoPrivateKey) -> Result<String, AwakeError> {
+        let key_data_promise = self.crypto.export_key("jwk", &private_key.ecdh).map_err(web_crypto_error)?;
+        let key_data = JsFuture::from(key_data_promise).await.map_err(web_crypto_error)?;
+        return match JSON::stringify(&key_data).map_err(web_crypto_error)?.as_string() {
+            Some(x) => Ok(x),
+            None => Err(AwakeError::WebCrypto("exported jwk could not be read as a string".to_string()))
+        };
+    }
+}
+impl WebCryptoBackend {
+    async fn verify_with_hash(&self, public_key:&CryptoKey, data:&[u8], signature:&[u8], hash:&str) -> Result<bool, AwakeError> {
+        let ecdsa_key = self.get_ecdsa_key(public_key).await?;
+        let algorithm = HashMap::from([
+            ("name".to_string(), JsValue::from_str("ECDSA")),
+            ("hash".to_string(), JsValue::from_str(hash)),
+        ]);
+        let is_valid_future = self.crypto.verify_with_object_and_buffer_source_and_buffer_source(
+            &js_objectify(&algorithm)?,
+            &ecdsa_key,
+            &u8_iter_js_array(signature.iter()),
+            &u8_iter_js_array(data.iter())
+        ).map_err(web_crypto_error)?;
+        let is_valid_js = JsFuture::from(is_valid_future).await.map_err(web_crypto_error)?;
+        return match is_valid_js.as_bool() {
+            Some(x) => Ok(x),
+            None => Err(AwakeError::WebCrypto("verify did not return a boolean".to_string()))
+        };
+    }
+    async fn aes_gcm(&self, is_encrypting:bool, key:&[u8], iv:&[u8], aad:&[u8], payload:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        let algorithm = HashMap::from([
+            ("name".to_string(), JsValue::from_str("AES-GCM")),
+            ("iv".to_string(), JsValue::from(u8_iter_js_array(iv.iter()))),
+            ("additionalData".to_string(), JsValue::from(u8_iter_js_array(aad.iter()))),
+        ]);
+        let key_uses = Array::new_with_length(2);
+        key_uses.set(0, "encrypt".into());
+        key_uses.set(1, "decrypt".into());
+
+        let js_algoritm = js_objectify(&algorithm)?;
+        let aes_key_promise = self.crypto.import_key_with_str("raw", &u8_iter_js_array(key.iter()), "AES-GCM", false, &key_uses).map_err(web_crypto_error)?;
+        let aes_key:CryptoKey = JsFuture::from(aes_key_promise).await.map_err(web_crypto_error)?.dyn_into().map_err(web_crypto_error)?;
+        let payload_array = u8_iter_js_array(payload.iter());
+        let payload_promise = match is_encrypting {
+            true => self.crypto.encrypt_with_object_and_buffer_source(
+                &js_algoritm,
+                &aes_key,
+                &payload_array
+            ).map_err(web_crypto_error)?,
+            false => self.crypto.decrypt_with_object_and_buffer_source(
+                &js_algoritm,
+                &aes_key,
+                &payload_array
+            ).map_err(web_crypto_error)?
+        };
+        let payload_js = JsFuture::from(payload_promise).await.map_err(web_crypto_error)?;
+        return Ok(Uint8Array::new(&payload_js).to_vec());
+    }
+    //Makes the ECDH and ECDSA copies of a private key from its jwk
+    async fn import_jwk(&self, jwk:&Object, is_extractable:bool) -> Result<WebCryptoPrivateKey, AwakeError> {
+        let key_data_map = obj_to_hash_map(jwk)?;
+        let ecdh_uses:Array = Array::new_with_length(2);
+        ecdh_uses.set(0, JsValue::from("deriveBits"));
+        ecdh_uses.set(1, JsValue::from("deriveKey"));
+        let ecdsa_uses:Array = Array::new_with_length(1);
+        ecdsa_uses.set(0, JsValue::from("sign"));
+        return Ok(WebCryptoPrivateKey{
+            ecdh: self.import_ec_key(&key_data_map, "ECDH", &ecdh_uses, is_extractable).await?,
+            ecdsa: self.import_ec_key(&key_data_map, "ECDSA", &ecdsa_uses, is_extractable).await?
+        });
+    }
+    //WebCrypto public keys are made for ECDH so a copy has to be imported for ECDSA before verifying
+    async fn get_ecdsa_key(&self, ecdh_key:&CryptoKey) -> Result<CryptoKey, AwakeError>{
+        let key_uses_array:Array = Array::new_with_length(1);
+        key_uses_array.set(0, JsValue::from("verify"));
+        let key_data_promise = self.crypto.export_key("jwk", ecdh_key).map_err(web_crypto_error)?;
+        let key_data_jwk = JsFuture::from(key_data_promise).await.map_err(web_crypto_error)?;
+        let key_data_map = obj_to_hash_map(&key_data_jwk.dyn_into().map_err(web_crypto_error)?)?;
+        return self.import_ec_key(&key_data_map, "ECDSA", &key_uses_array, false).await;
+    }
+    async fn import_ec_key(&self, key_data_map:&HashMap<String, JsValue>, name:&str, key_uses_array:&Array, is_extractable:bool) -> Result<CryptoKey, AwakeError>{
+        let algorithm = HashMap::from([
+            ("name".to_string(), JsValue::from_str(name)),
+            ("namedCurve".to_string(), JsValue::from_str("P-256")),
+        ]);
+        //the jwk's own key_ops are for the algorithm it was exported from, alg is left out so it fits either
+        let key_data_map_override:HashMap<String, JsValue> = HashMap::from([
+            ("crv".to_string(), JsValue::from("P-256")),
+            ("ext".to_string(), JsValue::from(true)),
+            ("kty".to_string(), JsValue::from("EC")),
+            ("key_ops".to_string(), JsValue::from(key_uses_array))
+        ]);
+        let mut key_data_map_new = overwrite_hash_map(&key_data_map_override, key_data_map);
+        key_data_map_new.remove("alg");
+        let key_data = hash_map_to_object(key_data_map_new)?;
+
+        let key_promise = self.crypto.import_key_with_object(
+            "jwk",
+            &key_data,
+            &js_objectify(&algorithm)?,
+            is_extractable,
+            key_uses_array
+        ).map_err(web_crypto_error)?;
+        let key_js = JsFuture::from(key_promise).await.map_err(web_crypto_error)?;
+        return key_js.dyn_into().map_err(web_crypto_error);
+    }
+}
+
+//Works y out from x with the curve equation, p is 3 mod 4 so the square root is a power of (p + 1) / 4
+fn decompress_point(point:&[u8]) -> Result<Vec<u8>, AwakeError>{
+    if point[0] != 2 && point[0] != 3 {
+        return Err(AwakeError::BadEncoding("public key is not a compressed P-256 point".to_string()));
+    }
+    let p = big_int(P256_P)?;
+    let x = big_int(&format!("0x{}", to_hex(&point[1..])))?;
+    let rhs = ((&(&(&x * &x) * &x) - &(&BigInt::from(3) * &x)) + big_int(P256_B)?) % &p;
+    let rhs = (&rhs + &p) % &p;
+    let mut y = mod_pow(&rhs, &((&p + &BigInt::from(1)) / BigInt::from(4)), &p);
+    if (&y * &y) % &p != rhs {
+        return Err(AwakeError::BadEncoding("public key is not on the P-256 curve".to_string()));
+    }
+    if (&y & &BigInt::from(1)) != BigInt::from(point[0] & 1) {
+        y = &p - &y;
+    }
+    let y_hex = format!("{:0>64}", format!("{:x}", y));
+    let mut uncompressed = vec![4];
+    uncompressed.extend_from_slice(&point[1..]);
+    for i in 0..32 {
+        match u8::from_str_radix(&y_hex[2*i..2*i + 2], 16) {
+            Ok(x) => uncompressed.push(x),
+            Err(_) => return Err(AwakeError::BadEncoding("could not decompress public key".to_string()))
+        }
+    }
+    return Ok(uncompressed);
+}
+fn mod_pow(base:&BigInt, exponent:&BigInt, modulus:&BigInt) -> BigInt{
+    let zero = BigInt::from(0);
+    let one = BigInt::from(1);
+    let mut result = one.clone();
+    let mut base = base % modulus;
+    let mut exponent = exponent.clone();
+    while exponent > zero {
+        if (&exponent & &one) == one {
+            result = (&result * &base) % modulus;
+        }
+        base = (&base * &base) % modulus;
+        exponent = exponent >> one.clone();
+    }
+    return result;
+}
+fn big_int(value:&str) -> Result<BigInt, AwakeError>{
+    return match BigInt::new(&JsValue::from_str(value)) {
+        Ok(x) => Ok(x),
+        Err(_) => Err(AwakeError::BadEncoding(format!("{} could not be read as a number", value)))
+    };
+}
+fn to_hex(bytes:&[u8]) -> String{
+    return bytes.iter().map(|x| format!("{:02x}", x)).collect();
+}
+fn js_objectify(props:&HashMap<String, JsValue>) -> Result<Object, AwakeError>{
+    let obj_array = Array::new_with_length(props.len() as u32);
+    let mut i:u32 = 0;
+    for (prop, val) in props {
+        let pair = Array::new_with_length(2 as u32);
+        pair.set(0 as u32, JsValue::from(prop));
+        pair.set(1 as u32, val.clone());
+        obj_array.set(i, JsValue::from(pair));
+        i += 1;
+    }
+    return Object::from_entries(&obj_array).map_err(web_crypto_error);
+}
+fn overwrite_hash_map<K, V>(top: &HashMap<K, V>, bot: &HashMap<K, V>) -> HashMap<K, V>
+    where K: std::hash::Hash, K: std::cmp::Eq, K: Clone, V: Clone{
+    let mut out = top.clone();
+    for (key, value) in bot {
+        if !out.contains_key(key){
+            out.insert(key.clone(), value.clone());
+        }
+    }
+    return out;
+}
+fn u8_iter_js_array(bytes:Iter<u8>) -> Uint8Array{
+    let array = Uint8Array::new_with_length(bytes.len() as u32);
+    let mut i:u32 = 0;
+    for byte in bytes {
+        array.set_index(i, *byte);
+        i += 1;
+    }
+    return array;
+}
+fn obj_to_hash_map(obj:&Object) -> Result<HashMap<String, JsValue>, AwakeError>{
+    let keys = Object::keys(obj).to_vec();
+    let values = Object::values(obj).to_vec();
+
+    let mut i = 0;
+    let mut ret:HashMap<String, JsValue> = HashMap::new();
+    for value in values{
+        let key = match keys[i].as_string() {
+            Some(x) => x,
+            None => return Err(AwakeError::WebCrypto("object keys must be strings".to_string()))
+        };
+        ret.insert(key, value);
+        i += 1;
+    }
+    return Ok(ret);
+}
+fn hash_map_to_object(map: HashMap<String, JsValue>) -> Result<Object, AwakeError>{
+    let entries = Array::new_with_length(map.len() as u32);
+    let mut i:u32 = 0;
+    for (key, value) in map {
+        let entry = Array::new_with_length(2);
+        entry.set(0, JsValue::from(key));
+        entry.set(1, value);
+        entries.set(i, JsValue::from(entry));
+        i += 1;
+    }
+    return Object::from_entries(&entries).map_err(web_crypto_error);
+}
+//Rejected promises and failed casts from SubtleCrypto
+fn web_crypto_error<T:std::fmt::Debug>(error:T) -> AwakeError{
+    return AwakeError::WebCrypto(format!("{:?}", error));
+}
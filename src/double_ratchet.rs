@@ -0,0 +1,276 @@
+use serde::{Serialize, Deserialize};
+
+use crate::backend::{CryptoBackend, PublicKey, PrivateKey, SharedSecret};
+use crate::ratchet::{Ratchet, SkipLimits};
+use crate::transitable::Transitable;
+use crate::utils::{fetch_backend, gen_key_pair};
+use crate::error::AwakeError;
+
+//hkdf info for the root chain so its output can never be mistaken for a message chain's
+const ROOT_INFO:&[u8] = b"awake/root";
+//how many replaced recieving chains are kept around for their skipped keys
+const MAX_PREVIOUS_CHAINS:usize = 4;
+
+//Sent in front of every message so the reciever knows which chain and which link of it to decrypt with
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RatchetHeader {
+    pub dh: String, //the sender's current ratchet public key as base64
+    pub n: usize, //the index of the message in the sender's current chain
+    pub pn: usize, //how many messages the sender sent in its previous chain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pdh: Option<String> //only after accepting a rekey, the key of the chain on the old ratchet the pn messages were sent on
+}
+impl RatchetHeader {
+    //The header is written as json with a two byte length in front of the encrypted payload
+    pub fn seal(&self, encrypted:&Transitable) -> Result<Transitable, AwakeError> {
+        let header = match serde_json::to_vec(self) {
+            Ok(x) => x,
+            Err(e) => return Err(AwakeError::MalformedJson(e.to_string()))
+        };
+        let mut message = (header.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(&header);
+        message.extend_from_slice(encrypted.as_slice());
+        return Ok(Transitable::from_bytes(&message));
+    }
+    pub fn open(message:&Transitable) -> Result<(RatchetHeader, Transitable), AwakeError> {
+        let message = message.as_slice();
+        if message.len() < 2 {
+            return Err(AwakeError::BadEncoding("ratchet message is too short to have a header".to_string()));
+        }
+        let header_end = 2 + u16::from_be_bytes([message[0], message[1]]) as usize;
+        if message.len() < header_end {
+            return Err(AwakeError::BadEncoding("ratchet message is shorter than its header".to_string()));
+        }
+        let header = match serde_json::from_slice(&message[2..header_end]) {
+            Ok(x) => x,
+            Err(e) => return Err(AwakeError::MalformedJson(e.to_string()))
+        };
+        return Ok((header, Transitable::from_bytes(&message[header_end..])));
+    }
+    //The header is authenticated along with whatever the caller wants bound to the message
+    fn aad(&self, aad:&[u8]) -> Result<Vec<u8>, AwakeError> {
+        let mut bound = aad.to_vec();
+        match serde_json::to_vec(self) {
+            Ok(x) => bound.extend_from_slice(&x),
+            Err(e) => return Err(AwakeError::MalformedJson(e.to_string()))
+        };
+        return Ok(bound);
+    }
+}
+
+//Signal style double ratchet. Every time the other agent replies with a new ratchet key both sides do a
+//diffie helman step that feeds the root chain, so a leaked chain key stops being useful after the next reply
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DoubleRatchet {
+    root_key: SharedSecret,
+    dh_public: Vec<u8>, //our current ratchet key, empty until the reciever has done its first step
+    dh_private: String, //kept as a jwk so the ratchet can be saved
+    remote_dh: Option<Vec<u8>>,
+    send_chain: Option<Ratchet>,
+    send_n: usize,
+    previous_send_n: usize,
+    recieve_chain: Option<Ratchet>,
+    previous_chains: Vec<(String, Ratchet)>, //retired recieving chains by ratchet key, oldest first
+    limits: SkipLimits,
+    #[serde(default)]
+    previous_dh: Option<Vec<u8>>, //our last ratchet key on the ratchet this one replaced, until the next dh step
+    #[serde(default)]
+    retired: bool //a retired ratchet never steps to a new key of the other agent's
+}
+impl DoubleRatchet {
+    //The agent that sends first already knows the other agent's ratchet key so it can start a sending chain straight away
+    pub async fn new_sender(shared_secret:SharedSecret, remote_public:&PublicKey) -> Result<DoubleRatchet, AwakeError> {
+        let crypto = fetch_backend()?;
+        let (dh_public, dh_private) = gen_key_pair(&crypto, true).await?;
+        return DoubleRatchet::new_sender_with_key(shared_secret, remote_public, &dh_public, &dh_private).await;
+    }
+    //Same as new_sender but with a ratchet key the other agent has already been told about
+    pub async fn new_sender_with_key(shared_secret:SharedSecret, remote_public:&PublicKey, dh_public:&PublicKey, dh_private:&PrivateKey) -> Result<DoubleRatchet, AwakeError> {
+        let crypto = fetch_backend()?;
+        let dh_output = crypto.diffie_helman(dh_private, remote_public).await?;
+        let (root_key, send_key) = root_step(&shared_secret, &dh_output).await?;
+        return Ok(DoubleRatchet{
+            root_key,
+            dh_public: crypto.export_public_key(dh_public).await?,
+            dh_private: crypto.export_private_key(dh_private).await?,
+            remote_dh: Some(crypto.export_public_key(remote_public).await?),
+            send_chain: Some(Ratchet::new(send_key, true, vec![]).await?),
+            send_n: 0,
+            previous_send_n: 0,
+            recieve_chain: None,
+            previous_chains: vec![],
+            limits: SkipLimits::default(),
+            previous_dh: None,
+            retired: false
+        });
+    }
+    //The other agent starts with the private half of the key the sender used and can not send until it has recieved
+    pub async fn new_reciever(shared_secret:SharedSecret, private_key:&PrivateKey) -> Result<DoubleRatchet, AwakeError> {
+        let crypto = fetch_backend()?;
+        return Ok(DoubleRatchet{
+            root_key: shared_secret,
+            dh_public: vec![],
+            dh_private: crypto.export_private_key(private_key).await?,
+            remote_dh: None,
+            send_chain: None,
+            send_n: 0,
+            previous_send_n: 0,
+            recieve_chain: None,
+            previous_chains: vec![],
+            limits: SkipLimits::default(),
+            previous_dh: None,
+            retired: false
+        });
+    }
+    //Lets a reciever send straight away when it already knows the sender's ratchet key
+    pub async fn accept(&mut self, remote_public:&PublicKey) -> Result<(), AwakeError> {
+        let remote_dh = fetch_backend()?.export_public_key(remote_public).await?;
+        return self.dh_step(remote_dh, 0).await;
+    }
+    //Stops the ratchet making any new keys, only keys kept for late messages can still be used
+    pub fn retire(&mut self){
+        self.retired = true;
+        self.send_chain = None;
+        if let Some(chain) = &mut self.recieve_chain {
+            chain.retire();
+        }
+    }
+    //Retires the ratchet once the first message from the other agent on the one that replaced it has arrived.
+    //Its header says which chain the other agent last sent on here and how long it got, the keys of any of those
+    //messages that have not arrived are kept
+    pub async fn retire_after(&mut self, header:&RatchetHeader){
+        //a header that can not be followed only costs the keys of late messages, the ratchet is retired all the same
+        let _ = self.keep_last_chain(header).await;
+        self.retire();
+    }
+    async fn keep_last_chain(&mut self, header:&RatchetHeader) -> Result<(), AwakeError>{
+        let pdh = match &header.pdh {
+            Some(x) => x,
+            None => return Ok(())
+        };
+        let remote_dh = match base64::decode(pdh) {
+            Ok(x) => x,
+            Err(e) => return Err(AwakeError::BadEncoding(format!("ratchet key is not valid base64: {}", e)))
+        };
+        //the other agent may have stepped to a key we have not had a message under yet
+        if self.remote_dh.as_ref() != Some(&remote_dh) && !self.previous_chains.iter().any(|(dh, _)| dh == pdh) {
+            self.dh_step(remote_dh.clone(), 0).await?;
+        }
+        if self.remote_dh.as_ref() != Some(&remote_dh) {
+            return Ok(());
+        }
+        return match &mut self.recieve_chain {
+            Some(chain) => chain.skip_to(header.pn).await,
+            None => Ok(())
+        };
+    }
+    //A ratchet started by accepting a rekey says in its first headers how far the old one got, like after a dh step
+    pub fn follow(&mut self, previous:&DoubleRatchet){
+        self.previous_send_n = previous.send_n;
+        if !previous.dh_public.is_empty() {
+            self.previous_dh = Some(previous.dh_public.clone());
+        }
+    }
+    pub fn limits(&self) -> SkipLimits{
+        return self.limits;
+    }
+    pub fn set_limits(&mut self, limits:SkipLimits){
+        self.limits = limits;
+        if let Some(chain) = &mut self.recieve_chain {
+            chain.set_limits(limits);
+        }
+        for (_, chain) in &mut self.previous_chains {
+            chain.set_limits(limits);
+        }
+    }
+    //The header the next message will be sent with, so anything that depends on it can be worked out before encrypting
+    pub fn next_header(&self) -> RatchetHeader {
+        return RatchetHeader{
+            dh: base64::encode(&self.dh_public),
+            n: self.send_n,
+            pn: self.previous_send_n,
+            pdh: self.previous_dh.as_ref().map(base64::encode)
+        };
+    }
+    pub async fn encrypt(&mut self, payload:Transitable, aad:&[u8]) -> Result<(RatchetHeader, Transitable), AwakeError> {
+        let header = self.next_header();
+        let aad = header.aad(aad)?;
+        let send_chain = match &mut self.send_chain {
+            Some(x) => x,
+            None => return Err(AwakeError::RatchetState("nothing can be sent until a message has been recieved".to_string()))
+        };
+        let encrypted = send_chain.process_payload_with_aad(self.send_n, &aad, payload).await?;
+        self.send_n += 1;
+        return Ok((header, encrypted));
+    }
+    pub async fn decrypt(&mut self, header:&RatchetHeader, payload:Transitable, aad:&[u8]) -> Result<Transitable, AwakeError> {
+        let aad = header.aad(aad)?;
+        let remote_dh = match base64::decode(&header.dh) {
+            Ok(x) => x,
+            Err(e) => return Err(AwakeError::BadEncoding(format!("ratchet key is not valid base64: {}", e)))
+        };
+        if self.remote_dh.as_ref() == Some(&remote_dh) {
+            return self.recieve(header.n, &aad, payload).await;
+        }
+        //a late message from a chain that has been replaced can only use a key that was kept when it was skipped
+        if let Some((_, chain)) = self.previous_chains.iter_mut().find(|(dh, _)| *dh == header.dh) {
+            return chain.process_payload_with_aad(header.n, &aad, payload).await;
+        }
+
+        if self.retired {
+            return Err(AwakeError::RatchetState("a retired ratchet can not step to a new key".to_string()));
+        }
+        //a new ratchet key is only trusted once a message under it decrypts, until then the step is made on a copy
+        let mut stepped = self.clone();
+        stepped.dh_step(remote_dh, header.pn).await?;
+        let decrypted = stepped.recieve(header.n, &aad, payload).await?;
+        *self = stepped;
+        return Ok(decrypted);
+    }
+    async fn recieve(&mut self, n:usize, aad:&[u8], payload:Transitable) -> Result<Transitable, AwakeError> {
+        return match &mut self.recieve_chain {
+            Some(x) => x.process_payload_with_aad(n, aad, payload).await,
+            None => Err(AwakeError::RatchetState("there is no recieving chain".to_string()))
+        };
+    }
+    //Mixes our current key with their new one for the recieving chain then does the same with a fresh key for the sending chain
+    async fn dh_step(&mut self, remote_dh:Vec<u8>, previous_n:usize) -> Result<(), AwakeError> {
+        //keep the keys for messages from the old chain that have not arrived yet
+        if let (Some(mut chain), Some(old_dh)) = (self.recieve_chain.take(), &self.remote_dh) {
+            chain.skip_to(previous_n).await?;
+            //retired chains are only a few bytes once their skipped keys are used and still catch replays
+            chain.retire();
+            self.previous_chains.push((base64::encode(old_dh), chain));
+            if self.previous_chains.len() > MAX_PREVIOUS_CHAINS {
+                self.previous_chains.remove(0);
+            }
+        }
+
+        let crypto = fetch_backend()?;
+        let remote_key = crypto.import_public_key(&remote_dh).await?;
+        let old_private = crypto.import_private_key(&self.dh_private).await?;
+        let (root_key, recieve_key) = root_step(&self.root_key, &crypto.diffie_helman(&old_private, &remote_key).await?).await?;
+
+        let (dh_public, dh_private) = gen_key_pair(&crypto, true).await?;
+        let (root_key, send_key) = root_step(&root_key, &crypto.diffie_helman(&dh_private, &remote_key).await?).await?;
+
+        self.root_key = root_key;
+        self.dh_public = crypto.export_public_key(&dh_public).await?;
+        self.dh_private = crypto.export_private_key(&dh_private).await?;
+        self.remote_dh = Some(remote_dh);
+        let mut recieve_chain = Ratchet::new(recieve_key, false, vec![]).await?;
+        recieve_chain.set_limits(self.limits);
+        self.recieve_chain = Some(recieve_chain);
+        self.send_chain = Some(Ratchet::new(send_key, true, vec![]).await?);
+        self.previous_send_n = self.send_n;
+        self.previous_dh = None;
+        self.send_n = 0;
+        return Ok(());
+    }
+}
+
+//32 bytes for the next root key and 32 for the chain key
+async fn root_step(root_key:&SharedSecret, dh_output:&SharedSecret) -> Result<(SharedSecret, SharedSecret), AwakeError> {
+    let key_data = fetch_backend()?.hkdf(dh_output, root_key.as_bytes(), ROOT_INFO, 64).await?;
+    return Ok((SharedSecret::from_bytes(&key_data[..32]), SharedSecret::from_bytes(&key_data[32..])));
+}
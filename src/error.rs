@@ -0,0 +1,135 @@
+use std::fmt;
+use wasm_bindgen::JsValue;
+use js_sys::Reflect;
+
+//Every way an AWAKE operation can fail. Each variant has a stable code so js callers can tell them apart
+#[derive(Debug, Clone, PartialEq)]
+pub enum AwakeError {
+    MalformedJson(String), //a message or payload could not be parsed as the expected json
+    BadJwt(String), //a signed transitable did not have the header.payload.signature shape
+    LegacyJwsRefused, //a transitable was signed in the old ES512 format and those are not being accepted
+    BadEncoding(String), //base64, base58 or utf8 data could not be decoded
+    BadDidKey(String), //a did could not be read as a Nist-256 did:key
+    UnsupportedDidKey(String), //a well formed did:key for a key type or encoding this agent does not support
+    BadCapability(String), //a capability was missing its with/can properties or they had the wrong types
+    SignatureFailure(String), //a signature did not verify against the expected key
+    CapabilityRejected, //the are_capabilities_valid callback rejected the requestor's capabilities
+    UcanRejected, //the is_ucan_valid callback rejected the responder's ucan
+    BadUcan(String), //the responder's ucan could not be parsed
+    Ucan(String), //a ucan of our own could not be built or signed
+    UcanSignatureFailure, //the responder's ucan was not signed by its issuer
+    UcanExpired, //the responder's ucan is past its exp time
+    UcanNotYetValid, //the responder's ucan is before its nbf time
+    UcanWrongAudience(String), //the responder's ucan was issued to a different did
+    MissingFact(String), //the responder's ucan is missing a fact the handshake needs
+    ResponseSignatureFailure, //the awake/res was not signed by the step 2 did it was sent from
+    PinRejected, //the is_pin_valid callback rejected the requestor's pin
+    ProofRejected(String), //the requestor's delegation chain was broken or rejected by the is_proof_valid callback
+    UnsupportedChallenge(String), //the responder asked for a challenge type this agent does not know
+    Callback(String), //a js callback threw or did not return the expected type
+    Replay(usize), //a message with this id has already been processed
+    UnknownMid(String), //no message key could be found for this mid
+    DecryptionFailure(usize), //the message with this id failed to decrypt or authenticate
+    RatchetState(String), //the ratchet was asked to do something its chain can no longer do
+    HandshakeComplete, //the handshake has already finished
+    HandshakeIncomplete, //the handshake has not reached the stage needed for this call
+    UnexpectedMessage(String), //a message of the wrong type was given for this stage
+    UnsupportedVersion(String), //a message was written with an awv this agent can not read
+    KeyConfirmationFailure, //the other agent's key confirmation did not match, the two sides saw different handshakes
+    BadState(String), //saved handshake state could not be unwrapped or read
+    Storage(String), //a session store could not read or write a session
+    WebCrypto(String), //the browser's SubtleCrypto api failed
+    Crypto(String) //the native crypto backend failed
+}
+impl AwakeError {
+    pub fn code(&self) -> &'static str {
+        return match self {
+            AwakeError::MalformedJson(_) => "MALFORMED_JSON",
+            AwakeError::BadJwt(_) => "BAD_JWT",
+            AwakeError::LegacyJwsRefused => "LEGACY_JWS_REFUSED",
+            AwakeError::BadEncoding(_) => "BAD_ENCODING",
+            AwakeError::BadDidKey(_) => "BAD_DID_KEY",
+            AwakeError::UnsupportedDidKey(_) => "UNSUPPORTED_DID_KEY",
+            AwakeError::BadCapability(_) => "BAD_CAPABILITY",
+            AwakeError::SignatureFailure(_) => "SIGNATURE_FAILURE",
+            AwakeError::CapabilityRejected => "CAPABILITY_REJECTED",
+            AwakeError::UcanRejected => "UCAN_REJECTED",
+            AwakeError::BadUcan(_) => "BAD_UCAN",
+            AwakeError::Ucan(_) => "UCAN",
+            AwakeError::UcanSignatureFailure => "UCAN_SIGNATURE_FAILURE",
+            AwakeError::UcanExpired => "UCAN_EXPIRED",
+            AwakeError::UcanNotYetValid => "UCAN_NOT_YET_VALID",
+            AwakeError::UcanWrongAudience(_) => "UCAN_WRONG_AUDIENCE",
+            AwakeError::MissingFact(_) => "MISSING_FACT",
+            AwakeError::ResponseSignatureFailure => "RESPONSE_SIGNATURE_FAILURE",
+            AwakeError::PinRejected => "PIN_REJECTED",
+            AwakeError::ProofRejected(_) => "PROOF_REJECTED",
+            AwakeError::UnsupportedChallenge(_) => "UNSUPPORTED_CHALLENGE",
+            AwakeError::Callback(_) => "CALLBACK_FAILED",
+            AwakeError::Replay(_) => "REPLAY",
+            AwakeError::UnknownMid(_) => "UNKNOWN_MID",
+            AwakeError::DecryptionFailure(_) => "DECRYPTION_FAILURE",
+            AwakeError::RatchetState(_) => "RATCHET_STATE",
+            AwakeError::HandshakeComplete => "HANDSHAKE_COMPLETE",
+            AwakeError::HandshakeIncomplete => "HANDSHAKE_INCOMPLETE",
+            AwakeError::UnexpectedMessage(_) => "UNEXPECTED_MESSAGE",
+            AwakeError::UnsupportedVersion(_) => "UNSUPPORTED_VERSION",
+            AwakeError::KeyConfirmationFailure => "KEY_CONFIRMATION_FAILURE",
+            AwakeError::BadState(_) => "BAD_STATE",
+            AwakeError::Storage(_) => "STORAGE",
+            AwakeError::WebCrypto(_) => "WEB_CRYPTO",
+            AwakeError::Crypto(_) => "CRYPTO"
+        };
+    }
+}
+impl fmt::Display for AwakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            AwakeError::MalformedJson(x) => write!(f, "message was not sent in the proper json format: {}", x),
+            AwakeError::BadJwt(x) => write!(f, "transitable is either not a Json Web Token or is improperly formatted: {}", x),
+            AwakeError::LegacyJwsRefused => write!(f, "legacy ES512 signatures are not accepted, see set_accept_legacy_jws"),
+            AwakeError::BadEncoding(x) => write!(f, "data could not be decoded: {}", x),
+            AwakeError::BadDidKey(x) => write!(f, "DID key is not Nist-256 or is improperly formatted: {}", x),
+            AwakeError::UnsupportedDidKey(x) => write!(f, "only P-256 did:keys are supported, {}", x),
+            AwakeError::BadCapability(x) => write!(f, "capability is improperly formatted: {}", x),
+            AwakeError::SignatureFailure(x) => write!(f, "failed to verify signature: {}", x),
+            AwakeError::CapabilityRejected => write!(f, "failed to verify sender's capabilities"),
+            AwakeError::UcanRejected => write!(f, "failed to verify sender's ucan"),
+            AwakeError::BadUcan(x) => write!(f, "ucan is improperly formatted: {}", x),
+            AwakeError::Ucan(x) => write!(f, "ucan could not be issued: {}", x),
+            AwakeError::UcanSignatureFailure => write!(f, "ucan was not signed by its issuer"),
+            AwakeError::UcanExpired => write!(f, "ucan has expired"),
+            AwakeError::UcanNotYetValid => write!(f, "ucan is not valid yet"),
+            AwakeError::UcanWrongAudience(x) => write!(f, "ucan was issued to {} rather than this agent", x),
+            AwakeError::MissingFact(x) => write!(f, "ucan did not contain the '{}' fact", x),
+            AwakeError::ResponseSignatureFailure => write!(f, "response was not signed by the did it was sent from"),
+            AwakeError::PinRejected => write!(f, "failed to verify sender's pin"),
+            AwakeError::ProofRejected(x) => write!(f, "failed to verify sender's delegation chain: {}", x),
+            AwakeError::UnsupportedChallenge(x) => write!(f, "the challenge '{}' is not supported", x),
+            AwakeError::Callback(x) => write!(f, "callback failed: {}", x),
+            AwakeError::Replay(x) => write!(f, "a message has already been proccessed with the id {}", x),
+            AwakeError::UnknownMid(x) => write!(f, "could not find a message key for the mid {}", x),
+            AwakeError::DecryptionFailure(x) => write!(f, "the message with the id {} could not be decrypted", x),
+            AwakeError::RatchetState(x) => write!(f, "ratchet error: {}", x),
+            AwakeError::HandshakeComplete => write!(f, "this awake object has already conducted a handshake. Please initialize a new awake object to conduct more conections."),
+            AwakeError::HandshakeIncomplete => write!(f, "the handshake has not reached the stage needed for this"),
+            AwakeError::UnexpectedMessage(x) => write!(f, "message was not expected at this stage: {}", x),
+            AwakeError::UnsupportedVersion(x) => write!(f, "the awake version {} is not supported", x),
+            AwakeError::KeyConfirmationFailure => write!(f, "the other agent did not derive the same session keys"),
+            AwakeError::BadState(x) => write!(f, "saved state could not be restored: {}", x),
+            AwakeError::Storage(x) => write!(f, "session store failed: {}", x),
+            AwakeError::WebCrypto(x) => write!(f, "web crypto failed: {}", x),
+            AwakeError::Crypto(x) => write!(f, "crypto failed: {}", x)
+        };
+    }
+}
+impl std::error::Error for AwakeError {}
+
+//Errors reach js as an Error object with a code property
+impl From<AwakeError> for JsValue {
+    fn from(error: AwakeError) -> JsValue {
+        let js_error = js_sys::Error::new(&error.to_string());
+        let _ = Reflect::set(&js_error, &JsValue::from("code"), &JsValue::from(error.code()));
+        return JsValue::from(js_error);
+    }
+}
@@ -1,103 +1,349 @@
-use web_sys::{CryptoKey};
+use serde::{Serialize, Deserialize};
 
-use wasm_bindgen_futures::JsFuture;
-use wasm_bindgen::JsCast;
-use wasm_bindgen::JsValue;
-use std::collections::HashMap;
-use web_sys::SubtleCrypto;
-use js_sys::Uint8Array;
-
-use crate::ratchet::Ratchet;
+use crate::backend::{CryptoBackend, PublicKey, PrivateKey, SharedSecret};
+use crate::ratchet::{Ratchet, SkipLimits};
+use crate::double_ratchet::{DoubleRatchet, RatchetHeader};
 use crate::transitable::Transitable;
-use crate::utils::{hash, diffie_helman, js_objectify, fetch_subtle_crypto, did_key_to_crypto_key, crypto_key_to_did_key};
+use crate::messages::{RekeyPayload, AWV, write_message, read_message, default_version};
+use crate::mid_window::{MidWindow, MID_WINDOW, HANDSHAKE_MID_WINDOW, REQUESTOR_MID_INFO, RESPONDER_MID_INFO, derive_mid_key, mid_for};
+use crate::utils::{hash, diffie_helman, fetch_backend, gen_key_pair, did_key_to_bytes, did_key_to_crypto_key, crypto_key_to_did_key};
+use crate::error::AwakeError;
 
-const MAX_MSGS: usize = 1000000;//One million should be enough
+//hkdf info for the secret a rekey starts the double ratchet from
+const REKEY_INFO:&[u8] = b"awake/rekey";
+//hkdf info for the session secret and key confirmation key a finalized agent starts with
+const SESSION_INFO:&[u8] = b"awake/session";
+//hmac labels so neither side's key confirmation can be sent back as the other's
+const REQUESTOR_CONFIRM_INFO:&[u8] = b"awake/confirm/requestor";
+const RESPONDER_CONFIRM_INFO:&[u8] = b"awake/confirm/responder";
+//hkdf info for the first link of each step 2 chain, so the two directions never share a key and iv
+pub const REQUESTOR_CHAIN_INFO:&[u8] = b"awake/chain/requestor";
+pub const RESPONDER_CHAIN_INFO:&[u8] = b"awake/chain/responder";
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ForeignAgent{
     pub did:String,
-    mid_prefix:Option<Vec<u8>>,
-    next_send_id:usize,
-    send_ratchet:Ratchet,
-    recieve_ratchet:Ratchet
+    #[serde(default)]
+    pub peer_did:Option<String>, //the other agent's real did once the handshake has revealed it, did is only a step 2 or 4 key
+    pub self_did:Option<String>, //the did we are known by once finalized
+    #[serde(default)]
+    pub handshake_did:Option<String>, //the other agent's step 2 did once finalized, an awake/fin it sends after the handshake is signed with it
+    #[serde(default = "default_version")]
+    pub awv:String, //the version agreed on during the handshake
+    is_requestor:bool,
+    next_send_id:usize, //the index of our next message, restarts when the agent is finalized
+    send_mid_key:Vec<u8>,
+    recieve_mids:MidWindow,
+    send_ratchet:Ratchet, //the step 2 chains only used during the handshake
+    recieve_ratchet:Ratchet,
+    double_ratchet:Option<DoubleRatchet>, //every message once the handshake is finalized
+    previous_ratchet:Option<DoubleRatchet>, //the ratchet before the last rekey, kept for messages sent before it
+    #[serde(default)]
+    previous_live:bool, //our rekey has not been answered on the new ratchet yet, until then the old one is not retired
+    #[serde(default)]
+    rekey_private:Option<String>, //the jwk of our step 4 key, never a long lived one, rekeys from the other agent are mixed with it
+    #[serde(default)]
+    rekey_did:Option<String>, //the other agent's step 4 did, our rekeys are mixed with it
+    session_secret:Option<SharedSecret>, //the secret the current double ratchet was started from
+    #[serde(default)]
+    transcript:Vec<u8>, //hash over every handshake message so far, the session keys are bound to it
+    #[serde(default)]
+    confirm_key:Option<Vec<u8>> //kept until the first message from the other agent has decrypted
 }
 impl ForeignAgent{
-    pub async fn new(private_key:&CryptoKey, forien_did:&str, requestor_public_key:Option<&CryptoKey>) -> ForeignAgent{
-        let crypto = fetch_subtle_crypto();
-        let salt = match requestor_public_key{
-            Some(salt_key) => crypto_key_to_did_key(&crypto, salt_key).await.as_bytes().to_vec(),
-            None => forien_did.as_bytes().to_vec()
+    pub async fn new(private_key:&PrivateKey, forien_did:&str, requestor_public_key:Option<&PublicKey>) -> Result<ForeignAgent, AwakeError>{
+        let crypto = fetch_backend()?;
+        //only the requestor passes its own key, the salt is always the requestor's did
+        let (salt, send_info, recieve_info) = match requestor_public_key{
+            Some(salt_key) => (crypto_key_to_did_key(&crypto, salt_key).await?.as_bytes().to_vec(), REQUESTOR_CHAIN_INFO, RESPONDER_CHAIN_INFO),
+            None => (forien_did.as_bytes().to_vec(), RESPONDER_CHAIN_INFO, REQUESTOR_CHAIN_INFO)
         };
-        let forien_key = did_key_to_crypto_key(&crypto, forien_did).await;
-        let shared_secret = diffie_helman(&crypto, private_key, &forien_key).await;
-        return ForeignAgent{
+        let is_requestor = requestor_public_key.is_some();
+        let (send_mid_info, recieve_mid_info) = mid_infos(is_requestor);
+        let forien_key = did_key_to_crypto_key(&crypto, forien_did).await?;
+        let shared_secret = diffie_helman(&crypto, private_key, &forien_key).await?;
+        return Ok(ForeignAgent{
+            is_requestor,
             next_send_id: 0,
+            send_mid_key: derive_mid_key(&shared_secret, &salt, send_mid_info).await?,
+            recieve_mids: MidWindow::new(derive_mid_key(&shared_secret, &salt, recieve_mid_info).await?, HANDSHAKE_MID_WINDOW).await?,
             did: forien_did.to_string(),
-            mid_prefix: None,
-            send_ratchet: Ratchet::new(shared_secret.clone(), true, salt.clone()).await,
-            recieve_ratchet: Ratchet::new(shared_secret, false, salt).await
-        }
+            peer_did: None,
+            self_did: None,
+            handshake_did: None,
+            awv: AWV.to_string(),
+            send_ratchet: Ratchet::with_info(shared_secret.clone(), true, salt.clone(), send_info).await?,
+            recieve_ratchet: Ratchet::with_info(shared_secret, false, salt, recieve_info).await?,
+            double_ratchet: None,
+            previous_ratchet: None,
+            previous_live: false,
+            rekey_private: None,
+            rekey_did: None,
+            session_secret: None,
+            transcript: vec![],
+            confirm_key: None
+        })
     }
-    pub async fn is_sender_of(&self, payload:&Transitable) -> bool{
-        let crypto = fetch_subtle_crypto();
-        let key = did_key_to_crypto_key(&crypto, &self.did).await;
+    pub async fn is_sender_of(&self, payload:&Transitable) -> Result<bool, AwakeError>{
+        let crypto = fetch_backend()?;
+        let key = did_key_to_crypto_key(&crypto, &self.did).await?;
         return payload.verify(&crypto, &key).await;
     }
-    pub async fn finalize(&mut self, private_key:CryptoKey, forien_did:&str, mid_prefix:Vec<u8>){
-        self.mid_prefix = Some(mid_prefix);
+    //private_key only agrees the session secret with the other agent's did. The double ratchet and rekeys start from
+    //the step 4 keys so a saved session never holds a long lived key
+    pub async fn finalize(&mut self, private_key:&PrivateKey, ratchet_private:PrivateKey, self_did:&str, forien_did:&str, forien_ratchet_did:&str) -> Result<(), AwakeError>{
+        self.self_did = Some(self_did.to_string());
         
-        let crypto = fetch_subtle_crypto();
-        let forien_key = did_key_to_crypto_key(&crypto, forien_did).await;
+        let crypto = fetch_backend()?;
+        let (shared_secret, confirm_key) = self.session_secret(private_key, forien_did).await?;
+        self.handshake_did = Some(self.did.clone());
         self.did = forien_did.to_string();
-        let shared_secret = diffie_helman(&crypto, &private_key, &forien_key).await;
+        self.confirm_key = Some(confirm_key);
+
+        //mids start again under keys only the finalized agents know, so they can not be linked to the handshake
+        let (send_mid_info, recieve_mid_info) = mid_infos(self.is_requestor);
+        let mid_prefix = self.mid_prefix(self_did, forien_did)?;
+        self.send_mid_key = derive_mid_key(&shared_secret, &mid_prefix, send_mid_info).await?;
+        self.recieve_mids = MidWindow::new(derive_mid_key(&shared_secret, &mid_prefix, recieve_mid_info).await?, MID_WINDOW).await?;
+        self.next_send_id = 0;
 
-        self.send_ratchet.set_new_shared_key(1, shared_secret.clone());
-        self.recieve_ratchet.set_new_shared_key(1, shared_secret).await;
-    }
-    pub async fn encrypt_for(&mut self, payload:Transitable) -> (String, Transitable){
-        let encrypted = self.send_ratchet.process_payload(self.next_send_id, payload).await.unwrap();
-        let mid = match &self.mid_prefix{
-            Some(prefix) => self.get_mid(self.next_send_id).await,
-            None => format!("{}", self.next_send_id)
+        //the responder sends the acknowledgement so it is the one that sends first
+        let forien_ratchet_key = did_key_to_crypto_key(&crypto, forien_ratchet_did).await?;
+        self.double_ratchet = Some(match self.is_requestor {
+            false => DoubleRatchet::new_sender(shared_secret.clone(), &forien_ratchet_key).await?,
+            true => DoubleRatchet::new_reciever(shared_secret.clone(), &ratchet_private).await?
+        });
+        self.rekey_private = Some(crypto.export_private_key(&ratchet_private).await?);
+        self.rekey_did = Some(forien_ratchet_did.to_string());
+        self.session_secret = Some(shared_secret);
+        //the step 2 chains are done with once the handshake is over
+        self.send_ratchet.retire();
+        self.recieve_ratchet.retire();
+        return Ok(());
+    }
+    //The mid of the first message the other agent will send once finalized with the same keys, so the requestor can
+    //tell which responder an acknowledgement is from without finalizing a copy of every one it challenged
+    pub async fn first_finalized_mid(&self, private_key:&PrivateKey, self_did:&str, forien_did:&str) -> Result<String, AwakeError>{
+        let (shared_secret, _) = self.session_secret(private_key, forien_did).await?;
+        let (_, recieve_mid_info) = mid_infos(self.is_requestor);
+        let recieve_mid_key = derive_mid_key(&shared_secret, &self.mid_prefix(self_did, forien_did)?, recieve_mid_info).await?;
+        return mid_for(&recieve_mid_key, 0).await;
+    }
+    //Both agents only get the same session secret and key confirmation key if they saw the same handshake messages
+    async fn session_secret(&self, private_key:&PrivateKey, forien_did:&str) -> Result<(SharedSecret, Vec<u8>), AwakeError>{
+        let crypto = fetch_backend()?;
+        let forien_key = did_key_to_crypto_key(&crypto, forien_did).await?;
+        let dh_output = diffie_helman(&crypto, private_key, &forien_key).await?;
+        let key_data = crypto.hkdf(&dh_output, &self.transcript, SESSION_INFO, 64).await?;
+        return Ok((SharedSecret::from_bytes(&key_data[..32]), key_data[32..].to_vec()));
+    }
+    //The mid prefix is shared by both parties once the requestor's real did and the responder's next did are known
+    fn mid_prefix(&self, self_did:&str, forien_did:&str) -> Result<Vec<u8>, AwakeError>{
+        let (requestor_did, responder_did) = match self.is_requestor {
+            true => (self_did, forien_did),
+            false => (forien_did, self_did)
+        };
+        let mut mid_prefix:Vec<u8> = vec![];
+        mid_prefix.append(&mut did_key_to_bytes(requestor_did)?);
+        mid_prefix.append(&mut did_key_to_bytes(responder_did)?);
+        return Ok(mid_prefix);
+    }
+    //Both agents have to add the same handshake messages in the same order, as they were signed
+    pub async fn add_to_transcript(&mut self, message:&Transitable) -> Result<(), AwakeError>{
+        let mut data = self.transcript.clone();
+        data.extend_from_slice(message.as_slice());
+        self.transcript = hash(&fetch_backend()?, &data).await?;
+        return Ok(());
+    }
+    //A mac over the transcript that shows the session keys were derived from it, only available once finalized
+    pub async fn confirmation(&self, from_requestor:bool) -> Result<String, AwakeError>{
+        let confirm_key = match &self.confirm_key {
+            Some(x) => x,
+            None => return Err(AwakeError::HandshakeIncomplete)
+        };
+        let mut data = match from_requestor {
+            true => REQUESTOR_CONFIRM_INFO.to_vec(),
+            false => RESPONDER_CONFIRM_INFO.to_vec()
+        };
+        data.extend_from_slice(&self.transcript);
+        return Ok(base64::encode(fetch_backend()?.hmac(confirm_key, &data).await?));
+    }
+    //The requestor's key confirmation, sent with every message until the responder has been heard from, as
+    //the responder can not read anything the requestor sends before one of them reaches it
+    pub async fn pending_confirmation(&self) -> Result<Option<String>, AwakeError>{
+        if !self.is_requestor || self.confirm_key.is_none() {
+            return Ok(None);
+        }
+        return Ok(Some(self.confirmation(true).await?));
+    }
+    //The responder reads nothing from the requestor until the requestor has confirmed the session keys
+    pub async fn check_confirmation(&self, conf:Option<&str>) -> Result<(), AwakeError>{
+        if self.is_requestor || self.confirm_key.is_none() {
+            return Ok(());
+        }
+        if conf != Some(self.confirmation(true).await?.as_str()) {
+            return Err(AwakeError::KeyConfirmationFailure);
+        }
+        return Ok(());
+    }
+    //Only once a message has decrypted do both sides know the other holds the same session keys
+    pub fn confirmed(&mut self){
+        self.confirm_key = None;
+    }
+    //The mid the next finalized message will be sent with, so it can be bound to the message as associated data
+    pub async fn next_mid(&self) -> Result<String, AwakeError>{
+        if self.double_ratchet.is_none() {
+            return Err(AwakeError::HandshakeIncomplete);
+        }
+        return mid_for(&self.send_mid_key, self.next_send_id).await;
+    }
+    //aad is only used once finalized, the handshake messages are signed instead
+    pub async fn encrypt_for(&mut self, payload:Transitable, aad:&[u8]) -> Result<(String, Transitable), AwakeError>{
+        let mid = mid_for(&self.send_mid_key, self.next_send_id).await?;
+        let encrypted = match &mut self.double_ratchet {
+            Some(double_ratchet) => {
+                let (header, encrypted) = double_ratchet.encrypt(payload, aad).await?;
+                header.seal(&encrypted)?
+            },
+            None => self.send_ratchet.process_payload(self.next_send_id, payload).await?
         };
         self.next_send_id += 1;
-        return (mid, encrypted);
-    }
-    pub async fn decrypt_for(&mut self, id:usize, payload:Transitable) -> Transitable{
-        return self.recieve_ratchet.process_payload(id, payload).await.unwrap();
-    }
-    pub async fn decrypt_with_mid(&mut self, mid:String, payload:Transitable) -> Transitable{
-        let mut i = 0;
-        let mut id:Option<usize> = None;
-        let start = self.recieve_ratchet.len();
-        while (start+i < MAX_MSGS) || (start - i) > 0{
-            if start+i < MAX_MSGS {
-                if self.get_mid(start+i).await == mid{
-                    id = Some(start+i);
-                    break;
-                }
-            }
-            if (start - i) > 0 {
-                if self.get_mid(start-i).await == mid{
-                    id = Some(start-i);
-                    break;
+        return Ok((mid, encrypted));
+    }
+    //For handshake messages that are sent without a mid, id is their index in the step 2 chain
+    pub async fn decrypt_for(&mut self, id:usize, payload:Transitable) -> Result<Transitable, AwakeError>{
+        let decrypted = self.recieve_ratchet.process_payload(id, payload).await?;
+        self.recieve_mids.advance(id).await?;
+        return Ok(decrypted);
+    }
+    //The mid gives the message's index, before finalizing that is its link in the step 2 chain. After it the
+    //header in front of the message says which chain and link of the double ratchet it is from
+    pub async fn decrypt_with_mid(&mut self, mid:String, payload:Transitable, aad:&[u8]) -> Result<Transitable, AwakeError>{
+        let id = match self.recieve_mids.resolve(&mid) {
+            Some(x) => x,
+            None => return Err(AwakeError::UnknownMid(mid))
+        };
+        let decrypted = match self.double_ratchet {
+            Some(_) => self.decrypt_finalized(payload, aad).await?,
+            None => self.recieve_ratchet.process_payload(id, payload).await?
+        };
+        self.recieve_mids.advance(id).await?;
+        return Ok(decrypted);
+    }
+    //Whether a mid is one the other agent's next messages could have
+    pub fn knows_mid(&self, mid:&str) -> bool {
+        return self.recieve_mids.resolve(mid).is_some();
+    }
+    async fn decrypt_finalized(&mut self, payload:Transitable, aad:&[u8]) -> Result<Transitable, AwakeError>{
+        let (header, encrypted) = RatchetHeader::open(&payload)?;
+        let double_ratchet = match &mut self.double_ratchet {
+            Some(x) => x,
+            None => return Err(AwakeError::HandshakeIncomplete)
+        };
+        let error = match double_ratchet.decrypt(&header, encrypted.clone(), aad).await {
+            Ok(x) => {
+                //the first message on the new ratchet says how far the other agent got on the old one, nothing more will come
+                if let (true, Some(previous_ratchet)) = (self.previous_live, &mut self.previous_ratchet) {
+                    previous_ratchet.retire_after(&header).await;
+                    self.previous_live = false;
                 }
-            }
-            i += 1;
+                return Ok(x);
+            },
+            Err(e) => e
+        };
+        //messages the other agent sent before it saw our rekey are still on the old ratchet
+        return match &mut self.previous_ratchet {
+            Some(x) => x.decrypt(&header, encrypted, aad).await.map_err(|_| error),
+            None => Err(error)
+        };
+    }
+    //Starts the double ratchet again from a new key pair mixed with the other agent's step 4 key.
+    //The rekey message is the last one sent on the old ratchet, everything after it uses the new one
+    pub async fn rekey(&mut self, aad:&[u8]) -> Result<(String, Transitable), AwakeError>{
+        let crypto = fetch_backend()?;
+        let (dh_public, dh_private) = gen_key_pair(&crypto, true).await?;
+        let forien_key = match &self.rekey_did {
+            Some(x) => did_key_to_crypto_key(&crypto, x).await?,
+            None => return Err(AwakeError::HandshakeIncomplete)
+        };
+        let shared_secret = self.next_session_secret(&dh_private, &forien_key).await?;
+
+        let rekey_msg = RekeyPayload::new(&self.awv, base64::encode(crypto.export_public_key(&dh_public).await?));
+        let (mid, encrypted) = self.encrypt_for(write_message(&rekey_msg)?, aad).await?;
+
+        let mut double_ratchet = DoubleRatchet::new_sender_with_key(shared_secret.clone(), &forien_key, &dh_public, &dh_private).await?;
+        self.switch_ratchet(&mut double_ratchet, shared_secret);
+        //our old ratchet still has to step for anything the other agent sends before it sees the rekey,
+        //it is retired once the first message on the new one arrives
+        self.previous_ratchet = self.double_ratchet.replace(double_ratchet);
+        self.previous_live = true;
+        return Ok((mid, encrypted));
+    }
+    //Moves to the ratchet the other agent started with rekey, the new key is mixed with our step 4 key
+    pub async fn accept_rekey(&mut self, mid:String, payload:Transitable, aad:&[u8]) -> Result<(), AwakeError>{
+        let rekey_msg:RekeyPayload = read_message(&self.decrypt_with_mid(mid, payload, aad).await?)?;
+        let crypto = fetch_backend()?;
+        let remote_dh = match base64::decode(&rekey_msg.key) {
+            Ok(x) => x,
+            Err(e) => return Err(AwakeError::BadEncoding(format!("rekey key is not valid base64: {}", e)))
+        };
+        let remote_key = crypto.import_public_key(&remote_dh).await?;
+        let rekey_private = match &self.rekey_private {
+            Some(x) => crypto.import_private_key(x).await?,
+            None => return Err(AwakeError::HandshakeIncomplete)
+        };
+        let shared_secret = self.next_session_secret(&rekey_private, &remote_key).await?;
+
+        let mut double_ratchet = DoubleRatchet::new_reciever(shared_secret.clone(), &rekey_private).await?;
+        double_ratchet.accept(&remote_key).await?;
+        if let Some(old) = &self.double_ratchet {
+            double_ratchet.follow(old);
+        }
+        self.switch_ratchet(&mut double_ratchet, shared_secret);
+        //nothing more will be sent on the old ratchet so it only keeps the keys for late messages
+        self.previous_ratchet = self.double_ratchet.replace(double_ratchet).map(|mut x| {
+            x.retire();
+            x
+        });
+        self.previous_live = false;
+        return Ok(());
+    }
+    async fn next_session_secret(&self, private_key:&PrivateKey, public_key:&PublicKey) -> Result<SharedSecret, AwakeError>{
+        let session_secret = match &self.session_secret {
+            Some(x) => x,
+            None => return Err(AwakeError::HandshakeIncomplete)
+        };
+        let crypto = fetch_backend()?;
+        let dh_output = diffie_helman(&crypto, private_key, public_key).await?;
+        let key_data = crypto.hkdf(&dh_output, session_secret.as_bytes(), REKEY_INFO, 32).await?;
+        return Ok(SharedSecret::from_bytes(&key_data));
+    }
+    fn switch_ratchet(&mut self, double_ratchet:&mut DoubleRatchet, shared_secret:SharedSecret){
+        if let Some(old) = &self.double_ratchet {
+            double_ratchet.set_limits(old.limits());
         }
-        return self.decrypt_for(id.unwrap(), payload).await;
-    }
-    async fn get_mid(&self, id:usize) -> String {
-        let crypto = fetch_subtle_crypto();
-        let mut count = self.next_send_id.to_be_bytes().to_vec();
-        let mut prefix_mut = self.mid_prefix.as_ref().unwrap().clone();
-        let mut key_data = Vec::new();
-        key_data.append(&mut prefix_mut);
-        key_data.append(&mut count);
-        let hash = hash(&crypto, &key_data).await;
-        return base64::encode(hash)
+        self.session_secret = Some(shared_secret);
+    }
+    //Only applies once the agent is finalized, the handshake never skips more than a message or two
+    pub async fn set_skip_limits(&mut self, limits:SkipLimits) -> Result<(), AwakeError>{
+        if let Some(double_ratchet) = &mut self.double_ratchet {
+            double_ratchet.set_limits(limits);
+            self.recieve_mids.set_limits(limits).await?;
+        }
+        if let Some(previous_ratchet) = &mut self.previous_ratchet {
+            previous_ratchet.set_limits(limits);
+        }
+        return Ok(());
     }
     pub fn empty_decryptor(&mut self, id:usize){
         self.recieve_ratchet.empty_decryptor(id);
     }
-}
\ No newline at end of file
+}
+
+//The mid key we send with and the one the other agent sends with
+fn mid_infos(is_requestor:bool) -> (&'static [u8], &'static [u8]){
+    return match is_requestor {
+        true => (REQUESTOR_MID_INFO, RESPONDER_MID_INFO),
+        false => (RESPONDER_MID_INFO, REQUESTOR_MID_INFO)
+    };
+}
@@ -1,65 +1,214 @@
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use web_sys::{SubtleCrypto, CryptoKey};
-use js_sys::{Array, Function, JSON};
-use std::collections::HashMap;
+use js_sys::{Array, Function, Object, JSON};
+use std::collections::{HashMap, HashSet};
 use ucan::builder::UcanBuilder;
+use ucan::ucan::Ucan;
+use ucan::crypto::KeyMaterial;
+use cid::Cid;
+use std::convert::TryFrom;
+use serde::{Serialize, Deserialize};
 use serde_json::{Value, json};
 
+use crate::backend::{Backend, CryptoBackend, PublicKey, PrivateKey};
 use crate::utils::*;
 use crate::ucan_ecdh_key::UcanEcdhKey;
 use crate::transitable::Transitable;
+use crate::messages::{Init, Response, Msg, ChallengePayload, AckPayload, Fin, FinPayload, write_message, read_message, read_signed_message, negotiate_version, expect_version};
 use crate::foreign_agent::ForeignAgent;
+use crate::session::Session;
+use crate::error::AwakeError;
+
+//How far a handshake has got, saved with its state so a caller knows which step to resume at
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum HandshakeStage {
+    Started, //no messages have been processed, a requestor stays here until it challenges
+    Responded, //a responder has answered an awake/init and is waiting on the challenge
+    Challenged, //a requestor has sent its challenge and is waiting on the acknowledgement
+    Done //the handshake is finished and can be turned into a session
+}
+
+//How the requestor proves itself in its challenge, the responder picks one for each request it answers
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ChallengeType {
+    None, //the requestor only proves it holds its real did
+    OobPin, //the requestor sends a pin that was shared out of band
+    UcanProof //the requestor presents a delegation chain that ends at its real did
+}
+impl ChallengeType {
+    //The value of the awake/challenge fact
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            ChallengeType::None => "none",
+            ChallengeType::OobPin => "oob-pin",
+            ChallengeType::UcanProof => "ucan"
+        };
+    }
+    pub fn parse(challenge:&str) -> Result<ChallengeType, AwakeError> {
+        return match challenge {
+            "none" => Ok(ChallengeType::None),
+            "oob-pin" => Ok(ChallengeType::OobPin),
+            "ucan" => Ok(ChallengeType::UcanProof),
+            x => Err(AwakeError::UnsupportedChallenge(x.to_string()))
+        };
+    }
+}
+
+//Why a handshake was given up on, sent to the other agent in an awake/fin
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum FinReason {
+    CapabilitiesRejected, //the responder did not accept the requestor's capabilities
+    UcanRejected, //the requestor did not accept the responder's ucan or it was invalid
+    ChallengeRejected, //the responder did not accept the requestor's pin or delegation chain
+    SignatureFailure, //a handshake message was not signed by the did it should have been
+    UnsupportedChallenge, //the requestor can not answer the challenge the responder asked for
+    UnsupportedVersion, //the agents do not share a version of the protocol
+    Other //anything else, including reasons this version does not know
+}
+impl FinReason {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            FinReason::CapabilitiesRejected => "capabilities-rejected",
+            FinReason::UcanRejected => "ucan-rejected",
+            FinReason::ChallengeRejected => "challenge-rejected",
+            FinReason::SignatureFailure => "signature-failure",
+            FinReason::UnsupportedChallenge => "unsupported-challenge",
+            FinReason::UnsupportedVersion => "unsupported-version",
+            FinReason::Other => "other"
+        };
+    }
+    pub fn parse(reason:&str) -> FinReason {
+        return match reason {
+            "capabilities-rejected" => FinReason::CapabilitiesRejected,
+            "ucan-rejected" => FinReason::UcanRejected,
+            "challenge-rejected" => FinReason::ChallengeRejected,
+            "signature-failure" => FinReason::SignatureFailure,
+            "unsupported-challenge" => FinReason::UnsupportedChallenge,
+            "unsupported-version" => FinReason::UnsupportedVersion,
+            _ => FinReason::Other
+        };
+    }
+    pub fn from_error(error:&AwakeError) -> FinReason {
+        return match error {
+            AwakeError::CapabilityRejected => FinReason::CapabilitiesRejected,
+            AwakeError::UcanRejected | AwakeError::BadUcan(_) | AwakeError::UcanSignatureFailure | AwakeError::UcanExpired |
+            AwakeError::UcanNotYetValid | AwakeError::UcanWrongAudience(_) | AwakeError::MissingFact(_) => FinReason::UcanRejected,
+            AwakeError::PinRejected | AwakeError::ProofRejected(_) => FinReason::ChallengeRejected,
+            AwakeError::SignatureFailure(_) | AwakeError::ResponseSignatureFailure => FinReason::SignatureFailure,
+            AwakeError::UnsupportedChallenge(_) => FinReason::UnsupportedChallenge,
+            AwakeError::UnsupportedVersion(_) => FinReason::UnsupportedVersion,
+            _ => FinReason::Other
+        };
+    }
+}
+
+//A request the responder has answered, kept until the requestor's challenge arrives
+#[derive(Clone, Serialize, Deserialize)]
+struct PendingRequest {
+    challenge: ChallengeType
+}
+
+//A response the requestor has challenged, kept until the acknowledgement or an awake/fin arrives
+#[derive(Clone, Serialize, Deserialize)]
+struct SentChallenge {
+    real_did: String, //the issuer of the responder's ucan, every message the responder sends is signed with it
+    next_did: String, //the did the responder will use for the rest of the session
+    #[serde(default)]
+    ack_mid: String //the mid the responder's acknowledgement will have, so it is found without finalizing
+}
+
+//Everything needed to rebuild a Handshake. Private keys are jwks and the whole thing is wrapped before it leaves
+#[derive(Serialize, Deserialize)]
+struct HandshakeState {
+    stage: HandshakeStage,
+    final_agent: Option<ForeignAgent>,
+    step_2_public: Vec<u8>,
+    step_2_private: String,
+    step_4_public: Vec<u8>,
+    step_4_private: String,
+    real_public: Vec<u8>,
+    real_private: String,
+    potential_partners: HashMap<String, ForeignAgent>,
+    potential_requests: HashMap<String, PendingRequest>,
+    ucan_proofs: Vec<String>,
+    #[serde(default)]
+    challenged: HashMap<String, SentChallenge>,
+    #[serde(default)]
+    sent_request: Option<Transitable>,
+    #[serde(default)]
+    closed: HashSet<String>,
+    #[serde(default)]
+    accept_legacy_jws: bool
+}
 
 #[wasm_bindgen]
 pub struct Handshake{
-    crypto: SubtleCrypto,
+    crypto: Backend,
     final_agent: Option<ForeignAgent>,
-    step_2_public: CryptoKey,
-    step_2_private: CryptoKey,
-    step_4_public: CryptoKey,
-    step_4_private: CryptoKey,
-    real_public: CryptoKey,
-    real_private: CryptoKey,
-    potential_partners: HashMap<String, ForeignAgent>
+    step_2_public: PublicKey,
+    step_2_private: PrivateKey,
+    step_4_public: PublicKey,
+    step_4_private: PrivateKey,
+    real_public: PublicKey,
+    real_private: PrivateKey,
+    real_did: String, //only signs the acknowledgement, everything before it is signed with the step 2 key
+    potential_partners: HashMap<String, ForeignAgent>,
+    potential_requests: HashMap<String, PendingRequest>,
+    ucan_proofs: Vec<String>, //delegations to our real did, presented when a responder asks for a ucan challenge
+    challenged: HashMap<String, SentChallenge>, //the responders we have challenged by their step 2 did
+    sent_request: Option<Transitable>, //our last awake/init, the start of the transcript the responder's answer continues
+    closed: HashSet<String>, //the step 2 dids of the agents whose awake/fin we have read, so the same fin is not read twice
+    accept_legacy_jws: bool, //let through handshake messages signed in the old ES512 format
+    resumable: bool, //made with new_resumable so every key can be exported
+    clock: fn() -> u64, //the unix time the ucans we issue are timed from
+    failure: Option<Transitable> //the awake/fin for the last step we rejected, waiting to be sent
 }
 
 
 #[wasm_bindgen]
 impl Handshake{
-    pub async fn new() -> Handshake{
-        let crypto = fetch_subtle_crypto();
-        let (step_2_public, step_2_private) = gen_key_pair(&crypto, false).await;
-        let (step_4_public, step_4_private) = gen_key_pair(&crypto, false).await;
-        let (real_public, real_private) = gen_key_pair(&crypto, true).await;
-        return Handshake{
+    pub async fn new() -> Result<Handshake, AwakeError>{
+        return Handshake::with_keys(false).await;
+    }
+    //A handshake that can be saved with export_state, which needs every key to be extractable
+    pub async fn new_resumable() -> Result<Handshake, AwakeError>{
+        return Handshake::with_keys(true).await;
+    }
+    async fn with_keys(resumable:bool) -> Result<Handshake, AwakeError>{
+        let crypto = fetch_backend()?;
+        //the step 4 key starts the session's ratchet, which saves its key as a jwk, so it is always extractable
+        let (step_2_public, step_2_private) = gen_key_pair(&crypto, resumable).await?;
+        let (step_4_public, step_4_private) = gen_key_pair(&crypto, true).await?;
+        let (real_public, real_private) = gen_key_pair(&crypto, resumable).await?;
+        let real_did = crypto_key_to_did_key(&crypto, &real_public).await?;
+        return Ok(Handshake{
             step_2_public,
             step_2_private,
             step_4_public,
             step_4_private,
             real_public,
             real_private,
+            real_did,
             potential_partners:HashMap::new(),
+            potential_requests:HashMap::new(),
+            ucan_proofs: vec![],
+            challenged: HashMap::new(),
+            sent_request: None,
+            closed: HashSet::new(),
+            accept_legacy_jws: false,
+            resumable,
+            clock: ucan::time::now,
+            failure: None,
             final_agent: None,
             crypto
-        };
+        });
     }
     // Part 3.2 from spec
-    pub async fn request(&self, capabilities: Array) -> Transitable {
-        if self.is_done(){
-            panic!("This awake object has already conducted a handshake. Please initialize a new awake object to conduct more conections.")
-        }
-
-        //TODO: Add error handling
-        let cap_json = capabilities_to_value(capabilities);
-        return Transitable::from_readable(&format!("{{
-                \"awv\": \"0.1.0\",
-                \"type\": \"awake/init\",
-                \"did\":\"{}\",
-                \"caps\": {}
-            }}", 
-            &crypto_key_to_did_key(&self.crypto, &self.step_2_public).await, cap_json))
-            .sign(&self.crypto, &self.real_private).await;
+    pub async fn request(&mut self, capabilities: Array) -> Result<Transitable, AwakeError> {
+        return self.request_with(&capabilities_from_array(capabilities)?).await;
     }
     //Part 3.3 from spec
     pub async fn reponse(
@@ -67,262 +216,763 @@ impl Handshake{
         request_signed:Transitable, //The handshake request you are trying to respond to
         capabilities: Array, //The capabilities you have and are trying to prove to them
         lifetime: u64, //how long should the ucan be valid for
-        are_capabilities_valid: Function //passes in the capabilities they want to prove and passes out a boolean on if you deem them valid
-    ) -> Option<Transitable>{
-        //error if there haas already been a handshake conducted
+        are_capabilities_valid: Function, //passes in the capabilities they want to prove and passes out a boolean on if you deem them valid
+        challenge: Option<ChallengeType> //how the requestor has to prove itself, defaults to an oob-pin
+    ) -> Result<Transitable, AwakeError>{
+        let capabilities = capabilities_from_array(capabilities)?;
+        let challenge = challenge.unwrap_or(ChallengeType::OobPin);
+        return self.reponse_with(request_signed, &capabilities, lifetime, challenge, |caps| {
+            call_validator(&are_capabilities_valid, &value_to_js(caps)?)
+        }).await;
+    }
+    //part 3.4 from spec
+    pub async fn challenge_response(&mut self, 
+        response_signed:Transitable, //The handshake response you are trying to challenge
+        oob_pin: &str, //The out of bounds pin to prove who you are, ignored unless the responder asked for one
+        is_ucan_valid: Function //passes in the capabilities they want to prove and passes out a boolean on if you deem them valid
+    )-> Result<Transitable, AwakeError> {
+        return self.challenge_response_with(response_signed, oob_pin, |ucan| {
+            call_validator(&is_ucan_valid, &value_to_js(ucan)?)
+        }).await;
+    }
+    pub async fn acknowledge_challenge(&mut self, 
+        challenge_signed:Transitable, //The challenge you are acknowledging
+        is_proof_valid: Function //passes in their oob_pin or the payloads of their delegation chain and passes out a boolean on if you deem them valid
+    ) -> Result<Transitable, AwakeError>{
+        return self.acknowledge_challenge_with(challenge_signed, |proof| {
+            call_validator(&is_proof_valid, &value_to_js(proof)?)
+        }).await;
+    }
+    //Our real did, the one other agents delegate to so we can answer ucan challenges
+    pub fn did(&self) -> String {
+        return self.real_did.clone();
+    }
+    //Compatibility for agents that still sign with the ES512 format from before transitables were real JWS, off by default
+    pub fn set_accept_legacy_jws(&mut self, accept:bool) {
+        self.accept_legacy_jws = accept;
+    }
+    //Adds a ucan delegated to our real did to the chain presented in ucan challenges
+    pub fn add_proof(&mut self, ucan:&str) -> Result<(), AwakeError> {
+        read_ucan(ucan)?;
+        self.ucan_proofs.push(ucan.to_string());
+        return Ok(());
+    }
+    //Delegates capabilities to another agent's real did, proofs are the ucans that gave us those capabilities
+    pub async fn delegate(&self, audience:&str, capabilities:Array, lifetime:u64, proofs:Array) -> Result<String, AwakeError> {
+        let mut proof_tokens:Vec<String> = vec![];
+        for proof in proofs.to_vec() {
+            match proof.as_string() {
+                Some(x) => proof_tokens.push(x),
+                None => return Err(AwakeError::BadUcan("proofs must be ucan strings".to_string()))
+            }
+        }
+        return self.delegate_with(audience, &capabilities_from_array(capabilities)?, lifetime, &proof_tokens).await;
+    }
+    //Part 3.5 from spec, from the requestor's side
+    pub async fn accept_acknowledgement(&mut self, 
+        ack_signed:Transitable //The acknowledgement of your challenge
+    ) -> Result<(), AwakeError> {
         if self.is_done(){
-            panic!("This awake object has already conducted a handshake. Please initialize a new awake object to conduct more conections.")
+            return Err(AwakeError::HandshakeComplete);
         }
-        let self_did = crypto_key_to_did_key(&self.crypto, &self.step_2_public);
+        let self_did = self.real_did.clone();
 
-        //get requestor's data from request
-        let request = request_signed.unsign();
-        let request_str = match request.as_readable(){
-            Some(x) => x,
-            None => panic!("The handshake init was not sent properly or the transitable is not a handshake request")
+        //get payload data
+        let ack:Msg = read_signed_message(&ack_signed)?;
+        let ack_msg_encrypted = Transitable::from_base64(&ack.msg)?;
+
+        //find the responder the ack came from and check it was signed by the real did its ucan was issued by
+        let agent_did = self.find_acknowledged(&ack.mid)?;
+        let sent = self.challenged[&agent_did].clone();
+        if !self.is_signed_by(&ack_signed, &sent.real_did).await? {
+            return Err(AwakeError::SignatureFailure("acknowledgement was not signed by the responder's did".to_string()));
+        }
+        //only the agent the ack belongs to is finalized
+        let mut agent = self.potential_partners[&agent_did].clone();
+        agent.finalize(&self.real_private, self.step_4_private.clone(), &self_did, &sent.next_did, &sent.next_did).await?;
+
+        //anything else wrong with an acknowledgement the responder signed is reported back with an awake/fin
+        if let Err(e) = check_acknowledgement(&mut agent, ack, ack_msg_encrypted, &self_did).await {
+            let partner = self.potential_partners[&agent_did].clone();
+            return self.reject(partner, e).await;
+        }
+
+        agent.peer_did = Some(sent.real_did);
+        self.potential_partners.remove(&agent_did);
+        self.challenged.remove(&agent_did);
+        self.final_agent = Some(agent);
+        return Ok(());
+    }
+    //The awake/fin to send after a step was rejected, a handshake method returned an error if there is one
+    pub fn take_failure(&mut self) -> Option<Transitable> {
+        return self.failure.take();
+    }
+    //Reads an awake/fin from the other agent and forgets them
+    pub async fn read_failure(&mut self, 
+        fin_signed:Transitable //The failure message the other agent sent
+    ) -> Result<FinReason, AwakeError> {
+        if self.is_done(){
+            return Err(AwakeError::HandshakeComplete);
+        }
+        let fin:Fin = read_signed_message(&fin_signed)?;
+        if fin.aud != crypto_key_to_did_key(&self.crypto, &self.step_2_public).await? {
+            return Err(AwakeError::UnexpectedMessage("failure message was not for this agent".to_string()));
+        }
+
+        //a responder can turn our request down before answering it, its fin is read with a new step 2 agent.
+        //Only the holder of fin.iss can derive that agent's chain so the fin is checked when it decrypts
+        let forien_did = fin.iss.as_str();
+        let mut agent = match self.potential_partners.get(forien_did) {
+            Some(x) => x.clone(),
+            None if self.sent_request.is_some() && !self.closed.contains(forien_did) => {
+                ForeignAgent::new(&self.step_2_private, forien_did, Some(&self.step_2_public)).await?
+            },
+            None => return Err(AwakeError::UnexpectedMessage("failure message was from an unknown agent".to_string()))
+        };
+        if !self.is_signed_by(&fin_signed, forien_did).await? {
+            return Err(AwakeError::SignatureFailure("failure message was not signed by the did it was sent from".to_string()));
+        }
+        let fin_encrypted = Transitable::from_base64(&fin.msg)?;
+        let fin_payload:FinPayload = read_message(&agent.decrypt_with_mid(fin.mid.clone(), fin_encrypted, &[]).await?)?;
+
+        self.potential_partners.remove(forien_did);
+        self.potential_requests.remove(forien_did);
+        self.challenged.remove(forien_did);
+        self.closed.insert(forien_did.to_string());
+        return Ok(FinReason::parse(&fin_payload.reason));
+    }
+    pub fn is_done(&self) -> bool {
+        return self.final_agent.is_some();
+    }
+    #[wasm_bindgen(getter)]
+    pub fn stage(&self) -> HandshakeStage {
+        if self.final_agent.is_some() {
+            return HandshakeStage::Done;
+        }
+        if !self.challenged.is_empty() {
+            return HandshakeStage::Challenged;
+        }
+        if !self.potential_partners.is_empty() {
+            return HandshakeStage::Responded;
+        }
+        return HandshakeStage::Started;
+    }
+    //Saves the handshake so it can be resumed after a reload, wrapping_key must be 32 bytes and is needed to import it again
+    pub async fn export_state(&self, wrapping_key:&[u8]) -> Result<Transitable, AwakeError> {
+        if !self.resumable {
+            return Err(AwakeError::BadState("only a handshake made with new_resumable can be saved".to_string()));
+        }
+        let crypto = &self.crypto;
+        let state = HandshakeState {
+            stage: self.stage(),
+            final_agent: self.final_agent.clone(),
+            step_2_public: crypto.export_public_key(&self.step_2_public).await?,
+            step_2_private: crypto.export_private_key(&self.step_2_private).await?,
+            step_4_public: crypto.export_public_key(&self.step_4_public).await?,
+            step_4_private: crypto.export_private_key(&self.step_4_private).await?,
+            real_public: crypto.export_public_key(&self.real_public).await?,
+            real_private: crypto.export_private_key(&self.real_private).await?,
+            potential_partners: self.potential_partners.clone(),
+            potential_requests: self.potential_requests.clone(),
+            ucan_proofs: self.ucan_proofs.clone(),
+            challenged: self.challenged.clone(),
+            sent_request: self.sent_request.clone(),
+            closed: self.closed.clone(),
+            accept_legacy_jws: self.accept_legacy_jws
         };
-        let request_map:Value = match serde_json::from_str(&request_str){
+        let state_json = match serde_json::to_vec(&state) {
             Ok(x) => x,
-            Err(_) => panic!("The handshake was not sent in the proper json format")
+            Err(e) => return Err(AwakeError::BadState(e.to_string()))
         };
+        return Ok(Transitable::from_bytes(&wrap(crypto, wrapping_key, &state_json).await?));
+    }
+    //Restores a handshake saved with export_state, its ucans are timed from the system clock
+    pub async fn import_state(state:Transitable, wrapping_key:&[u8]) -> Result<Handshake, AwakeError> {
+        return Handshake::import_state_with_clock(state, wrapping_key, ucan::time::now).await;
+    }
+    //True for a requestor that has sent its challenge and is waiting on the acknowledgement
+    pub(crate) fn is_awaiting_acknowledgement(&self) -> bool {
+        return !self.challenged.is_empty();
+    }
+    //Hands the finalized agent over to a session once the handshake is done
+    pub fn into_session(self) -> Result<Session, AwakeError> {
+        return match self.final_agent {
+            Some(agent) => Ok(Session::new(agent)),
+            None => Err(AwakeError::HandshakeIncomplete)
+        };
+    }
+}
+impl Handshake{
+    //The same steps as the js api but capabilities are rust values and the validators are closures
+    pub async fn delegate_with(&self, audience:&str, capabilities:&[UcanCapability], lifetime:u64, proofs:&[String]) -> Result<String, AwakeError> {
+        let mut proof_ucans:Vec<Ucan> = vec![];
+        for proof in proofs {
+            proof_ucans.push(read_ucan(proof)?.0);
+        }
+        let caps_fact = json!({"caps": capabilities_to_value(capabilities)?});
+        let issuer = self.real_issuer();
+        return build_ucan(&issuer, audience, (self.clock)() + lifetime, vec![caps_fact], &proof_ucans).await;
+    }
+    //Replaces the clock the ucans we issue are timed from, the ucans of an agent whose clock is behind expire early
+    pub fn set_clock(&mut self, clock:fn() -> u64) {
+        self.clock = clock;
+    }
+    //Restores a handshake saved with export_state. The clock is not saved with the state so it is given again here
+    pub async fn import_state_with_clock(state:Transitable, wrapping_key:&[u8], clock:fn() -> u64) -> Result<Handshake, AwakeError> {
+        let crypto = fetch_backend()?;
+        let state_json = unwrap(&crypto, wrapping_key, state.as_slice()).await?;
+        let state:HandshakeState = match serde_json::from_slice(&state_json) {
+            Ok(x) => x,
+            Err(e) => return Err(AwakeError::BadState(e.to_string()))
+        };
+        let real_public = crypto.import_public_key(&state.real_public).await?;
+        let handshake = Handshake{
+            final_agent: state.final_agent,
+            step_2_public: crypto.import_public_key(&state.step_2_public).await?,
+            step_2_private: crypto.import_private_key(&state.step_2_private).await?,
+            step_4_public: crypto.import_public_key(&state.step_4_public).await?,
+            step_4_private: crypto.import_private_key(&state.step_4_private).await?,
+            real_did: crypto_key_to_did_key(&crypto, &real_public).await?,
+            real_public,
+            real_private: crypto.import_private_key(&state.real_private).await?,
+            potential_partners: state.potential_partners,
+            potential_requests: state.potential_requests,
+            ucan_proofs: state.ucan_proofs,
+            challenged: state.challenged,
+            sent_request: state.sent_request,
+            closed: state.closed,
+            accept_legacy_jws: state.accept_legacy_jws,
+            resumable: true, //only a resumable handshake could have been saved
+            clock,
+            failure: None,
+            crypto
+        };
+        if handshake.stage() != state.stage {
+            return Err(AwakeError::BadState(format!("saved as {:?} but restored as {:?}", state.stage, handshake.stage())));
+        }
+        return Ok(handshake);
+    }
+    pub async fn request_with(&mut self, capabilities: &[UcanCapability]) -> Result<Transitable, AwakeError> {
+        if self.is_done(){
+            return Err(AwakeError::HandshakeComplete);
+        }
+
+        //the request is sent in the clear so it is signed with our step 2 key, the responder learns our real did from the challenge
+        let self_did = crypto_key_to_did_key(&self.crypto, &self.step_2_public).await?;
+        let request = Init::new(self_did.clone(), capabilities.to_vec());
+        let request_signed = write_message(&request)?.sign(&self.crypto, &self.step_2_private, Some(&self_did)).await?;
+        self.sent_request = Some(request_signed.clone());
+        return Ok(request_signed);
+    }
+    pub async fn reponse_with(
+        &mut self, 
+        request_signed:Transitable,
+        capabilities: &[UcanCapability],
+        lifetime: u64,
+        challenge: ChallengeType,
+        are_capabilities_valid: impl Fn(&Value) -> Result<bool, AwakeError>
+    ) -> Result<Transitable, AwakeError>{
+        //error if there haas already been a handshake conducted
+        if self.is_done(){
+            return Err(AwakeError::HandshakeComplete);
+        }
+
+        //get requestor's data from request
+        let request:Init = read_signed_message(&request_signed)?;
+
+        //a request its did did not sign is dropped without a fin, nothing has been sent to that did yet
+        let forien_did_key = request.did.as_str();
+        if !self.is_signed_by(&request_signed, forien_did_key).await? {
+            return Err(AwakeError::SignatureFailure("awake/init was not signed by the did it was sent from".to_string()));
+        }
 
         //init agent
-        let forien_did_key = &request_map["did"].as_str().unwrap();
-        let mut agent = ForeignAgent::new(&self.step_2_private, forien_did_key, None).await;
+        let mut agent = ForeignAgent::new(&self.step_2_private, forien_did_key, None).await?;
 
-        //verify sender of the request
-        //The forien agent's real public is not known yet so this is impossible
-        // if !agent.is_sender_of(&request_signed).await {
-        //     warn("Failed to verify sender's signature");
-        //     return None;
-        // }
+        //pick the newest version we both speak, every later message is written in it
+        agent.awv = match negotiate_version(&request.offered_versions()) {
+            Ok(x) => x,
+            Err(e) => return self.reject(agent, e).await
+        };
+        let awv = agent.awv.clone();
+        agent.add_to_transcript(&request_signed).await?;
 
         //verify the capabilities of the request
-        //TODO: fix this
-        let forien_caps_str = serde_json::to_string(&request_map["caps"]).unwrap();
-        let forien_caps_js = JSON::parse(&forien_caps_str).unwrap();
-        let is_sender_capable = are_capabilities_valid.call1(&forien_caps_js, &forien_caps_js).unwrap();
-        if !is_sender_capable.as_bool().unwrap() { 
-            warn("Failed to verify sender's capabilities");
-            return None;
+        match are_capabilities_valid(&capabilities_to_value(&request.caps)?) {
+            Ok(true) => (),
+            Ok(false) => return self.reject(agent, AwakeError::CapabilityRejected).await,
+            Err(e) => return self.reject(agent, e).await
         }
+        let self_did = crypto_key_to_did_key(&self.crypto, &self.step_2_public).await?;
 
         //create facts for verification
-        let oob_pin_fact = json!({
-            "awake/challenge": "oob-pin",
-            "caps": capabilities_to_value(capabilities)
+        let challenge_fact = json!({
+            "awake/challenge": challenge.as_str(),
+            "caps": capabilities_to_value(capabilities)?
         });
         let next_did_fact = json!({
-            "awake/nextdid": crypto_key_to_did_key(&self.crypto, &self.step_4_public).await
+            "awake/nextdid": crypto_key_to_did_key(&self.crypto, &self.step_4_public).await?
         });
 
         //build ucan message
-        let ucan = UcanBuilder::default()
-            .issued_by(&UcanEcdhKey::from(self.real_public.clone(), self.real_private.clone()))
-            .for_audience(forien_did_key)
-            .with_lifetime(lifetime)
-            .with_fact(next_did_fact)
-            .with_fact(oob_pin_fact)
-            .build().unwrap()
-            .sign().await.unwrap()
-            .encode().unwrap();
+        let issuer = self.real_issuer();
+        let ucan = build_ucan(&issuer, forien_did_key, (self.clock)() + lifetime, vec![next_did_fact, challenge_fact], &[]).await?;
         
         //encrypt the ucan and add agent to the list of potential agents
-        let (_, encrypted_ucan) = agent.encrypt_for(Transitable::from_readable(&ucan)).await;
-        self.potential_partners.insert(forien_did_key.to_string(), agent);
+        let (_, encrypted_ucan) = agent.encrypt_for(Transitable::from_readable(&ucan), &[]).await?;
 
         //build the response 
-        let response = Transitable::from_readable(&format!("{{
-                \"awv\": \"0.1.0\",
-                \"type\": \"awake/res\",
-                \"aud\":\"{}\",
-                \"iss\": \"{}\",
-                \"msg\": \"{}\"
-            }}", 
-            forien_did_key, self_did.await, encrypted_ucan.as_base64()))
-            .sign(&self.crypto, &self.real_private).await;
-        return Some(response);
+        let response = Response::new(&awv, forien_did_key.to_string(), self_did.clone(), encrypted_ucan.as_base64());
+        //our real did is only given to the requestor as the issuer of the encrypted ucan
+        let response_signed = write_message(&response)?.sign(&self.crypto, &self.step_2_private, Some(&self_did)).await?;
+        agent.add_to_transcript(&response_signed).await?;
+
+        self.potential_partners.insert(forien_did_key.to_string(), agent);
+        self.potential_requests.insert(forien_did_key.to_string(), PendingRequest{challenge});
+        return Ok(response_signed);
     }
-    //part 3.4 from spec
-    pub async fn challenge_response(&mut self, 
-        response_signed:Transitable, //The handshake response you are trying to challenge
-        oob_pin: &str, //The out of bounds pin to prove who you are
-        is_ucan_valid: Function //passes in the capabilities they want to prove and passes out a boolean on if you deem them valid
-    )-> Option<Transitable> {
+    pub async fn challenge_response_with(&mut self, 
+        response_signed:Transitable,
+        oob_pin: &str,
+        is_ucan_valid: impl Fn(&Value) -> Result<bool, AwakeError>
+    )-> Result<Transitable, AwakeError> {
         if self.is_done(){
-            panic!("This awake object has already conducted a handshake. Please initialize a new awake object to conduct more conections.")
+            return Err(AwakeError::HandshakeComplete);
         }
-        let self_did_future = crypto_key_to_did_key(&self.crypto, &self.real_public);
 
         //get requestor's data from request
-        let response = response_signed.unsign();
-        let response_str = match response.as_readable(){
-            Some(x) => x,
-            None => {
-                warn("handshake response was not sent properly or the transitable is not a handshake request");
-                return None
-            }
-        };
-        let response_map:Value = match serde_json::from_str(&response_str){
-            Ok(x) => x,
-            Err(_) => {
-                warn(&format!("handshake response was not sent in the proper json format: \n{}", response_str));
-                return None;
-            }
-        };
+        let response:Response = read_signed_message(&response_signed)?;
 
         //init agent
-        let forien_step_2_did = &response_map["iss"].as_str().unwrap();
-        let mut agent = ForeignAgent::new(&self.step_2_private, forien_step_2_did, Some(&self.step_2_public)).await;
-
-        //get message id
-        let forien_step_2_key = did_key_to_crypto_key(&self.crypto, forien_step_2_did).await;
-        let mid_future = get_message_id(&self.crypto, &self.step_2_public, &forien_step_2_key, None);
+        let forien_step_2_did = response.iss.as_str();
+        let mut agent = ForeignAgent::new(&self.step_2_private, forien_step_2_did, Some(&self.step_2_public)).await?;
+        //the responder picked from the versions we offered, reading the response already checked we speak it
+        agent.awv = response.awv.clone();
+        let awv = agent.awv.clone();
+        //the transcript starts with the request we sent, the responder started it with the one it recieved
+        let sent_request = match &self.sent_request {
+            Some(x) => x.clone(),
+            None => return Err(AwakeError::UnexpectedMessage("a response arrived before a request was sent".to_string()))
+        };
+        agent.add_to_transcript(&sent_request).await?;
+        agent.add_to_transcript(&response_signed).await?;
 
         //get ucan serde
-        let ucan_encrypted_str = match response_map["msg"].as_str(){
-            Some(x) => x,
-            None => {
-                warn("handshake ucan was not sent in the proper json format");
-                return None;
-            }
-        }.to_string();
-        let ucan = process_encrypted_ucan(&mut agent, &ucan_encrypted_str, 0).await;
+        let (ucan_parsed, ucan) = process_encrypted_ucan(&mut agent, &response.msg).await?;
 
-        //check if ucan is valid
-        let ucan_str = serde_json::to_string(&ucan).unwrap();
-        let ucan_js = JSON::parse(&ucan_str).unwrap();
-        let is_sender_capable = is_ucan_valid.call1(&ucan_js, &ucan_js).unwrap();
-        if !is_sender_capable.as_bool().unwrap() { 
-            warn("Failed to verify sender's capabilities");
-            return None;
-        }
+        //anything wrong with the ucan is reported back with an awake/fin
+        let (challenge, forien_next_did) = match self.check_response(&response_signed, &ucan_parsed, &ucan, is_ucan_valid).await {
+            Ok(x) => x,
+            Err(e) => return self.reject(agent, e).await
+        };
+        let forein_real_did = ucan_parsed.issuer();
 
-        //get signed hash for the payload
-        let forein_real_did = ucan["iss"].as_str().unwrap();
+        //build the proof the responder asked for, a delegation chain is presented with a ucan from our real did to theirs
+        let mut msg = ChallengePayload::new(&awv);
+        let proof = match challenge {
+            ChallengeType::None => String::new(),
+            ChallengeType::OobPin => {
+                msg.pin = Some(oob_pin.to_string());
+                oob_pin.to_string()
+            },
+            ChallengeType::UcanProof => {
+                let mut proofs:Vec<Ucan> = vec![];
+                for proof in &self.ucan_proofs {
+                    proofs.push(read_ucan(proof)?.0);
+                }
+                let issuer = self.real_issuer();
+                let leaf = build_ucan(&issuer, forein_real_did, *ucan_parsed.expires_at(), vec![], &proofs).await?;
+                msg.ucan = Some(leaf.clone());
+                msg.prf = Some(self.ucan_proofs.clone());
+                leaf
+            }
+        };
+
+        //get signed hash for the payload, our step 2 did ties our real key to the request it signed
+        let self_step_2_did = crypto_key_to_did_key(&self.crypto, &self.step_2_public).await?;
         let mut hash_data:Vec<u8> = vec![];
-        hash_data.append(&mut did_key_to_bytes(forein_real_did));
-        hash_data.append(&mut oob_pin.as_bytes().to_vec());
-        let hash = hash(&self.crypto, &hash_data).await;
-        let signature = sign(&self.crypto, &self.real_private, &hash).await;
-
-        //create the message field and encrypt it
-        let msg_plain = format!("{{
-            \"pin\":\"{}\"
-            \"did\":\"{}\"
-            \"sig\":\"{}\"
-        }}", oob_pin, self_did_future.await, base64::encode(signature));
-        let (_, msg_encrypted) = agent.encrypt_for(Transitable::from_readable(&msg_plain)).await;
+        hash_data.append(&mut did_key_to_bytes(forein_real_did)?);
+        hash_data.append(&mut did_key_to_bytes(&self_step_2_did)?);
+        hash_data.append(&mut proof.as_bytes().to_vec());
+        let hash = hash(&self.crypto, &hash_data).await?;
+        let signature = sign(&self.crypto, &self.real_private, &hash).await?;
+
+        //create the message field and encrypt it, the session's ratchet starts from our step 4 key rather than our real one
+        msg.did = self.real_did.clone();
+        msg.nextdid = crypto_key_to_did_key(&self.crypto, &self.step_4_public).await?;
+        msg.sig = base64::encode(signature);
+        let (challenge_mid, msg_encrypted) = agent.encrypt_for(write_message(&msg)?, &[]).await?;
+        let challenge_msg = Msg::new(&awv, challenge_mid, msg_encrypted.as_base64());
+        //our real did is in the encrypted payload, the envelope is signed with our step 2 key so it does not give it away
+        let challenge_signed = write_message(&challenge_msg)?.sign(&self.crypto, &self.step_2_private, Some(&self_step_2_did)).await?;
+        agent.add_to_transcript(&challenge_signed).await?;
+        let ack_mid = agent.first_finalized_mid(&self.real_private, &self.real_did, &forien_next_did).await?;
         
-        //add agent to potential partner list
+        //add agent to potential partner list, every responder we challenge keeps its own next did
         self.potential_partners.insert(forien_step_2_did.to_string(), agent);
+        self.challenged.insert(forien_step_2_did.to_string(), SentChallenge{real_did: forein_real_did.to_string(), next_did: forien_next_did, ack_mid});
 
         //return the final product, a response challange
-        let challenge = Transitable::from_readable(&format!("{{
-                \"awv\": \"0.1.0\",
-                \"type\": \"awake/msg\",
-                \"mid\":\"{}\",
-                \"msg\": \"{}\"
-            }}", 
-            base64::encode(mid_future.await), msg_encrypted.as_base64()))
-            .sign(&self.crypto, &self.real_private).await;
-
-        return Some(challenge);
+        return Ok(challenge_signed);
     }
-    pub async fn acknowledge_challenge(&mut self, 
-        challenge_signed:Transitable, //The challenge you are acknowledging
-        is_pin_valid: Function //passes in the oob_pin they want to prove and passes out a boolean on if you deem them valid
-    ) -> Option<Transitable>{
+    pub async fn acknowledge_challenge_with(&mut self, 
+        challenge_signed:Transitable,
+        is_proof_valid: impl Fn(&Value) -> Result<bool, AwakeError>
+    ) -> Result<Transitable, AwakeError>{
         if self.is_done(){
-            panic!("This awake object has already conducted a handshake. Please initialize a new awake object to conduct more conections.")
+            return Err(AwakeError::HandshakeComplete);
         }
-        let self_did_future = crypto_key_to_did_key(&self.crypto, &self.real_public);
 
         //get payload data
-        let challenge = challenge_signed.unsign();
-        let challenge_str = match challenge.as_readable(){
-            Some(x) => x,
-            None => {
-                warn("challenge was not sent properly or the transitable is not a handshake request");
-                return None;
-            }
-        };
-        let challenge_map:Value = match serde_json::from_str(&challenge_str){
+        let challenge:Msg = read_signed_message(&challenge_signed)?;
+
+        //get agent, anyone can copy a mid so a challenge the requestor did not sign changes nothing
+        let (agent_did, mut agent) = find_agent(&self.potential_partners, &challenge.mid)?;
+        if !self.is_signed_by(&challenge_signed, &agent_did).await? {
+            return Err(AwakeError::SignatureFailure("challenge was not signed by the did it was sent from".to_string()));
+        }
+        if let Err(e) = expect_version(&challenge.awv, &agent.awv) {
+            return self.reject(agent, e).await;
+        }
+        
+        //anything wrong with the challenge is reported back with an awake/fin
+        let challenge_payload = match decrypt_challenge(&mut agent, &challenge).await {
             Ok(x) => x,
-            Err(_) => {
-                warn(&format!("challenge was not sent in the proper json format: \n{}", challenge_str));
-                return None;
-            }
+            Err(e) => return self.reject(agent, e).await
+        };
+        let pending = match self.potential_requests.get(&agent_did) {
+            Some(x) => x.clone(),
+            None => return self.reject(agent, AwakeError::HandshakeIncomplete).await
+        };
+        if let Err(e) = self.check_challenge(&pending, &agent_did, &challenge_payload, is_proof_valid).await {
+            return self.reject(agent, e).await;
+        }
+        let real_forien_did = challenge_payload.did.as_str();
+
+        //finalize the agent using the requestor's real key and our next did, the keys are bound to the transcript
+        agent.add_to_transcript(&challenge_signed).await?;
+        let self_next_did = crypto_key_to_did_key(&self.crypto, &self.step_4_public).await?;
+        agent.finalize(&self.step_4_private, self.step_4_private.clone(), &self_next_did, real_forien_did, &challenge_payload.nextdid).await?;
+        agent.peer_did = Some(real_forien_did.to_string());
+
+        //build the acknowledgement and encrypt it with the finalized agent
+        let ack_plain = AckPayload::new(&agent.awv, real_forien_did.to_string(), agent.confirmation(false).await?);
+        let (ack_mid, ack_encrypted) = agent.encrypt_for(write_message(&ack_plain)?, &[]).await?;
+        //the requestor already knows our real did from the ucan so the ack can name it
+        let ack = write_message(&Msg::new(&agent.awv, ack_mid, ack_encrypted.as_base64()))?.sign(&self.crypto, &self.real_private, Some(&self.real_did)).await?;
+
+        //the handshake is over so the agent is no longer a potential partner
+        self.potential_partners.remove(&agent_did);
+        self.potential_requests.remove(&agent_did);
+        self.final_agent = Some(agent);
+        return Ok(ack);
+    }
+    //Checks the responder's ucan was issued to us, that the response was signed by the did it came from and that we accept it
+    async fn check_response(&self, 
+        response_signed:&Transitable,
+        ucan_parsed:&Ucan,
+        ucan:&Value,
+        is_ucan_valid: impl Fn(&Value) -> Result<bool, AwakeError>
+    ) -> Result<(ChallengeType, String), AwakeError>{
+        let self_step_2_did = crypto_key_to_did_key(&self.crypto, &self.step_2_public).await?;
+        validate_ucan(&self.crypto, ucan_parsed, &self_step_2_did).await?;
+        let response:Response = read_signed_message(response_signed)?;
+        if !self.is_signed_by(response_signed, &response.iss).await? {
+            return Err(AwakeError::ResponseSignatureFailure);
+        }
+        let challenge = match get_fact(ucan, "awake/challenge") {
+            Some(x) => ChallengeType::parse(&x)?,
+            None => return Err(AwakeError::MissingFact("awake/challenge".to_string()))
         };
 
-        //get agent
-        let challenge_mid = match challenge_map["mid"].as_str(){
+        //check if ucan is valid
+        if !is_ucan_valid(ucan)? { 
+            return Err(AwakeError::UcanRejected);
+        }
+
+        //remember the did the responder will use for the rest of the session
+        let forien_next_did = match get_fact(ucan, "awake/nextdid"){
             Some(x) => x,
-            None => {
-                warn("challenge was not sent in the proper json format. The 'mid' field could not be found.");
-                return None;
-            }
-        }.to_string();
-        let mut agent = find_agent(&self.crypto, &self.step_2_public, &self.potential_partners, &challenge_mid).await;
-        
-        //get challenge msg
-        let challenge_msg_encrypted = match challenge_map["msg"].as_str(){
-            Some(x) => Transitable::from_base64(x),
-            None => {
-                warn("challenge was not sent in the proper json format. The 'msg' field could not be found.");
-                return None;
-            }
+            None => return Err(AwakeError::MissingFact("awake/nextdid".to_string()))
         };
-        let challenge_msg_str = agent.decrypt_for(0, challenge_msg_encrypted).await.as_readable().unwrap();
-        let challenge_msg_map:Value = match serde_json::from_str(&challenge_msg_str){
+        return Ok((challenge, forien_next_did));
+    }
+    //Checks the requestor's real key signed its proof for the request it sent and that the proof is the one we asked for
+    async fn check_challenge(&self, 
+        pending:&PendingRequest,
+        forien_did:&str, //the requestor's step 2 did that signed the request
+        challenge_payload:&ChallengePayload,
+        is_proof_valid: impl Fn(&Value) -> Result<bool, AwakeError>
+    ) -> Result<(), AwakeError>{
+        //the requestor's real did is now known so check it signed its proof
+        let real_forien_did = challenge_payload.did.as_str();
+        let real_forien_key = did_key_to_crypto_key(&self.crypto, real_forien_did).await?;
+        let proof = match pending.challenge {
+            ChallengeType::None => "",
+            ChallengeType::OobPin => required(&challenge_payload.pin, "pin")?,
+            ChallengeType::UcanProof => required(&challenge_payload.ucan, "ucan")?
+        };
+        let self_did = self.real_did.as_str();
+        let mut hash_data:Vec<u8> = vec![];
+        hash_data.append(&mut did_key_to_bytes(self_did)?);
+        hash_data.append(&mut did_key_to_bytes(forien_did)?);
+        hash_data.append(&mut proof.as_bytes().to_vec());
+        let hash = hash(&self.crypto, &hash_data).await?;
+        let signature = match base64::decode(&challenge_payload.sig) {
             Ok(x) => x,
-            Err(_) => {
-                warn(&format!("challenge message was not sent in the proper json format: \n{}", challenge_msg_str));
-                return None;
-            }
+            Err(e) => return Err(AwakeError::BadEncoding(format!("challenge signature is not valid base64: {}", e)))
         };
+        if !verify(&self.crypto, &real_forien_key, &hash, &signature).await? {
+            return Err(AwakeError::SignatureFailure("challenge proof was not signed by the requestor's did".to_string()));
+        }
 
-        //check if pin is valid
-        let pin = challenge_msg_map["pin"].as_str().unwrap();
-        let pin_js = JsValue::from(pin);
-        let is_sender_capable = is_pin_valid.call1(&pin_js, &pin_js).unwrap();
-        if !is_sender_capable.as_bool().unwrap() { 
-            warn("Failed to verify sender's pin");
-            return None;
+        //check the proof is valid
+        match pending.challenge {
+            ChallengeType::None => (),
+            ChallengeType::OobPin => {
+                if !is_proof_valid(&json!(proof))? { 
+                    return Err(AwakeError::PinRejected);
+                }
+            },
+            ChallengeType::UcanProof => {
+                let proofs = match &challenge_payload.prf {
+                    Some(x) => x,
+                    None => return Err(AwakeError::MalformedJson("challenge is missing its prf array".to_string()))
+                };
+                let chain = validate_delegation_chain(&self.crypto, proof, proofs, self_did, real_forien_did).await?;
+                if !is_proof_valid(&chain)? {
+                    return Err(AwakeError::ProofRejected("rejected by the validator".to_string()));
+                }
+            }
         }
+        return Ok(());
+    }
+    //The ack's mid is the first of the finalized session and was worked out when the challenge was sent,
+    //so the responder it came from is found without doing any crypto
+    fn find_acknowledged(&self, mid:&str) -> Result<String, AwakeError>{
+        for (agent_did, sent) in &self.challenged {
+            if sent.ack_mid == mid && self.potential_partners.contains_key(agent_did) {
+                return Ok(agent_did.clone());
+            }
+        }
+        return Err(AwakeError::UnknownMid(mid.to_string()));
+    }
+    //Our real key pair as a ucan issuer, keys are Copy outside the browser
+    fn real_issuer(&self) -> UcanEcdhKey {
+        #[cfg(target_arch = "wasm32")]
+        let real_public = self.real_public.clone();
+        #[cfg(not(target_arch = "wasm32"))]
+        let real_public = self.real_public;
+        return UcanEcdhKey::from(real_public, self.real_private.clone());
+    }
+    //Tells the other agent why we stopped with an awake/fin, forgets them and passes the error back out.
+    //The awake/fin is kept until it is collected with take_failure
+    async fn reject<T>(&mut self, mut agent:ForeignAgent, error:AwakeError) -> Result<T, AwakeError>{
+        self.potential_partners.remove(&agent.did);
+        self.potential_requests.remove(&agent.did);
+        self.challenged.remove(&agent.did);
+        self.failure = None;
+        //if the fin can not be built the other agent is never told, so that is the error worth returning
+        self.failure = Some(self.build_failure(&mut agent, FinReason::from_error(&error)).await?);
+        return Err(error);
+    }
+    async fn build_failure(&self, agent:&mut ForeignAgent, reason:FinReason) -> Result<Transitable, AwakeError>{
+        let fin_plain = FinPayload::new(&agent.awv, reason.as_str());
+        //before the handshake is finished the mid is the index of the message in the step 2 chain
+        let (fin_mid, fin_encrypted) = agent.encrypt_for(write_message(&fin_plain)?, &[]).await?;
+        let self_did = crypto_key_to_did_key(&self.crypto, &self.step_2_public).await?;
+        let fin = Fin::new(&agent.awv, agent.did.clone(), self_did.clone(), fin_mid, fin_encrypted.as_base64());
+        //the other agent may not know our real did yet, so a fin is signed with our step 2 key
+        return write_message(&fin)?.sign(&self.crypto, &self.step_2_private, Some(&self_did)).await;
+    }
+    //Checks a message was signed by the key of did and, if it names its signer, that it names that did
+    async fn is_signed_by(&self, message_signed:&Transitable, did:&str) -> Result<bool, AwakeError>{
+        if let Some(kid) = message_signed.kid()? {
+            if kid != did {
+                return Ok(false);
+            }
+        }
+        let key = did_key_to_crypto_key(&self.crypto, did).await?;
+        return message_signed.verify_with(&self.crypto, &key, self.accept_legacy_jws).await;
+    }
+}
+async fn check_acknowledgement(agent:&mut ForeignAgent, ack:Msg, ack_msg_encrypted:Transitable, self_did:&str) -> Result<(), AwakeError>{
+    expect_version(&ack.awv, &agent.awv)?;
+
+    //check that it is us that has been acknowledged
+    let ack_payload:AckPayload = read_message(&agent.decrypt_with_mid(ack.mid, ack_msg_encrypted, &[]).await?)?;
+    expect_version(&ack_payload.awv, &agent.awv)?;
+    if ack_payload.did != self_did {
+        return Err(AwakeError::UnexpectedMessage("acknowledgement message was not for this requestor".to_string()));
+    }
+    //the responder has to have seen exactly the handshake we did
+    if ack_payload.conf != agent.confirmation(false).await? {
+        return Err(AwakeError::KeyConfirmationFailure);
+    }
+    return Ok(());
+}
+async fn decrypt_challenge(agent:&mut ForeignAgent, challenge:&Msg) -> Result<ChallengePayload, AwakeError>{
+    let challenge_msg_encrypted = Transitable::from_base64(&challenge.msg)?;
+    let challenge_payload:ChallengePayload = read_message(&agent.decrypt_with_mid(challenge.mid.clone(), challenge_msg_encrypted, &[]).await?)?;
+    expect_version(&challenge_payload.awv, &agent.awv)?;
+    return Ok(challenge_payload);
+}
+async fn process_encrypted_ucan(agent:&mut ForeignAgent, encrypted_ucan_str:&str) -> Result<(Ucan, Value), AwakeError>{
+    let encrypted_ucan = Transitable::from_base64(encrypted_ucan_str)?;
+    let ucan_signed = match agent.decrypt_for(0, encrypted_ucan).await?.as_readable() {
+        Some(x) => x,
+        None => return Err(AwakeError::BadEncoding("handshake ucan was not utf8".to_string()))
+    };
+    return read_ucan(&ucan_signed);
+}
+fn read_ucan(ucan_signed:&str) -> Result<(Ucan, Value), AwakeError>{
+    //ucans are encoded with unpadded url safe base64 rather than the format used by Transitable
+    let ucan_payload_b64 = match ucan_signed.split(".").nth(1) {
+        Some(x) => x,
+        None => return Err(AwakeError::BadJwt("handshake ucan had no payload section".to_string()))
+    };
+    let ucan_payload = match base64::decode_config(ucan_payload_b64, base64::URL_SAFE_NO_PAD) {
+        Ok(x) => x,
+        Err(e) => return Err(AwakeError::BadJwt(format!("handshake ucan payload is not valid base64: {}", e)))
+    };
+    let ucan_value = match serde_json::from_slice(&ucan_payload) {
+        Ok(x) => x,
+        Err(e) => return Err(AwakeError::MalformedJson(e.to_string()))
+    };
+    return match Ucan::try_from_token_string(ucan_signed) {
+        Ok(x) => Ok((x, ucan_value)),
+        Err(e) => Err(AwakeError::BadUcan(e.to_string()))
+    };
+}
+async fn build_ucan(issuer:&UcanEcdhKey, audience:&str, expiration:u64, facts:Vec<Value>, proofs:&[Ucan]) -> Result<String, AwakeError>{
+    let mut builder = UcanBuilder::default()
+        .issued_by(issuer)
+        .for_audience(audience)
+        .with_expiration(expiration);
+    for fact in facts {
+        builder = builder.with_fact(fact);
+    }
+    for proof in proofs {
+        builder = builder.witnessed_by(proof);
+    }
+    let ucan = match builder.build() {
+        Ok(x) => x,
+        Err(e) => return Err(AwakeError::Ucan(format!("could not build ucan: {}", e)))
+    };
+    return match ucan.sign().await.and_then(|x| x.encode()) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(AwakeError::Ucan(format!("could not sign ucan: {}", e)))
+    };
+}
+//Checks a ucan challenge, the leaf must go from the requestor to us and every proof it points at must be
+//valid and issued to the agent below it. Passes back the payloads of the whole chain, leaf first
+async fn validate_delegation_chain(crypto:&Backend, leaf:&str, proofs:&[String], self_did:&str, requestor_did:&str) -> Result<Value, AwakeError>{
+    let (leaf_ucan, leaf_value) = read_ucan(leaf)?;
+    validate_ucan(crypto, &leaf_ucan, self_did).await?;
+    if leaf_ucan.issuer() != requestor_did {
+        return Err(AwakeError::ProofRejected("the challenge ucan was not issued by the requestor".to_string()));
+    }
 
-        //make mid_prefix
-        let real_forien_did = challenge_msg_map["did"].as_str().unwrap();
-        let mut mid_prefix:Vec<u8> = vec![];
-        //mid_prefix.append(did_key_to_bytes(real_forien_did), 
+    //proofs are referenced by cid so index the ones that were sent
+    let mut proofs_by_cid:HashMap<String, (Ucan, Value)> = HashMap::new();
+    for proof in proofs {
+        let proof = read_ucan(proof)?;
+        let cid = match Cid::try_from(&proof.0) {
+            Ok(x) => x.to_string(),
+            Err(e) => return Err(AwakeError::BadUcan(e.to_string()))
+        };
+        proofs_by_cid.insert(cid, proof);
+    }
 
-        return None
+    let mut chain = vec![leaf_value];
+    let mut to_check = vec![leaf_ucan];
+    while let Some(child) = to_check.pop() {
+        for cid in child.proofs() {
+            //each proof is only used once so a chain can not loop
+            let (proof, proof_value) = match proofs_by_cid.remove(cid) {
+                Some(x) => x,
+                None => return Err(AwakeError::ProofRejected(format!("the proof {} was not sent", cid)))
+            };
+            validate_ucan(crypto, &proof, child.issuer()).await?;
+            chain.push(proof_value);
+            to_check.push(proof);
+        }
     }
-    pub fn is_done(&self) -> bool {
-        self.final_agent.is_some()
+    return Ok(Value::Array(chain));
+}
+//ucan's DidParser only knows multicodec did:keys so the signature is checked with our own key type
+async fn validate_ucan(crypto:&Backend, ucan:&Ucan, audience:&str) -> Result<(), AwakeError>{
+    if ucan.is_expired() {
+        return Err(AwakeError::UcanExpired);
+    }
+    if ucan.is_too_early() {
+        return Err(AwakeError::UcanNotYetValid);
+    }
+    if ucan.audience() != audience {
+        return Err(AwakeError::UcanWrongAudience(ucan.audience().to_string()));
     }
+    let issuer = UcanEcdhKey::from_did(crypto, ucan.issuer()).await?;
+    if issuer.verify(ucan.signed_data(), ucan.signature()).await.is_err() {
+        return Err(AwakeError::UcanSignatureFailure);
+    }
+    return Ok(());
 }
-async fn process_encrypted_ucan(agent:&mut ForeignAgent, encrypted_ucan_str:&str, msg_count:usize) -> Value{
-    let encrypted_ucan = Transitable::from_base64(encrypted_ucan_str);
-    let ucan_signed = agent.decrypt_for(0, encrypted_ucan).await;
-    let ucan_payload_str = ucan_signed.unsign().as_readable().unwrap();
-    return serde_json::from_str(&ucan_payload_str).unwrap()
+fn get_fact(ucan:&Value, fact_name:&str) -> Option<String>{
+    for fact in ucan["fct"].as_array()? {
+        if let Some(x) = fact[fact_name].as_str() {
+            return Some(x.to_string());
+        }
+    }
+    return None;
 }
-fn capabilities_to_value(capabilities:Array) -> Value{
+fn capabilities_from_array(capabilities:Array) -> Result<Vec<UcanCapability>, AwakeError>{
     let mut caps:Vec<UcanCapability> = vec![];
     for cap in capabilities.to_vec() {
-        caps.push(UcanCapability::from_object(&cap.dyn_into().unwrap()));
+        let cap_object:Object = match cap.dyn_into() {
+            Ok(x) => x,
+            Err(_) => return Err(AwakeError::BadCapability("capabilities must be objects".to_string()))
+        };
+        caps.push(UcanCapability::from_object(&cap_object)?);
     }
-    return serde_json::to_value(&caps).unwrap();
+    return Ok(caps);
+}
+fn capabilities_to_value(capabilities:&[UcanCapability]) -> Result<Value, AwakeError>{
+    return match serde_json::to_value(capabilities) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(AwakeError::BadCapability(e.to_string()))
+    };
 }
-async fn find_agent(crypto:&SubtleCrypto, self_key:&CryptoKey, agents:&HashMap<String, ForeignAgent>, mid:&str) -> ForeignAgent{
+//Each potential partner knows the mids its next messages will have
+fn find_agent(agents:&HashMap<String, ForeignAgent>, mid:&str) -> Result<(String, ForeignAgent), AwakeError>{
     for (agent_did, agent) in agents{
-        let agent_key = did_key_to_crypto_key(crypto, &agent_did).await;
-        let comp_mid = base64::encode(get_message_id(crypto, &agent_key, self_key, None).await);
-        if mid == comp_mid {return agent.clone()}
+        if agent.knows_mid(mid) {return Ok((agent_did.clone(), agent.clone()))}
     }
-    panic!("cpuld not find agent using given mid {}", mid);
+    return Err(AwakeError::UnknownMid(mid.to_string()));
+}
+//Optional fields of the challenge payload that the challenge type makes required
+fn required<'a>(field:&'a Option<String>, name:&str) -> Result<&'a str, AwakeError>{
+    return match field {
+        Some(x) => Ok(x),
+        None => Err(AwakeError::MalformedJson(format!("the '{}' field could not be found", name)))
+    };
+}
+fn value_to_js(value:&Value) -> Result<JsValue, AwakeError>{
+    let value_str = match serde_json::to_string(value) {
+        Ok(x) => x,
+        Err(e) => return Err(AwakeError::MalformedJson(e.to_string()))
+    };
+    return match JSON::parse(&value_str) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(AwakeError::MalformedJson(format!("{:?}", e)))
+    };
+}
+fn call_validator(validator:&Function, arg:&JsValue) -> Result<bool, AwakeError>{
+    let is_valid = match validator.call1(arg, arg) {
+        Ok(x) => x,
+        Err(e) => return Err(AwakeError::Callback(format!("{:?}", e)))
+    };
+    return match is_valid.as_bool() {
+        Some(x) => Ok(x),
+        None => Err(AwakeError::Callback("validator did not return a boolean".to_string()))
+    };
 }
-fn warn(msg:&str){
-    web_sys::console::warn_1(&JsValue::from(msg));
-}
\ No newline at end of file
@@ -1,8 +1,17 @@
-#![cfg(target_arch = "wasm32")]
+//Functions end with an explicit return, that is the house style so clippy is told not to flag it
+#![allow(clippy::needless_return)]
 
+pub mod backend;
 pub mod utils;
+pub mod error;
 pub mod handshake;
 pub mod ratchet;
+pub mod double_ratchet;
 pub mod foreign_agent;
+pub mod mid_window;
 pub mod transitable;
+pub mod messages;
+pub mod session;
+pub mod router;
+pub mod store;
 mod ucan_ecdh_key;
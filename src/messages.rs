@@ -0,0 +1,280 @@
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+use crate::transitable::Transitable;
+use crate::utils::UcanCapability;
+use crate::error::AwakeError;
+
+//The newest protocol version this agent speaks, used until a version has been agreed with the other agent
+pub const AWV:&str = "0.1.0";
+//Every version this agent can speak, newest first
+pub const SUPPORTED_VERSIONS:&[&str] = &[AWV];
+
+//Every message with a type field, each also carries the awv it was written with
+pub trait Message: Serialize + DeserializeOwned {
+    const TYPE:&'static str;
+}
+
+//Just enough of a message to tell what it is before reading the rest
+#[derive(Clone, Debug, Deserialize)]
+pub struct Header {
+    pub awv: Option<String>, //only a legacy awake/init may leave it out
+    #[serde(rename = "type")]
+    pub message_type: String
+}
+
+//Part 3.2, sent in the clear and signed with the requestor's real key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Init {
+    #[serde(default = "default_version")]
+    pub awv: String, //requestors from before versions were negotiated may leave it out, they only spoke the first version
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub did: String, //the requestor's step 2 did
+    pub caps: Vec<UcanCapability>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub versions: Vec<String> //every version the requestor speaks, older requestors leave it out
+}
+impl Init {
+    pub fn new(did:String, caps:Vec<UcanCapability>) -> Init {
+        let versions = SUPPORTED_VERSIONS.iter().map(|x| x.to_string()).collect();
+        return Init{awv: AWV.to_string(), message_type: Init::TYPE.to_string(), did, caps, versions};
+    }
+    //A requestor that does not list its versions only speaks the one it wrote the request in
+    pub fn offered_versions(&self) -> Vec<String> {
+        if self.versions.is_empty() {
+            return vec![self.awv.clone()];
+        }
+        return self.versions.clone();
+    }
+}
+impl Message for Init {
+    const TYPE:&'static str = "awake/init";
+}
+
+//Part 3.3, msg is the responder's ucan encrypted for the requestor's step 2 did
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub awv: String,
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub aud: String,
+    pub iss: String,
+    pub msg: String
+}
+impl Response {
+    pub fn new(awv:&str, aud:String, iss:String, msg:String) -> Response {
+        return Response{awv: awv.to_string(), message_type: Response::TYPE.to_string(), aud, iss, msg};
+    }
+}
+impl Message for Response {
+    const TYPE:&'static str = "awake/res";
+}
+
+//Carries the challenge, the acknowledgement and every session message, msg is encrypted
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Msg {
+    pub awv: String,
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub mid: String,
+    pub msg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conf: Option<String> //the requestor's key confirmation, on its messages until it has read one from the responder
+}
+impl Msg {
+    pub fn new(awv:&str, mid:String, msg:String) -> Msg {
+        return Msg{awv: awv.to_string(), message_type: Msg::TYPE.to_string(), mid, msg, conf: None};
+    }
+}
+impl Message for Msg {
+    const TYPE:&'static str = "awake/msg";
+}
+
+//Part 3.4, the encrypted part of the requestor's challenge. Which proof is present depends on the challenge type
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ChallengePayload {
+    pub awv: String,
+    #[serde(rename = "type")]
+    pub message_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pin: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ucan: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prf: Option<Vec<String>>,
+    pub did: String, //the requestor's real did
+    pub nextdid: String, //the requestor's step 4 did, the session's ratchet and rekeys start from it
+    pub sig: String
+}
+impl ChallengePayload {
+    //The proof, dids and signature are filled in once the challenge type is known
+    pub fn new(awv:&str) -> ChallengePayload {
+        return ChallengePayload{awv: awv.to_string(), message_type: ChallengePayload::TYPE.to_string(), ..Default::default()};
+    }
+}
+impl Message for ChallengePayload {
+    const TYPE:&'static str = "awake/challenge";
+}
+
+//Part 3.5, the encrypted part of the acknowledgement
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AckPayload {
+    pub awv: String,
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub did: String, //the requestor that has been acknowledged
+    pub conf: String //the responder's key confirmation
+}
+impl AckPayload {
+    pub fn new(awv:&str, did:String, conf:String) -> AckPayload {
+        return AckPayload{awv: awv.to_string(), message_type: AckPayload::TYPE.to_string(), did, conf};
+    }
+}
+impl Message for AckPayload {
+    const TYPE:&'static str = "awake/ack";
+}
+
+//Sent when a handshake step is rejected, msg is the encrypted FinPayload
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Fin {
+    pub awv: String,
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub aud: String,
+    pub iss: String,
+    pub mid: String,
+    pub msg: String
+}
+impl Fin {
+    pub fn new(awv:&str, aud:String, iss:String, mid:String, msg:String) -> Fin {
+        return Fin{awv: awv.to_string(), message_type: Fin::TYPE.to_string(), aud, iss, mid, msg};
+    }
+}
+impl Message for Fin {
+    const TYPE:&'static str = "awake/fin";
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FinPayload {
+    pub awv: String,
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub reason: String
+}
+impl FinPayload {
+    pub fn new(awv:&str, reason:&str) -> FinPayload {
+        return FinPayload{awv: awv.to_string(), message_type: FinPayload::TYPE.to_string(), reason: reason.to_string()};
+    }
+}
+impl Message for FinPayload {
+    const TYPE:&'static str = "awake/fin";
+}
+
+//Starts a session's ratchet again, msg is the encrypted RekeyPayload
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rekey {
+    pub awv: String,
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub mid: String,
+    pub msg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conf: Option<String> //same as Msg, in case the requestor rekeys before sending anything
+}
+impl Rekey {
+    pub fn new(awv:&str, mid:String, msg:String) -> Rekey {
+        return Rekey{awv: awv.to_string(), message_type: Rekey::TYPE.to_string(), mid, msg, conf: None};
+    }
+}
+impl Message for Rekey {
+    const TYPE:&'static str = "awake/rekey";
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RekeyPayload {
+    pub awv: String,
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub key: String //the new ratchet public key as base64
+}
+impl RekeyPayload {
+    pub fn new(awv:&str, key:String) -> RekeyPayload {
+        return RekeyPayload{awv: awv.to_string(), message_type: RekeyPayload::TYPE.to_string(), key};
+    }
+}
+impl Message for RekeyPayload {
+    const TYPE:&'static str = "awake/rekey";
+}
+
+pub fn write_message<T:Serialize>(message:&T) -> Result<Transitable, AwakeError> {
+    return match serde_json::to_string(message) {
+        Ok(x) => Ok(Transitable::from_readable(&x)),
+        Err(e) => Err(AwakeError::MalformedJson(e.to_string()))
+    };
+}
+//Reads json without checking its type or version, only for peeking at a message before it is read properly
+pub fn read_as<T:DeserializeOwned>(message:&Transitable) -> Result<T, AwakeError> {
+    let message_str = match message.as_readable() {
+        Some(x) => x,
+        None => return Err(AwakeError::BadEncoding("message was not utf8".to_string()))
+    };
+    return match serde_json::from_str(&message_str) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(AwakeError::MalformedJson(format!("{}: \n{}", e, message_str)))
+    };
+}
+//Checks the type and version before reading the rest so the error says what was actually wrong
+pub fn read_message<T:Message>(message:&Transitable) -> Result<T, AwakeError> {
+    let header:Header = read_as(message)?;
+    if header.message_type != T::TYPE {
+        return Err(AwakeError::UnexpectedMessage(format!("expected {} but got {}", T::TYPE, header.message_type)));
+    }
+    match &header.awv {
+        Some(awv) => check_version(awv)?,
+        None if T::TYPE == Init::TYPE => (),
+        None => return Err(AwakeError::MalformedJson(format!("{} has no awv", T::TYPE)))
+    };
+    return read_as(message);
+}
+pub fn read_signed_message<T:Message>(message_signed:&Transitable) -> Result<T, AwakeError> {
+    return read_message(&message_signed.unsign()?);
+}
+//Checks a message is in a version this agent speaks at all
+pub fn check_version(awv:&str) -> Result<(), AwakeError> {
+    for supported in SUPPORTED_VERSIONS {
+        if is_compatible(awv, supported) {
+            return Ok(());
+        }
+    }
+    return Err(AwakeError::UnsupportedVersion(awv.to_string()));
+}
+//Checks a message is in the version agreed on during the handshake
+pub fn expect_version(awv:&str, agreed:&str) -> Result<(), AwakeError> {
+    if !is_compatible(awv, agreed) {
+        return Err(AwakeError::UnsupportedVersion(format!("{} when {} was agreed", awv, agreed)));
+    }
+    return Ok(());
+}
+//Picks the newest version both agents speak, written the way this agent writes it
+pub fn negotiate_version(offered:&[String]) -> Result<String, AwakeError> {
+    for supported in SUPPORTED_VERSIONS {
+        if offered.iter().any(|x| is_compatible(x, supported)) {
+            return Ok(supported.to_string());
+        }
+    }
+    return Err(AwakeError::UnsupportedVersion(offered.join(", ")));
+}
+pub fn default_version() -> String {
+    return AWV.to_string();
+}
+//Before 1.0 a minor version can change the wire format so only the patch version is allowed to differ
+fn is_compatible(awv:&str, other:&str) -> bool {
+    return major_minor(awv).is_some() && major_minor(awv) == major_minor(other);
+}
+fn major_minor(version:&str) -> Option<(u64, u64)> {
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    return Some((major, minor));
+}
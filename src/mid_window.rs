@@ -0,0 +1,101 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+use crate::backend::{CryptoBackend, SharedSecret};
+use crate::ratchet::SkipLimits;
+use crate::utils::fetch_backend;
+use crate::error::AwakeError;
+
+//hkdf info for the mid key of each direction
+pub const REQUESTOR_MID_INFO:&[u8] = b"awake/mid/requestor";
+pub const RESPONDER_MID_INFO:&[u8] = b"awake/mid/responder";
+//how many mids past the newest message are worked out for a finalized agent, set_skip_limits moves it to max_skip
+pub const MID_WINDOW:usize = 64;
+//the handshake never gets more than a message or two ahead
+pub const HANDSHAKE_MID_WINDOW:usize = 8;
+
+pub async fn derive_mid_key(secret:&SharedSecret, salt:&[u8], info:&[u8]) -> Result<Vec<u8>, AwakeError> {
+    return fetch_backend()?.hkdf(secret, salt, info, 32).await;
+}
+//A mid is the hmac of the message's index in its direction, without the key mids can not be linked to each other
+pub async fn mid_for(key:&[u8], id:usize) -> Result<String, AwakeError> {
+    //always 8 bytes so wasm32 and 64 bit targets agree
+    let mac = fetch_backend()?.hmac(key, &(id as u64).to_be_bytes()).await?;
+    return Ok(base64::encode(mac));
+}
+
+//The mids the other agent will send next, worked out ahead of time so finding a message's index is a single lookup.
+//Behind the newest message only the mids of messages that were skipped are kept, like the ratchet's skipped keys
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MidWindow {
+    key: Vec<u8>,
+    next_id: usize, //the lowest index that has not been worked out yet, always size past the newest message
+    known: HashMap<String, usize>,
+    #[serde(default = "default_size")]
+    size: usize, //how many mids past the newest message are known, a message further ahead than this is unknown
+    #[serde(default = "default_max_skipped")]
+    max_skipped: usize //how many mids of skipped messages are kept before the oldest are dropped
+}
+impl MidWindow {
+    pub async fn new(key:Vec<u8>, size:usize) -> Result<MidWindow, AwakeError> {
+        let mut window = MidWindow{key, next_id: 0, known: HashMap::new(), size, max_skipped: default_max_skipped()};
+        window.fill(0).await?;
+        return Ok(window);
+    }
+    //Follows the ratchet's limits, no further ahead than it will skip and no more skipped mids than it keeps keys for
+    pub async fn set_limits(&mut self, limits:SkipLimits) -> Result<(), AwakeError> {
+        let head = self.head();
+        self.size = limits.max_skip;
+        self.max_skipped = limits.max_skipped;
+        if self.next_id > head + self.size {
+            self.next_id = head + self.size;
+            let next_id = self.next_id;
+            self.known.retain(|_, x| *x < next_id);
+        }
+        self.fill(head).await?;
+        self.forget_skipped();
+        return Ok(());
+    }
+    pub fn resolve(&self, mid:&str) -> Option<usize> {
+        return self.known.get(mid).copied();
+    }
+    //Forgets the mid of the message at id once it has been read and moves the window on past it
+    pub async fn advance(&mut self, id:usize) -> Result<(), AwakeError> {
+        self.known.retain(|_, x| *x != id);
+        self.fill(id + 1).await?;
+        self.forget_skipped();
+        return Ok(());
+    }
+    //One past the newest message read, anything below it that is still known was skipped
+    fn head(&self) -> usize {
+        return self.next_id.saturating_sub(self.size);
+    }
+    async fn fill(&mut self, from:usize) -> Result<(), AwakeError> {
+        while self.next_id < from + self.size {
+            self.known.insert(mid_for(&self.key, self.next_id).await?, self.next_id);
+            self.next_id += 1;
+        }
+        return Ok(());
+    }
+    fn forget_skipped(&mut self) {
+        let head = self.head();
+        let mut skipped:Vec<usize> = self.known.values().copied().filter(|x| *x < head).collect();
+        if skipped.len() <= self.max_skipped {
+            return;
+        }
+        skipped.sort_unstable();
+        let oldest_kept = match self.max_skipped {
+            0 => head,
+            n => skipped[skipped.len() - n]
+        };
+        self.known.retain(|_, x| *x >= oldest_kept);
+    }
+}
+
+//windows saved before they were sized had the fixed window
+fn default_size() -> usize {
+    return MID_WINDOW;
+}
+fn default_max_skipped() -> usize {
+    return SkipLimits::default().max_skipped;
+}
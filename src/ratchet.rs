@@ -1,174 +1,213 @@
-use js_sys::{Uint8Array, ArrayBuffer, Array};
-use std::collections::HashMap;
-use wasm_bindgen::{JsCast, JsValue};
-use wasm_bindgen_futures::JsFuture;
-use web_sys::CryptoKey;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
 
+use crate::backend::{CryptoBackend, SharedSecret};
 use crate::transitable::Transitable;
-use crate::utils::{js_objectify, fetch_subtle_crypto, u8_iter_js_array};
+use crate::utils::fetch_backend;
+use crate::error::AwakeError;
 
-#[derive(Clone)]
+//How many message keys a recieving chain holds on to for messages that have not arrived yet
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SkipLimits {
+    pub max_skip: usize, //how far ahead of the chain a single message may be
+    pub max_skipped: usize, //how many skipped keys are kept before the oldest are dropped
+    pub max_age: usize //how many messages can be processed before a skipped key is dropped
+}
+impl Default for SkipLimits {
+    fn default() -> SkipLimits {
+        return SkipLimits{
+            max_skip: 1000,
+            max_skipped: 1000,
+            max_age: 10000
+        };
+    }
+}
+
+//A chain of message keys. Only the next link is kept, along with the keys of any links a reciever
+//has passed over, so the chain does not grow with the number of messages
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Ratchet{
     is_encrypting:bool,
-    secret_chain:Vec<PayloadHandler>
+    head_id:usize,
+    head:Option<PayloadHandler>, //none once the chain has been retired
+    skipped:BTreeMap<usize, SkippedKey>,
+    processed:usize, //how many messages have been through the chain, skipped keys are aged by it
+    limits:SkipLimits
 }
 impl Ratchet{
-    pub async fn new(shared_secret:CryptoKey, is_encrypting:bool, salt:Vec<u8>) -> Ratchet{
-        let mut ratchet = Ratchet{
+    pub async fn new(shared_secret:SharedSecret, is_encrypting:bool, salt:Vec<u8>) -> Result<Ratchet, AwakeError>{
+        return Ratchet::with_info(shared_secret, is_encrypting, salt, &[]).await;
+    }
+    //info is used for the first link only, chains from the same secret and salt with different info share no keys
+    pub async fn with_info(shared_secret:SharedSecret, is_encrypting:bool, salt:Vec<u8>, info:&[u8]) -> Result<Ratchet, AwakeError>{
+        return Ok(Ratchet{
             is_encrypting,
-            secret_chain: vec![]
-        };
-        ratchet.secret_chain.push(PayloadHandler::new(shared_secret, salt, None).await);
-        return ratchet;
+            head_id: 0,
+            head: Some(PayloadHandler::new(shared_secret, salt, info).await?),
+            skipped: BTreeMap::new(),
+            processed: 0,
+            limits: SkipLimits::default()
+        });
     }
-    pub async fn process_payload(&mut self, id:usize, payload:Transitable) -> Result<Transitable, String>{
-        //web_sys::console::log_1(&JsValue::from(id));
-        self.gen_handlers_to(id).await;
-        let ret = self.secret_chain[id].proccess_payload(self.is_encrypting, payload).await;
-        if !self.is_encrypting {self.secret_chain[id].empty_msg_keys()}
-        return ret;
+    pub fn set_limits(&mut self, limits:SkipLimits){
+        self.limits = limits;
+        self.evict();
     }
-    pub fn empty_decryptor(&mut self, id:usize){
-        self.secret_chain[id].empty_msg_keys();
+    pub async fn process_payload(&mut self, id:usize, payload:Transitable) -> Result<Transitable, AwakeError>{
+        return self.process_payload_with_aad(id, &[], payload).await;
     }
-    pub async fn set_new_shared_key(&mut self, start_id:usize, shared_secret:CryptoKey){
-        self.gen_handlers_to(start_id-1).await;
-        if self.secret_chain[start_id-1].has_created_next() || self.secret_chain.len() > start_id{
-            panic!("Note: PayloadHandlers have already been gennerated at or beyond the id {} and a new key set cannot be started", start_id);
+    //aad has to be the same on both sides for the message to decrypt
+    pub async fn process_payload_with_aad(&mut self, id:usize, aad:&[u8], payload:Transitable) -> Result<Transitable, AwakeError>{
+        //a message from behind the head can only be processed with a key that was kept when it was skipped
+        if id < self.head_id {
+            let skipped = match self.skipped.remove(&id) {
+                Some(x) => x,
+                None => return Err(AwakeError::Replay(id))
+            };
+            return match skipped.message_key.process(id, self.is_encrypting, aad, payload).await {
+                Ok(x) => {
+                    self.processed += 1;
+                    self.evict();
+                    Ok(x)
+                },
+                Err(e) => {
+                    self.skipped.insert(id, skipped);
+                    Err(e)
+                }
+            };
         }
-        let secret = self.secret_chain[start_id-1].secret.unwrap();
-        let salt = self.secret_chain[start_id-1].salt.as_ref().unwrap().clone();
-        self.secret_chain.push(PayloadHandler::new(shared_secret, salt, Some(&secret)).await);
-    }
-    async fn gen_handlers_to(&mut self, id:usize){
-        while self.secret_chain.len() <= id {
-            let last = self.secret_chain.len()-1;
-            let new_handler = self.secret_chain[last].next().await;
-            self.secret_chain.push(new_handler);
+
+        //the chain is only moved on once the message has been processed so a bad message can not skip it forward
+        let (head, skipped) = self.advance_to(id).await?;
+        let processed = head.message_key.process(id, self.is_encrypting, aad, payload).await?;
+        self.head = Some(head.next().await?);
+        self.head_id = id + 1;
+        self.keep_skipped(skipped);
+        self.processed += 1;
+        self.evict();
+        return Ok(processed);
+    }
+    //Moves the head to id keeping the keys passed over, used when a chain is replaced and its last messages have not arrived
+    pub async fn skip_to(&mut self, id:usize) -> Result<(), AwakeError>{
+        if id <= self.head_id {
+            return Ok(());
         }
+        let (head, skipped) = self.advance_to(id).await?;
+        self.head = Some(head);
+        self.head_id = id;
+        self.keep_skipped(skipped);
+        self.evict();
+        return Ok(());
     }
+    //Drops the chain secret so no new keys can be made, any skipped keys can still be used
+    pub fn retire(&mut self){
+        self.head = None;
+    }
+    pub fn empty_decryptor(&mut self, id:usize){
+        self.skipped.remove(&id);
+    }
+    //How many links of the chain have been used or skipped
     pub fn len(&self) -> usize{
-        self.secret_chain.len()
+        return self.head_id;
     }
-}
-
-#[derive(Clone)]
-struct PayloadHandler{
-    secret:Option<[u8; 32]>,
-    aes_key:Option<[u8; 32]>,
-    unique_iv:Option<[u8; 12]>,
-    salt:Option<Vec<u8>>,
-    shared_secret:Option<CryptoKey>
-}
-impl PayloadHandler{
-    pub async fn new(shared_secret:CryptoKey, salt:Vec<u8>, last_secret:Option<&[u8]>) -> PayloadHandler{
-        let last_secret_array = match last_secret {
-            Some(secret) => u8_iter_js_array(secret.iter()),
-            None => Uint8Array::new_with_length(0)
+    pub fn is_empty(&self) -> bool{
+        return self.head_id == 0;
+    }
+    pub fn skipped_len(&self) -> usize{
+        return self.skipped.len();
+    }
+    async fn advance_to(&self, id:usize) -> Result<(PayloadHandler, Vec<(usize, MessageKey)>), AwakeError>{
+        let mut head = match &self.head {
+            Some(x) => x.clone(),
+            None => return Err(AwakeError::RatchetState("the chain has been retired and can not make new keys".to_string()))
         };
-        let salt_array = u8_iter_js_array(salt.iter());
-        // web_sys::console::log_1(&last_secret_array);
-        let algorithm = HashMap::from([
-            ("name".to_string(), JsValue::from_str("HKDF")),
-            ("hash".to_string(), JsValue::from("SHA-256")),
-            ("salt".to_string(), JsValue::from(salt_array)),
-            ("info".to_string(), JsValue::from(last_secret_array))
-        ]);
-        let key_data_promise = fetch_subtle_crypto().derive_bits_with_object(
-            &js_objectify(&algorithm),
-            &shared_secret,
-            608 as u32
-        ).unwrap();
-        let key_data_js_value = JsFuture::from(key_data_promise).await.unwrap();
-        let key_data_array_buffer:ArrayBuffer = key_data_js_value.dyn_into().unwrap();
-        let key_data_array:Uint8Array = js_sys::Uint8Array::new(&key_data_array_buffer);
-        let key_data = key_data_array.to_vec();
-        let mut secret:[u8; 32] = [0 as u8; 32];
-        let mut aes_key:[u8; 32] = [0 as u8; 32];
-        let mut unique_iv:[u8; 12] = [0 as u8; 12];
-        let mut i = 0;
-        for byte in key_data {
-            if i < secret.len(){
-                secret[i] = byte;
-            }else if i < secret.len() + aes_key.len(){
-                aes_key[i - secret.len()] = byte;
-            }else{
-                unique_iv[i - secret.len() - aes_key.len()] = byte;
-            }
-            i += 1;
+        if !self.is_encrypting && id - self.head_id > self.limits.max_skip {
+            return Err(AwakeError::RatchetState(format!("message {} is more than {} ahead of the chain", id, self.limits.max_skip)));
+        }
+        let mut skipped = vec![];
+        for skipped_id in self.head_id..id {
+            let next = head.next().await?;
+            skipped.push((skipped_id, head.message_key));
+            head = next;
+        }
+        return Ok((head, skipped));
+    }
+    //a sender never needs the keys it skips
+    fn keep_skipped(&mut self, skipped:Vec<(usize, MessageKey)>){
+        if self.is_encrypting {
+            return;
         }
-        return PayloadHandler{ 
-            secret:Some(secret),
-            aes_key:Some(aes_key), 
-            unique_iv:Some(unique_iv), 
-            salt:Some(salt),
-            shared_secret:Some(shared_secret)
+        for (id, message_key) in skipped {
+            self.skipped.insert(id, SkippedKey{message_key, skipped_at: self.processed});
         }
     }
-    pub async fn proccess_payload(&mut self, is_encrypting:bool, payload:Transitable)-> Result<Transitable, String>{
-        if self.has_processed_message() {
-            return Err("A message has already been proccessed with given id".to_string());
+    //skipped keys are dropped once they are too old, then the lowest ids go first if there are too many
+    fn evict(&mut self){
+        let processed = self.processed;
+        let max_age = self.limits.max_age;
+        self.skipped.retain(|_, x| processed - x.skipped_at <= max_age);
+        while self.skipped.len() > self.limits.max_skipped {
+            let oldest = match self.skipped.keys().next() {
+                Some(x) => *x,
+                None => break
+            };
+            self.skipped.remove(&oldest);
         }
-        let iv_array = u8_iter_js_array(self.unique_iv.unwrap().iter());
-        let aes_key_array = u8_iter_js_array(self.aes_key.unwrap().iter());
-        let algorithm = HashMap::from([
-            ("name".to_string(), JsValue::from_str("AES-GCM")),
-            ("iv".to_string(), JsValue::from(iv_array)),
-        ]);
-        let key_uses = Array::new_with_length(2);
-        key_uses.set(0, "encrypt".into());
-        key_uses.set(1, "decrypt".into());
+    }
+}
 
-        let payload_array = payload.as_bytes();
-        let crypto =  fetch_subtle_crypto();
-        let js_algoritm = js_objectify(&algorithm);
-        let aes_key_promise = crypto.import_key_with_str("raw", &aes_key_array, "AES-GCM", false, &key_uses).unwrap();
-        let aes_key_js = JsFuture::from(aes_key_promise).await.unwrap();
-        let aes_key:CryptoKey = aes_key_js.dyn_into().unwrap();
-        //web_sys::console::log_1(&JsValue::from(is_encrypting));
-        // web_sys::console::log_1(&js_algoritm);
-        // web_sys::console::log_1(&aes_key);
-        //web_sys::console::log_1(&payload_array);
-        let payload_promise = match is_encrypting {
-            true => crypto.encrypt_with_object_and_buffer_source(
-                &js_algoritm,
-                &aes_key,
-                &payload_array
-            ).unwrap(),
-            false => crypto.decrypt_with_object_and_buffer_source(
-                &js_algoritm,
-                &aes_key,
-                &payload_array
-            ).unwrap()
+#[derive(Clone, Serialize, Deserialize)]
+struct SkippedKey{
+    message_key:MessageKey,
+    skipped_at:usize
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct MessageKey{
+    aes_key:[u8; 32],
+    unique_iv:[u8; 12]
+}
+impl MessageKey{
+    async fn process(&self, id:usize, is_encrypting:bool, aad:&[u8], payload:Transitable) -> Result<Transitable, AwakeError>{
+        let crypto = fetch_backend()?;
+        let payload_data = payload.as_slice();
+        let payload_vec = match is_encrypting {
+            true => crypto.encrypt(&self.aes_key, &self.unique_iv, aad, payload_data).await?,
+            false => match crypto.decrypt(&self.aes_key, &self.unique_iv, aad, payload_data).await {
+                Ok(x) => x,
+                Err(_) => return Err(AwakeError::DecryptionFailure(id))
+            }
         };
-        let payload_js = JsFuture::from(payload_promise).await.unwrap();
-        let payload_vec = Uint8Array::new(&payload_js).to_vec();
         return Ok(Transitable::from_bytes(payload_vec.as_slice()));
     }
-    pub fn empty_msg_keys(&mut self){
-        self.aes_key = None;
-        self.unique_iv = None;
-    }
-    pub fn has_created_next(&self) -> bool{
-        return !(self.secret.is_some() 
-            && self.shared_secret.is_some() 
-            && self.salt.is_some());
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct PayloadHandler{
+    secret:[u8; 32],
+    message_key:MessageKey,
+    salt:Vec<u8>,
+    shared_secret:SharedSecret
+}
+impl PayloadHandler{
+    //info is the last link's secret, or what the chain was started with for the first link
+    pub async fn new(shared_secret:SharedSecret, salt:Vec<u8>, info:&[u8]) -> Result<PayloadHandler, AwakeError>{
+        //32 bytes for the next secret, 32 for the aes key and 12 for the iv
+        let key_data = fetch_backend()?.hkdf(&shared_secret, &salt, info, 76).await?;
+        let mut secret:[u8; 32] = [0; 32];
+        let mut aes_key:[u8; 32] = [0; 32];
+        let mut unique_iv:[u8; 12] = [0; 12];
+        secret.copy_from_slice(&key_data[..32]);
+        aes_key.copy_from_slice(&key_data[32..64]);
+        unique_iv.copy_from_slice(&key_data[64..76]);
+        return Ok(PayloadHandler{
+            secret,
+            message_key: MessageKey{aes_key, unique_iv},
+            salt,
+            shared_secret
+        })
     }
-    fn has_processed_message(&self) -> bool{
-        return !(self.aes_key.is_some() && self.unique_iv.is_some());
+    pub async fn next(&self) -> Result<PayloadHandler, AwakeError> {
+        return PayloadHandler::new(self.shared_secret.clone(), self.salt.clone(), &self.secret).await;
     }
-    pub async fn next(&mut self) -> PayloadHandler {
-        if self.has_created_next() {
-            panic!("This PayloadHandler has already generated the next member in the chain and can not do so again");
-        }
-        let handler = PayloadHandler::new(
-            self.shared_secret.as_ref().unwrap().clone(), 
-            self.salt.as_ref().unwrap().clone(),
-            Some(&self.secret.as_ref().unwrap().clone())
-        ).await;
-        self.secret = None;
-        self.shared_secret = None;
-        self.salt = None;
-        return handler;
-    }
-}
\ No newline at end of file
+}
@@ -0,0 +1,262 @@
+use wasm_bindgen::prelude::*;
+use js_sys::{Array, Function};
+
+use crate::transitable::Transitable;
+use crate::messages::{Header, read_as};
+use crate::handshake::{Handshake, FinReason, ChallengeType};
+use crate::session::Session;
+use crate::error::AwakeError;
+
+//Describes what the router did with an incoming message
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RouterEventKind {
+    Responded, //an awake/init was answered, the reply is an awake/res
+    Challenged, //an awake/res was answered, the reply is the challenge
+    Acknowledged, //a challenge was accepted, the reply is the acknowledgement and the session is established
+    Established, //our challenge was acknowledged and the session is established
+    Message, //a message was recieved over the established session, the payload is the decrypted message
+    Rekeyed, //the other agent rekeyed the session, there is nothing to send back
+    Rejected, //we rejected the message, the reply is the awake/fin telling the other agent why if there is one to send
+    Failed, //the other agent gave up on the handshake with an awake/fin, the reason says why
+    Closed, //the other agent rejected the handshake after we had finished it, the session has been dropped
+    Unrecognized //the message was of a type this router does not know or was not expected at this stage
+}
+
+#[wasm_bindgen]
+pub struct RouterEvent {
+    kind: RouterEventKind,
+    reply: Option<Transitable>,
+    payload: Option<Transitable>,
+    reason: Option<FinReason>
+}
+#[wasm_bindgen]
+impl RouterEvent {
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> RouterEventKind {
+        return self.kind;
+    }
+    //The message that should be sent back to the other agent, if any
+    #[wasm_bindgen(getter)]
+    pub fn reply(&self) -> Option<Transitable> {
+        return self.reply.clone();
+    }
+    //The decrypted message, if any
+    #[wasm_bindgen(getter)]
+    pub fn payload(&self) -> Option<Transitable> {
+        return self.payload.clone();
+    }
+    //Why the handshake failed, if it did
+    #[wasm_bindgen(getter)]
+    pub fn reason(&self) -> Option<FinReason> {
+        return self.reason;
+    }
+}
+impl RouterEvent {
+    fn new(kind:RouterEventKind, reply:Option<Transitable>, payload:Option<Transitable>) -> RouterEvent {
+        return RouterEvent{kind, reply, payload, reason: None};
+    }
+    fn failed(kind:RouterEventKind, reply:Option<Transitable>, reason:FinReason) -> RouterEvent {
+        return RouterEvent{kind, reply, payload: None, reason: Some(reason)};
+    }
+}
+
+//Wraps a Handshake and the Session it produces so every incoming message can be passed through one object
+#[wasm_bindgen]
+pub struct Router {
+    handshake: Option<Handshake>,
+    session: Option<Session>,
+    capabilities: Array, //The capabilities you have and are trying to prove to them
+    lifetime: u64, //how long should the ucan be valid for
+    challenge: Option<ChallengeType>, //how requestors have to prove themselves, defaults to an oob-pin
+    are_capabilities_valid: Function, //passes in the capabilities they want to prove and passes out a boolean on if you deem them valid
+    is_ucan_valid: Function, //passes in the ucan they sent and passes out a boolean on if you deem it valid
+    is_pin_valid: Function, //passes in the oob_pin they want to prove and passes out a boolean on if you deem them valid
+    get_pin: Function //passes out the out of bounds pin to prove who you are
+}
+
+#[wasm_bindgen]
+impl Router {
+    pub async fn new(
+        capabilities: Array,
+        lifetime: u64,
+        challenge: Option<ChallengeType>, //the challenge we set when responding
+        proofs: Array, //ucans delegated to our real did, presented when a responder sets a ucan challenge
+        are_capabilities_valid: Function,
+        is_ucan_valid: Function,
+        is_pin_valid: Function,
+        get_pin: Function
+    ) -> Result<Router, AwakeError> {
+        let mut handshake = Handshake::new().await?;
+        for proof in proofs.to_vec() {
+            match proof.as_string() {
+                Some(x) => handshake.add_proof(&x)?,
+                None => return Err(AwakeError::BadUcan("proofs must be ucan strings".to_string()))
+            }
+        }
+        return Ok(Router{
+            handshake: Some(handshake),
+            session: None,
+            capabilities,
+            lifetime,
+            challenge,
+            are_capabilities_valid,
+            is_ucan_valid,
+            is_pin_valid,
+            get_pin
+        });
+    }
+    //Starts a handshake as the requestor
+    pub async fn request(&mut self) -> Result<Transitable, AwakeError> {
+        return match &mut self.handshake {
+            Some(handshake) => handshake.request(self.capabilities.clone()).await,
+            None => Err(AwakeError::HandshakeComplete)
+        };
+    }
+    //Encrypts a payload for the other agent once the session is established
+    pub async fn send(&mut self, payload:Transitable) -> Result<Transitable, AwakeError> {
+        return match &mut self.session {
+            Some(session) => session.send(payload).await,
+            None => Err(AwakeError::HandshakeIncomplete)
+        };
+    }
+    //Refreshes the session's keys, the awake/rekey returned has to be sent to the other agent
+    pub async fn rekey(&mut self) -> Result<Transitable, AwakeError> {
+        return match &mut self.session {
+            Some(session) => session.rekey().await,
+            None => Err(AwakeError::HandshakeIncomplete)
+        };
+    }
+    //Lets the handshake through for agents that still sign with the old ES512 format, see Handshake::set_accept_legacy_jws
+    pub fn set_accept_legacy_jws(&mut self, accept:bool) {
+        if let Some(handshake) = &mut self.handshake {
+            handshake.set_accept_legacy_jws(accept);
+        }
+    }
+    //Adds a ucan to the chain presented in ucan challenges, see Handshake::add_proof
+    pub fn add_proof(&mut self, ucan:&str) -> Result<(), AwakeError> {
+        return match &mut self.handshake {
+            Some(handshake) => handshake.add_proof(ucan),
+            None => Err(AwakeError::HandshakeComplete)
+        };
+    }
+    pub fn is_established(&self) -> bool {
+        return self.session.is_some();
+    }
+    //Works out which stage an incoming message belongs to and processes it
+    pub async fn route(&mut self, incoming:Transitable) -> Result<RouterEvent, AwakeError> {
+        let message_type = read_envelope(&incoming)?.message_type;
+
+        if let Some(session) = &mut self.session {
+            if message_type == "awake/fin" {
+                //a fin that can not be checked leaves the session alone, anyone could have sent it
+                return match session.read_failure(incoming).await {
+                    Ok(()) => {
+                        self.session = None;
+                        Ok(RouterEvent::new(RouterEventKind::Closed, None, None))
+                    },
+                    Err(e) => Ok(RouterEvent::failed(RouterEventKind::Rejected, None, FinReason::from_error(&e)))
+                };
+            }
+            return match message_type.as_str() {
+                "awake/msg" => {
+                    let payload = session.receive(incoming).await?;
+                    Ok(RouterEvent::new(RouterEventKind::Message, None, Some(payload)))
+                },
+                "awake/rekey" => {
+                    session.accept_rekey(incoming).await?;
+                    Ok(RouterEvent::new(RouterEventKind::Rekeyed, None, None))
+                },
+                _ => Ok(RouterEvent::new(RouterEventKind::Unrecognized, None, None))
+            };
+        }
+
+        let handshake = match self.handshake.as_mut() {
+            Some(x) => x,
+            None => return Err(AwakeError::HandshakeIncomplete)
+        };
+        let event = match message_type.as_str() {
+            "awake/init" => {
+                let response = match handshake.reponse(
+                    incoming,
+                    self.capabilities.clone(),
+                    self.lifetime,
+                    self.are_capabilities_valid.clone(),
+                    self.challenge
+                ).await {
+                    Ok(x) => x,
+                    Err(e) => return rejected(handshake, e)
+                };
+                RouterEvent::new(RouterEventKind::Responded, Some(response), None)
+            },
+            "awake/res" => {
+                let pin = match self.get_pin.call0(&JsValue::NULL) {
+                    Ok(x) => x.as_string(),
+                    Err(e) => return Err(AwakeError::Callback(format!("{:?}", e)))
+                };
+                let pin = match pin {
+                    Some(x) => x,
+                    None => return Err(AwakeError::Callback("get_pin did not return a string pin".to_string()))
+                };
+                let challenge = match handshake.challenge_response(incoming, &pin, self.is_ucan_valid.clone()).await {
+                    Ok(x) => x,
+                    Err(e) => return rejected(handshake, e)
+                };
+                RouterEvent::new(RouterEventKind::Challenged, Some(challenge), None)
+            },
+            "awake/msg" if handshake.is_awaiting_acknowledgement() => {
+                if let Err(e) = handshake.accept_acknowledgement(incoming).await {
+                    return rejected(handshake, e);
+                }
+                RouterEvent::new(RouterEventKind::Established, None, None)
+            },
+            "awake/msg" => {
+                let ack = match handshake.acknowledge_challenge(incoming, self.is_pin_valid.clone()).await {
+                    Ok(x) => x,
+                    Err(e) => return rejected(handshake, e)
+                };
+                RouterEvent::new(RouterEventKind::Acknowledged, Some(ack), None)
+            },
+            "awake/fin" => {
+                //an awake/fin is never answered with another
+                match handshake.read_failure(incoming).await {
+                    Ok(reason) => RouterEvent::failed(RouterEventKind::Failed, None, reason),
+                    Err(e) => RouterEvent::failed(RouterEventKind::Rejected, None, FinReason::from_error(&e))
+                }
+            },
+            _ => RouterEvent::new(RouterEventKind::Unrecognized, None, None)
+        };
+
+        //once the handshake is done all further messages go through the session
+        if handshake.is_done() {
+            if let Some(handshake) = self.handshake.take() {
+                self.session = Some(handshake.into_session()?);
+            }
+        }
+        return Ok(event);
+    }
+}
+
+//A rejected step leaves an awake/fin to send back, anything else is passed out as an error
+fn rejected(handshake:&mut Handshake, error:AwakeError) -> Result<RouterEvent, AwakeError> {
+    return match handshake.take_failure() {
+        Some(fin) => Ok(RouterEvent::failed(RouterEventKind::Rejected, Some(fin), FinReason::from_error(&error))),
+        None => Err(error)
+    };
+}
+
+//Messages sent during the handshake are signed while session messages are plain json
+fn read_envelope(incoming:&Transitable) -> Result<Header, AwakeError> {
+    let error = match read_as(incoming) {
+        Ok(x) => return Ok(x),
+        Err(e) => e
+    };
+    //plain json can have three dot separated parts too, so it is only an error about the jws if it looked like one
+    if !incoming.is_signed() {
+        return Err(error);
+    }
+    return match incoming.unsign() {
+        Ok(x) => read_as(&x),
+        Err(_) => Err(error)
+    };
+}
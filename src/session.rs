@@ -0,0 +1,114 @@
+use wasm_bindgen::prelude::*;
+use serde::{Serialize, Deserialize};
+
+use crate::transitable::Transitable;
+use crate::foreign_agent::ForeignAgent;
+use crate::ratchet::SkipLimits;
+use crate::messages::{Message, Msg, Rekey, Fin, write_message, read_message, read_signed_message, expect_version};
+use crate::utils::{fetch_backend, did_key_to_crypto_key};
+use crate::error::AwakeError;
+
+//An established point to point channel with the agent a handshake was conducted with
+#[wasm_bindgen]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session{
+    agent: ForeignAgent
+}
+
+#[wasm_bindgen]
+impl Session{
+    //Encrypts a payload for the other agent and wraps it in an awake/msg
+    pub async fn send(&mut self, payload:Transitable) -> Result<Transitable, AwakeError>{
+        let self_did = match &self.agent.self_did {
+            Some(x) => x.clone(),
+            None => return Err(AwakeError::HandshakeIncomplete)
+        };
+        let mid = self.agent.next_mid().await?;
+        let aad = envelope_aad(&self.agent.awv, Msg::TYPE, &mid, &self_did);
+        let (mid, encrypted) = self.agent.encrypt_for(payload, &aad).await?;
+        let mut message = Msg::new(&self.agent.awv, mid, encrypted.as_base64());
+        message.conf = self.agent.pending_confirmation().await?;
+        return write_message(&message);
+    }
+    //Finds the message key for an awake/msg using its mid and decrypts it
+    pub async fn receive(&mut self, message:Transitable) -> Result<Transitable, AwakeError>{
+        let message:Msg = read_message(&message)?;
+        expect_version(&message.awv, &self.agent.awv)?;
+        self.agent.check_confirmation(message.conf.as_deref()).await?;
+        let encrypted = Transitable::from_base64(&message.msg)?;
+        let aad = envelope_aad(&message.awv, &message.message_type, &message.mid, &self.agent.did);
+        let decrypted = self.agent.decrypt_with_mid(message.mid, encrypted, &aad).await?;
+        self.agent.confirmed();
+        return Ok(decrypted);
+    }
+    //Starts the session's ratchet again from a fresh key, the awake/rekey returned has to reach the other agent
+    //before anything sent after it can be read
+    pub async fn rekey(&mut self) -> Result<Transitable, AwakeError>{
+        let self_did = match &self.agent.self_did {
+            Some(x) => x.clone(),
+            None => return Err(AwakeError::HandshakeIncomplete)
+        };
+        let mid = self.agent.next_mid().await?;
+        let aad = envelope_aad(&self.agent.awv, Rekey::TYPE, &mid, &self_did);
+        let (mid, encrypted) = self.agent.rekey(&aad).await?;
+        let mut message = Rekey::new(&self.agent.awv, mid, encrypted.as_base64());
+        message.conf = self.agent.pending_confirmation().await?;
+        return write_message(&message);
+    }
+    //Moves to the ratchet the other agent started with rekey
+    pub async fn accept_rekey(&mut self, message:Transitable) -> Result<(), AwakeError>{
+        let message:Rekey = read_message(&message)?;
+        expect_version(&message.awv, &self.agent.awv)?;
+        self.agent.check_confirmation(message.conf.as_deref()).await?;
+        let encrypted = Transitable::from_base64(&message.msg)?;
+        let aad = envelope_aad(&message.awv, &message.message_type, &message.mid, &self.agent.did);
+        self.agent.accept_rekey(message.mid, encrypted, &aad).await?;
+        self.agent.confirmed();
+        return Ok(());
+    }
+    //Checks an awake/fin the other agent sent after we finished the handshake, when it went on to reject it.
+    //The reason is encrypted with the step 2 chains which are gone, so only the signature can be checked
+    pub async fn read_failure(&self, fin_signed:Transitable) -> Result<(), AwakeError>{
+        let fin:Fin = read_signed_message(&fin_signed)?;
+        if self.agent.handshake_did.as_deref() != Some(fin.iss.as_str()) {
+            return Err(AwakeError::UnexpectedMessage("failure message was not from the agent this session is with".to_string()));
+        }
+        if let Some(kid) = fin_signed.kid()? {
+            if kid != fin.iss {
+                return Err(AwakeError::SignatureFailure("failure message named a different signer".to_string()));
+            }
+        }
+        let crypto = fetch_backend()?;
+        if !fin_signed.verify(&crypto, &did_key_to_crypto_key(&crypto, &fin.iss).await?).await? {
+            return Err(AwakeError::SignatureFailure("failure message was not signed by the did it was sent from".to_string()));
+        }
+        return Ok(());
+    }
+    //Bounds how many keys are kept for messages that arrive out of order, see SkipLimits
+    pub async fn set_skip_limits(&mut self, max_skip:usize, max_skipped:usize, max_age:usize) -> Result<(), AwakeError>{
+        return self.agent.set_skip_limits(SkipLimits{max_skip, max_skipped, max_age}).await;
+    }
+    //The did the agent on the other end of this session sends from, for a requestor it is the responder's next did
+    #[wasm_bindgen(getter)]
+    pub fn did(&self) -> String {
+        return self.agent.did.clone();
+    }
+    //The real did of the agent on the other end of this session, the one its ucan was issued by or to
+    #[wasm_bindgen(getter)]
+    pub fn peer_did(&self) -> String {
+        return match &self.agent.peer_did {
+            Some(x) => x.clone(),
+            None => self.agent.did.clone()
+        };
+    }
+}
+impl Session{
+    pub fn new(agent:ForeignAgent) -> Session{
+        return Session{agent};
+    }
+}
+
+//The envelope fields are authenticated with the payload so none of them can be changed in transit
+fn envelope_aad(awv:&str, message_type:&str, mid:&str, sender_did:&str) -> Vec<u8>{
+    return serde_json::json!([awv, message_type, mid, sender_did]).to_string().into_bytes();
+}
@@ -0,0 +1,119 @@
+use async_trait::async_trait;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{IdbDatabase, IdbObjectStore, IdbOpenDbRequest, IdbRequest, IdbTransactionMode, Event};
+use js_sys::{Array, Promise, Uint8Array};
+
+use crate::session::Session;
+use crate::store::{SessionStore, session_to_bytes, session_from_bytes};
+use crate::utils::{fetch_backend, wrap, unwrap};
+use crate::error::AwakeError;
+
+const STORE_NAME:&str = "sessions";
+
+//Keeps sessions in the browser's IndexedDB. Sessions hold ratchet secrets so they are wrapped with a caller supplied 32 byte key
+#[wasm_bindgen]
+pub struct IndexedDbSessionStore {
+    db: IdbDatabase,
+    wrapping_key: Vec<u8>
+}
+
+#[wasm_bindgen]
+impl IndexedDbSessionStore {
+    pub async fn open(name:&str, wrapping_key:&[u8]) -> Result<IndexedDbSessionStore, AwakeError> {
+        let window = match web_sys::window() {
+            Some(x) => x,
+            None => return Err(AwakeError::Storage("there is no window to get IndexedDB from".to_string()))
+        };
+        let factory = match window.indexed_db().map_err(storage_error)? {
+            Some(x) => x,
+            None => return Err(AwakeError::Storage("IndexedDB is not available".to_string()))
+        };
+        let open_request = factory.open_with_u32(name, 1).map_err(storage_error)?;
+        //the object store can only be created while the database is being upgraded
+        let on_upgrade = Closure::once(move |event:Event| {
+            let db = event.target()
+                .and_then(|x| x.dyn_into::<IdbOpenDbRequest>().ok())
+                .and_then(|x| x.result().ok())
+                .and_then(|x| x.dyn_into::<IdbDatabase>().ok());
+            if let Some(db) = db {
+                let _ = db.create_object_store(STORE_NAME);
+            }
+        });
+        open_request.set_onupgradeneeded(Some(on_upgrade.as_ref().unchecked_ref()));
+        let db = await_request(&open_request).await?.dyn_into().map_err(storage_error)?;
+        return Ok(IndexedDbSessionStore{db, wrapping_key: wrapping_key.to_vec()});
+    }
+    pub async fn put(&self, session:&Session) -> Result<(), AwakeError> {
+        let crypto = fetch_backend()?;
+        let session_data = wrap(&crypto, &self.wrapping_key, &session_to_bytes(session)?).await?;
+        let store = self.object_store(IdbTransactionMode::Readwrite)?;
+        await_request(&store.put_with_key(&Uint8Array::from(&session_data[..]), &JsValue::from(session.peer_did())).map_err(storage_error)?).await?;
+        return Ok(());
+    }
+    pub async fn get(&self, did:&str) -> Result<Option<Session>, AwakeError> {
+        let store = self.object_store(IdbTransactionMode::Readonly)?;
+        let session_js = await_request(&store.get(&JsValue::from(did)).map_err(storage_error)?).await?;
+        if session_js.is_undefined() {
+            return Ok(None);
+        }
+        let crypto = fetch_backend()?;
+        let session_data = unwrap(&crypto, &self.wrapping_key, &Uint8Array::new(&session_js).to_vec()).await?;
+        return Ok(Some(session_from_bytes(&session_data)?));
+    }
+    pub async fn list(&self) -> Result<Array, AwakeError> {
+        let store = self.object_store(IdbTransactionMode::Readonly)?;
+        return await_request(&store.get_all_keys().map_err(storage_error)?).await?.dyn_into().map_err(storage_error);
+    }
+    pub async fn delete(&self, did:&str) -> Result<(), AwakeError> {
+        let store = self.object_store(IdbTransactionMode::Readwrite)?;
+        await_request(&store.delete(&JsValue::from(did)).map_err(storage_error)?).await?;
+        return Ok(());
+    }
+}
+impl IndexedDbSessionStore {
+    fn object_store(&self, mode:IdbTransactionMode) -> Result<IdbObjectStore, AwakeError> {
+        let transaction = self.db.transaction_with_str_and_mode(STORE_NAME, mode).map_err(storage_error)?;
+        return transaction.object_store(STORE_NAME).map_err(storage_error);
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStore for IndexedDbSessionStore {
+    async fn put(&self, session:&Session) -> Result<(), AwakeError> {
+        return IndexedDbSessionStore::put(self, session).await;
+    }
+    async fn get(&self, did:&str) -> Result<Option<Session>, AwakeError> {
+        return IndexedDbSessionStore::get(self, did).await;
+    }
+    async fn list(&self) -> Result<Vec<String>, AwakeError> {
+        let mut dids = vec![];
+        for did in IndexedDbSessionStore::list(self).await?.to_vec() {
+            match did.as_string() {
+                Some(x) => dids.push(x),
+                None => return Err(AwakeError::Storage("session keys must be strings".to_string()))
+            }
+        }
+        return Ok(dids);
+    }
+    async fn delete(&self, did:&str) -> Result<(), AwakeError> {
+        return IndexedDbSessionStore::delete(self, did).await;
+    }
+}
+
+//IndexedDB requests report back through events so they are turned into a promise that settles with them
+async fn await_request(request:&IdbRequest) -> Result<JsValue, AwakeError> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+    if let Err(e) = JsFuture::from(promise).await {
+        return Err(AwakeError::Storage(format!("{:?}", e)));
+    }
+    return request.result().map_err(storage_error);
+}
+//Failed IndexedDB requests and casts
+fn storage_error<T:std::fmt::Debug>(error:T) -> AwakeError{
+    return AwakeError::Storage(format!("{:?}", error));
+}
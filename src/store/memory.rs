@@ -0,0 +1,47 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::session::Session;
+use crate::store::{SessionStore, session_to_bytes, session_from_bytes};
+use crate::error::AwakeError;
+
+//Holds sessions for as long as the process runs, mostly useful for tests
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, Vec<u8>>>
+}
+impl MemorySessionStore {
+    pub fn new() -> MemorySessionStore {
+        return MemorySessionStore::default();
+    }
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, Vec<u8>>>, AwakeError> {
+        return match self.sessions.lock() {
+            Ok(x) => Ok(x),
+            Err(_) => Err(AwakeError::Storage("session store lock was poisoned".to_string()))
+        };
+    }
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+impl SessionStore for MemorySessionStore {
+    async fn put(&self, session:&Session) -> Result<(), AwakeError> {
+        let session_data = session_to_bytes(session)?;
+        self.lock()?.insert(session.peer_did(), session_data);
+        return Ok(());
+    }
+    async fn get(&self, did:&str) -> Result<Option<Session>, AwakeError> {
+        return match self.lock()?.get(did) {
+            Some(x) => Ok(Some(session_from_bytes(x)?)),
+            None => Ok(None)
+        };
+    }
+    async fn list(&self) -> Result<Vec<String>, AwakeError> {
+        return Ok(self.lock()?.keys().cloned().collect());
+    }
+    async fn delete(&self, did:&str) -> Result<(), AwakeError> {
+        self.lock()?.remove(did);
+        return Ok(());
+    }
+}
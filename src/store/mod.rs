@@ -0,0 +1,41 @@
+use async_trait::async_trait;
+
+use crate::backend::ConditionalSendSync;
+use crate::session::Session;
+use crate::error::AwakeError;
+
+mod memory;
+pub use memory::MemorySessionStore;
+
+#[cfg(feature = "indexeddb")]
+mod indexed_db;
+#[cfg(feature = "indexeddb")]
+pub use indexed_db::IndexedDbSessionStore;
+
+#[cfg(all(feature = "indexeddb", not(target_arch = "wasm32")))]
+compile_error!("the indexeddb feature only works when building for wasm32");
+
+//Keeps established sessions keyed by the peer's real did, see Session::peer_did, so they can be picked up again without another handshake.
+//A session's ratchets move on with every send and receive so it should be put again after each one
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+pub trait SessionStore: ConditionalSendSync {
+    async fn put(&self, session:&Session) -> Result<(), AwakeError>;
+    async fn get(&self, did:&str) -> Result<Option<Session>, AwakeError>;
+    async fn list(&self) -> Result<Vec<String>, AwakeError>;
+    async fn delete(&self, did:&str) -> Result<(), AwakeError>;
+}
+
+//Sessions are stored as json which includes the ratchet counters and any message keys not used yet
+fn session_to_bytes(session:&Session) -> Result<Vec<u8>, AwakeError> {
+    return match serde_json::to_vec(session) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(AwakeError::Storage(e.to_string()))
+    };
+}
+fn session_from_bytes(bytes:&[u8]) -> Result<Session, AwakeError> {
+    return match serde_json::from_slice(bytes) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(AwakeError::Storage(e.to_string()))
+    };
+}
@@ -1,26 +1,38 @@
 use wasm_bindgen::prelude::wasm_bindgen;
-use wasm_bindgen::JsValue;
-use wasm_bindgen_futures::JsFuture;
+use serde::{Serialize, Deserialize};
 
-use web_sys::{SubtleCrypto, CryptoKey};
-use js_sys::Uint8Array;
-
-use std::collections::HashMap;
 use std::str;
 
-use crate::utils::*;
+use crate::backend::{Backend, PublicKey, PrivateKey};
+use crate::utils::{sign, verify, verify_legacy};
+use crate::error::AwakeError;
+
+//AWAKE signs every transitable with ECDSA P-256 and SHA-256
+pub const JWS_ALG:&str = "ES256";
+//the header transitables were signed with before they were real JWS, braces and all
+const LEGACY_HEADER:&str = "{{\"alg\": \"ES512\", \"typ\": \"JWT\" }}";
+
+#[derive(Serialize, Deserialize)]
+struct JwsHeader {
+    alg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    typ: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kid: Option<String>
+}
 
 #[wasm_bindgen]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Transitable {
     data: Vec<u8>
 }
 
 #[wasm_bindgen]
 impl Transitable {
-    pub fn from_base58(input: &str) -> Transitable{
-        return Transitable{
-            //TODO: add error handling here
-            data: bs58::decode(input).into_vec().unwrap()
+    pub fn from_base58(input: &str) -> Result<Transitable, AwakeError>{
+        return match bs58::decode(input).into_vec() {
+            Ok(data) => Ok(Transitable{data}),
+            Err(e) => Err(AwakeError::BadEncoding(format!("invalid base58: {}", e)))
         };
     }
     pub fn from_readable(input: &str) -> Transitable{
@@ -33,10 +45,11 @@ impl Transitable {
             data: input.to_vec()
         }
     }
-    pub fn from_base64(input: &str) -> Transitable{
-        return Transitable{
-            data: base64::decode(input).unwrap()
-        }
+    pub fn from_base64(input: &str) -> Result<Transitable, AwakeError>{
+        return match base64::decode(input) {
+            Ok(data) => Ok(Transitable{data}),
+            Err(e) => Err(AwakeError::BadEncoding(format!("invalid base64: {}", e)))
+        };
     }
     #[wasm_bindgen(getter)]
     pub fn as_base64(&self) -> String {
@@ -59,41 +72,97 @@ impl Transitable {
     }
 }
 impl Transitable {
-    pub async fn sign(&self, crypto:&SubtleCrypto, key:&CryptoKey) -> Transitable{
-        let payload_str = match self.as_readable() {
-            Some(x) => x,
-            None => panic!("could not format as jwt as the data was not a string")
+    //as_bytes hands js a Uint8Array, rust callers can borrow the data directly
+    pub fn as_slice(&self) -> &[u8] {
+        return &self.data[..];
+    }
+    //Signs as a compact JWS (RFC 7515). kid is the did:key of the signer, left out when the signer should stay hidden
+    pub async fn sign(&self, crypto:&Backend, key:&PrivateKey, kid:Option<&str>) -> Result<Transitable, AwakeError>{
+        let header = JwsHeader{alg: JWS_ALG.to_string(), typ: Some("JWT".to_string()), kid: kid.map(|x| x.to_string())};
+        let header_json = match serde_json::to_vec(&header) {
+            Ok(x) => x,
+            Err(e) => return Err(AwakeError::BadJwt(format!("could not write the header: {}", e)))
         };
-        //let mut payload_bytes = payload_str.as_bytes().copy();
-        let payload_b64 = base64::encode(payload_str.as_bytes());
-        let header_text = "{{\"alg\": \"ES512\", \"typ\": \"JWT\" }}";
-        let header_b64 = base64::encode(header_text.as_bytes());
-    
-        let signature_vec = sign(crypto, key, &self.data).await;
-        let signature_b64 = base64::encode(signature_vec.as_slice());
-    
-        Transitable::from_readable(&format!("{}.{}.{}", header_b64, payload_b64, signature_b64))
-    }
-    pub async fn verify(&self, crypto:&SubtleCrypto, key:&CryptoKey) -> bool{
-        verify(
-            crypto,
-            key,
-            &self.get_jwt_section(1),
-            &self.get_jwt_section(2)
-        ).await
-    }
-    fn get_jwt_section(&self, i:usize) -> Vec<u8>{
+        let signing_input = format!("{}.{}", base64_url(&header_json), base64_url(&self.data));
+        let signature_vec = sign(crypto, key, signing_input.as_bytes()).await?;
+        return Ok(Transitable::from_readable(&format!("{}.{}", signing_input, base64_url(&signature_vec))));
+    }
+    pub async fn verify(&self, crypto:&Backend, key:&PublicKey) -> Result<bool, AwakeError>{
+        return self.verify_with(crypto, key, false).await;
+    }
+    //accept_legacy also lets through the ES512 signatures transitables had before they were real JWS
+    pub async fn verify_with(&self, crypto:&Backend, key:&PublicKey, accept_legacy:bool) -> Result<bool, AwakeError>{
+        let sections = self.get_jwt_sections()?;
+        if self.is_legacy() {
+            if !accept_legacy {
+                return Err(AwakeError::LegacyJwsRefused);
+            }
+            return verify_legacy(
+                crypto,
+                key,
+                &decode_section(&sections, 1, base64::STANDARD)?,
+                &decode_section(&sections, 2, base64::STANDARD)?
+            ).await;
+        }
+        let header = self.read_header()?;
+        if header.alg != JWS_ALG {
+            return Err(AwakeError::BadJwt(format!("expected alg {} but found {}", JWS_ALG, header.alg)));
+        }
+        let signing_input = format!("{}.{}", sections[0], sections[1]);
+        return verify(crypto, key, signing_input.as_bytes(), &decode_section(&sections, 2, base64::URL_SAFE_NO_PAD)?).await;
+    }
+    //The did:key the signer put in the header, if any
+    pub fn kid(&self) -> Result<Option<String>, AwakeError>{
+        if self.is_legacy() {
+            return Ok(None);
+        }
+        return Ok(self.read_header()?.kid);
+    }
+    fn read_header(&self) -> Result<JwsHeader, AwakeError>{
+        return match serde_json::from_slice(&decode_section(&self.get_jwt_sections()?, 0, base64::URL_SAFE_NO_PAD)?) {
+            Ok(x) => Ok(x),
+            Err(e) => Err(AwakeError::BadJwt(format!("the header is not valid json: {}", e)))
+        };
+    }
+    fn get_jwt_sections(&self) -> Result<Vec<String>, AwakeError>{
         let jwt_str = match self.as_readable() {
             Some(x) => x,
-            None => panic!("Error: This transitable is either not a Json Web Token or is improperly formatted")
+            None => return Err(AwakeError::BadJwt("the data was not a string".to_string()))
         };
-        let sections:Vec<&str> =jwt_str.split(".").collect();
+        let sections:Vec<String> = jwt_str.split(".").map(|x| x.to_string()).collect();
         if sections.len() != 3 {
-            panic!("Error: This transitable is either not a Json Web Token or is improperly formatted")
+            return Err(AwakeError::BadJwt(format!("expected 3 sections but found {}", sections.len())));
         }
-        return base64::decode(sections[i]).unwrap().to_vec();
+        return Ok(sections);
     }
-    pub fn unsign(&self) -> Transitable{
-        Transitable::from_bytes(&self.get_jwt_section(1))
+    //Legacy transitables are told apart by their header, which was always the same text
+    fn is_legacy(&self) -> bool {
+        return match self.get_jwt_sections() {
+            Ok(sections) => base64::decode(&sections[0]).ok().as_deref() == Some(LEGACY_HEADER.as_bytes()),
+            Err(_) => false
+        };
     }
+    pub fn is_signed(&self) -> bool{
+        return match self.as_readable() {
+            Some(x) => x.split(".").count() == 3,
+            None => false
+        };
+    }
+    pub fn unsign(&self) -> Result<Transitable, AwakeError>{
+        let config = match self.is_legacy() {
+            true => base64::STANDARD,
+            false => base64::URL_SAFE_NO_PAD
+        };
+        return Ok(Transitable::from_bytes(&decode_section(&self.get_jwt_sections()?, 1, config)?));
+    }
+}
+
+fn base64_url(data:&[u8]) -> String {
+    return base64::encode_config(data, base64::URL_SAFE_NO_PAD);
+}
+fn decode_section(sections:&[String], i:usize, config:base64::Config) -> Result<Vec<u8>, AwakeError>{
+    return match base64::decode_config(&sections[i], config) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(AwakeError::BadJwt(format!("section {} is not valid base64: {}", i, e)))
+    };
 }
\ No newline at end of file
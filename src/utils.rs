@@ -1,324 +1,186 @@
-//#[macro_use]
-// use simple_error::bail;
-
 use bs58;
-use wasm_bindgen::prelude::wasm_bindgen;
-use wasm_bindgen::{JsValue, JsCast};
-use wasm_bindgen_futures::JsFuture;
+use wasm_bindgen::JsCast;
 
 use serde::{Serialize, Deserialize};
 
-use web_sys::{SubtleCrypto, CryptoKey};
-use js_sys::{Object, Array, JSON, Uint8Array};
+use js_sys::{Object, Array, JSON};
 
-use std::collections::HashMap;
-use std::slice::Iter;
+use crate::backend::{Backend, CryptoBackend, PublicKey, PrivateKey, SharedSecret};
+use crate::error::AwakeError;
 
 const DID_KEY_PREFIX:&str = "did:key:";
-const DID_KEY_PREFIX_NIST256:&str = "zDn";
-
-pub fn js_objectify(props:&HashMap<String, JsValue>) -> Object{
-    let obj_array = Array::new_with_length(props.len() as u32);
-    let mut i:u32 = 0;
-    for (prop, val) in props {
-        let pair = Array::new_with_length(2 as u32);
-        pair.set(0 as u32, JsValue::from(prop));
-        pair.set(1 as u32, val.clone());
-        obj_array.set(i, JsValue::from(pair));
-        i += 1;
-    }
-    return Object::from_entries(&obj_array).unwrap();
-}
+//the multibase prefix for base58btc, the only one did:key uses
+const MULTIBASE_BASE58BTC:&str = "z";
+//the multicodec for a P-256 public key, written as a varint in front of the compressed point
+const MULTICODEC_P256_PUB:u64 = 0x1200;
 
-pub fn fetch_subtle_crypto() -> SubtleCrypto{
-    let window = web_sys::window().unwrap();
-    return window.crypto().unwrap().subtle();
+//WebCrypto in the browser, the pure rust backend everywhere else
+pub fn fetch_backend() -> Result<Backend, AwakeError>{
+    return Backend::fetch();
 }
 
-#[wasm_bindgen]
-struct ObjectProperty {
-    value:JsValue,
-    writable:bool
+pub async fn sign(crypto:&Backend, private_key: &PrivateKey, data:&[u8]) -> Result<Vec<u8>, AwakeError>{
+    return crypto.sign(private_key, data).await;
 }
-
-pub async fn get_message_id(crypto:&SubtleCrypto, key1: &CryptoKey, key2: &CryptoKey, message_count:Option<usize>) -> Vec<u8>{
-    let mut key1_data = crypto_key_to_bytes(crypto, key1).await;
-    let mut key2_data = crypto_key_to_bytes(crypto, key2).await;
-    let mut key_data = Vec::new();
-    key_data.append(&mut key1_data);
-    key_data.append(&mut key2_data);
-    if message_count.is_some() {
-        let mut msg_count_bytes = message_count.unwrap().to_be_bytes().to_vec();
-        key_data.append(&mut msg_count_bytes)
-    }
-    return hash(crypto, &key_data).await;
-}
-
-pub async fn sign(crypto:&SubtleCrypto, ecdh_key: &CryptoKey, data:&Vec<u8>) -> Vec<u8>{
-    let ecdsa_key = get_ecdsa_key(crypto, ecdh_key, true).await;
-    let algorithm = HashMap::from([
-        ("name".to_string(), JsValue::from_str("ECDSA")),
-        ("hash".to_string(), JsValue::from_str("SHA-512")),
-    ]);
-
-    let signature_promise = crypto.sign_with_object_and_buffer_source(
-        &js_objectify(&algorithm), 
-        &ecdsa_key,
-        &u8_iter_js_array(data.iter())
-    ).unwrap();
-    let signature_js = JsFuture::from(signature_promise).await.unwrap();
-    let signature_array = Uint8Array::new(&signature_js);
-    return signature_array.to_vec();
+pub async fn verify(crypto:&Backend, public_key: &PublicKey, data:&[u8], signature:&[u8]) -> Result<bool, AwakeError> {
+    return crypto.verify(public_key, data, signature).await;
 }
-pub async fn verify(crypto:&SubtleCrypto, ecdh_key: &CryptoKey, data:&Vec<u8>, signature:&Vec<u8>) -> bool {
-    let ecdsa_key = get_ecdsa_key(crypto, ecdh_key, false).await;
-    let algorithm = HashMap::from([
-        ("name".to_string(), JsValue::from_str("ECDSA")),
-        ("hash".to_string(), JsValue::from_str("SHA-512")),
-    ]);
-    let is_valid_future = crypto.verify_with_object_and_buffer_source_and_buffer_source(
-        &js_objectify(&algorithm),
-        &ecdsa_key,
-        &u8_iter_js_array(signature.iter()),
-        &u8_iter_js_array(data.iter())
-    ).unwrap();
-    let is_valid_js = JsFuture::from(is_valid_future).await.unwrap();
-    return is_valid_js.as_bool().unwrap()
+pub async fn verify_legacy(crypto:&Backend, public_key: &PublicKey, data:&[u8], signature:&[u8]) -> Result<bool, AwakeError> {
+    return crypto.verify_legacy(public_key, data, signature).await;
 }
 
-pub async fn hash(crypto: &SubtleCrypto, data:&Vec<u8>) -> Vec<u8>{
-    let hash_promise = crypto.digest_with_str_and_buffer_source("SHA-256", &u8_iter_js_array(data.iter())).unwrap();
-    return Uint8Array::new(&JsFuture::from(hash_promise).await.unwrap()).to_vec();
+pub async fn hash(crypto:&Backend, data:&[u8]) -> Result<Vec<u8>, AwakeError>{
+    return crypto.hash(data).await;
 }
 
-pub async fn gen_key_pair(crypto:&SubtleCrypto, is_extractable:bool) -> (CryptoKey, CryptoKey){
-    let algorithm = HashMap::from([
-        ("name".to_string(), JsValue::from_str("ECDH")),
-        ("namedCurve".to_string(), JsValue::from_str("P-256")),
-    ]);
-    let key_uses_array:Array = Array::new_with_length(2);
-    key_uses_array.set(0, JsValue::from("deriveBits"));
-    key_uses_array.set(1, JsValue::from("deriveKey"));
-    let js_algorithm = js_objectify(&algorithm);
-    let key_pair_promise = crypto.generate_key_with_object(&js_algorithm, is_extractable, &key_uses_array).unwrap();
-    let key_pair_future = JsFuture::from(key_pair_promise);
-    let key_pair_object:Object = key_pair_future.await.unwrap().dyn_into().unwrap();
-    let key_pair_map = obj_to_hash_map(&key_pair_object);
-    return (
-        key_pair_map["publicKey"].clone().dyn_into().unwrap(), 
-        key_pair_map["privateKey"].clone().dyn_into().unwrap()
-    );
-}
-pub async fn diffie_helman(crypto:&SubtleCrypto, self_key:&CryptoKey, other_agent_key:&CryptoKey) -> CryptoKey{
-    let key_uses_array:Array = Array::new_with_length(2);
-    key_uses_array.set(0, JsValue::from("deriveBits"));
-    key_uses_array.set(1, JsValue::from("deriveKey"));
-    let shared_secret_algorithm = HashMap::from([
-        ("name".to_string(), JsValue::from_str("ECDH")),
-        ("namedCurve".to_string(), JsValue::from_str("P-256")),
-        ("public".to_string(), JsValue::from(other_agent_key))
-    ]);
-    let shared_secret_data_promise = crypto.derive_bits_with_object(
-        &js_objectify(&shared_secret_algorithm),
-        self_key,
-        256,
-    ).unwrap();
-    let shared_secret_data = JsFuture::from(shared_secret_data_promise).await.unwrap();
-    let shared_secret_data_obj:Object  = shared_secret_data.dyn_into().unwrap();
-    //web_sys::console::log_1(&shared_secret_data_obj);
-    let shared_secret_promise = crypto.import_key_with_str(
-        "raw",
-        &shared_secret_data_obj,
-        "HKDF",
-        false,
-        &key_uses_array
-    ).unwrap();
-    let shared_secret = JsFuture::from(shared_secret_promise).await.unwrap();
-    return shared_secret.dyn_into().unwrap();
+pub async fn gen_key_pair(crypto:&Backend, is_extractable:bool) -> Result<(PublicKey, PrivateKey), AwakeError>{
+    return crypto.gen_key_pair(is_extractable).await;
 }
-
-pub async fn get_ecdsa_key(crypto:&SubtleCrypto, ecdh_key:&CryptoKey, is_sign_key:bool) -> CryptoKey{
-    let algorithm = HashMap::from([
-        ("name".to_string(), JsValue::from_str("ECDSA")),
-        ("namedCurve".to_string(), JsValue::from_str("P-256")),
-    ]);
-    
-    let key_uses_array:Array = Array::new_with_length(1);
-    if is_sign_key { key_uses_array.set(0, JsValue::from("sign")); }
-    else {key_uses_array.set(0, JsValue::from("verify"));}
-
-    let key_data_promise = crypto.export_key("jwk", ecdh_key).unwrap();
-    let key_data_jwk = JsFuture::from(key_data_promise).await.unwrap();
-    let key_data_map = obj_to_hash_map(&key_data_jwk.dyn_into().unwrap());
-    let key_data_map_override:HashMap<String, JsValue> = HashMap::from([
-        ("alg".to_string(), JsValue::from("ES256")),
-        ("crv".to_string(), JsValue::from("P-256")),
-        ("ext".to_string(), JsValue::from(true)),
-        ("kty".to_string(), JsValue::from("EC")),
-        ("key_ops".to_string(), JsValue::from(&key_uses_array))
-    ]);
-    let key_data_map_new = overwrite_hash_map(&key_data_map_override, &key_data_map);
-    let key_data = hash_map_to_object(key_data_map_new);
-
-    // web_sys::console::log_1(&JsValue::from("---------"));
-    // web_sys::console::log_1(&JsValue::from(is_sign_key));
-    // web_sys::console::log_1(&key_data);
-    // web_sys::console::log_1(&js_objectify(&algorithm));
-    // web_sys::console::log_1(&key_uses_array);
-    // web_sys::console::log_1(&JsValue::from("---------"));
-
-    let ecdsa_key_promise = crypto.import_key_with_object(
-        "jwk",
-        &key_data,
-        &js_objectify(&algorithm), 
-        false,
-        &key_uses_array
-    ).unwrap();
-    let ecdsa_key = JsFuture::from(ecdsa_key_promise).await.unwrap();
-    return ecdsa_key.dyn_into().unwrap();
+pub async fn diffie_helman(crypto:&Backend, self_key:&PrivateKey, other_agent_key:&PublicKey) -> Result<SharedSecret, AwakeError>{
+    return crypto.diffie_helman(self_key, other_agent_key).await;
 }
 
-pub fn did_key_to_bytes(did_key:&str) -> Vec<u8>{
-    let key_first_prefix_length = String::from(DID_KEY_PREFIX).len();
-    let key_prefix_length = key_first_prefix_length+String::from(DID_KEY_PREFIX_NIST256).len();
-    if &did_key[key_first_prefix_length..key_prefix_length] == DID_KEY_PREFIX_NIST256 {
-        let key_byte_str = &did_key[key_prefix_length..];
-        return bs58::decode(key_byte_str).into_vec().unwrap();
-    }else{
-        panic!("DID key is not Nist-256 or is improperly formatted")
+//Encrypts saved state with AES-256-GCM under a caller supplied 32 byte key, the random iv is prepended
+pub async fn wrap(crypto:&Backend, wrapping_key:&[u8], data:&[u8]) -> Result<Vec<u8>, AwakeError>{
+    let mut iv = [0; 12];
+    if let Err(e) = getrandom::getrandom(&mut iv) {
+        return Err(AwakeError::Crypto(format!("could not generate an iv: {}", e)));
     }
+    let mut wrapped = iv.to_vec();
+    wrapped.append(&mut crypto.encrypt(wrapping_key, &iv, &[], data).await?);
+    return Ok(wrapped);
 }
-
-pub async fn did_key_to_crypto_key(crypto:&SubtleCrypto, did_key:&str) -> CryptoKey{
-    let algorithm = HashMap::from([
-        ("name".to_string(), JsValue::from_str("ECDH")),
-        ("namedCurve".to_string(), JsValue::from_str("P-256")),
-    ]);
-    let key_uses_array:Array = Array::new_with_length(2);
-    key_uses_array.set(0, JsValue::from("deriveBits"));
-    key_uses_array.set(1, JsValue::from("deriveKey"));
-    let key_byte_vec = did_key_to_bytes(did_key);
-    let key_byte_array = u8_iter_js_array(key_byte_vec.iter());
-    let key_promise = crypto.import_key_with_object(
-        "raw", 
-        &key_byte_array, 
-        &js_objectify(&algorithm), 
-        true, 
-        &Array::new_with_length(0)
-    ).unwrap();
-    let key_future = JsFuture::from(key_promise);
-    let key_js = key_future.await.unwrap();
-    return key_js.dyn_into().unwrap();
-    
-}
-
-
-pub async fn crypto_key_to_did_key(crypto:&SubtleCrypto, crypto_key:&CryptoKey) -> String{
-    let key_data = bs58::encode(crypto_key_to_bytes(crypto, crypto_key).await).into_string();
-    return format!("{}{}{}", DID_KEY_PREFIX, DID_KEY_PREFIX_NIST256, key_data)
-}
-async fn crypto_key_to_bytes(crypto:&SubtleCrypto, crypto_key:&CryptoKey) -> Vec<u8> {
-    let key_data_promise = crypto.export_key("raw", crypto_key).unwrap();
-    let key_data = Uint8Array::new(&JsFuture::from(key_data_promise).await.unwrap());
-    // web_sys::console::log_1(&key_data);
-    return key_data.to_vec();
-}
-
-pub fn overwrite_hash_map<K, V>(top: &HashMap<K, V>, bot: &HashMap<K, V>) -> HashMap<K, V>
-    where K: std::hash::Hash, K: std::cmp::Eq, K: Clone, V: Clone{
-    let mut out = top.clone();
-    for (key, value) in bot {
-        if !out.contains_key(key){
-            out.insert(key.clone(), value.clone());
+pub async fn unwrap(crypto:&Backend, wrapping_key:&[u8], wrapped:&[u8]) -> Result<Vec<u8>, AwakeError>{
+    if wrapped.len() < 12 {
+        return Err(AwakeError::BadState("wrapped data is too short to contain an iv".to_string()));
+    }
+    return match crypto.decrypt(wrapping_key, &wrapped[..12], &[], &wrapped[12..]).await {
+        Ok(x) => Ok(x),
+        Err(_) => Err(AwakeError::BadState("could not unwrap, the wrapping key may be wrong".to_string()))
+    };
+}
+
+//The public key in a did:key as a compressed point, only P-256 keys are supported
+pub fn did_key_to_bytes(did_key:&str) -> Result<Vec<u8>, AwakeError>{
+    let multibase = match did_key.strip_prefix(DID_KEY_PREFIX) {
+        Some(x) => x,
+        None => return Err(AwakeError::BadDidKey(did_key.to_string()))
+    };
+    let encoded = match multibase.strip_prefix(MULTIBASE_BASE58BTC) {
+        Some(x) => x,
+        None => return Err(AwakeError::UnsupportedDidKey(format!("{} is not base58btc", did_key)))
+    };
+    let decoded = match bs58::decode(encoded).into_vec() {
+        Ok(x) => x,
+        Err(_) => return Err(AwakeError::BadDidKey(did_key.to_string()))
+    };
+    let (codec, codec_len) = match read_varint(&decoded) {
+        Some(x) => x,
+        None => return Err(AwakeError::BadDidKey(did_key.to_string()))
+    };
+    if codec != MULTICODEC_P256_PUB {
+        return Err(AwakeError::UnsupportedDidKey(format!("{} has the multicodec 0x{:x}", did_key, codec)));
+    }
+    //some implementations write the point uncompressed so both are read
+    return match compress_point(&decoded[codec_len..]) {
+        Ok(x) => Ok(x),
+        Err(_) => Err(AwakeError::BadDidKey(did_key.to_string()))
+    };
+}
+pub fn bytes_to_did_key(public_key:&[u8]) -> Result<String, AwakeError>{
+    let mut key_data = write_varint(MULTICODEC_P256_PUB);
+    key_data.append(&mut compress_point(public_key)?);
+    return Ok(format!("{}{}{}", DID_KEY_PREFIX, MULTIBASE_BASE58BTC, bs58::encode(key_data).into_string()));
+}
+//A compressed point is x with a prefix for whether y is odd, a compressed point is returned as it is
+pub fn compress_point(point:&[u8]) -> Result<Vec<u8>, AwakeError>{
+    return match (point.len(), point.first()) {
+        (33, Some(2)) | (33, Some(3)) => Ok(point.to_vec()),
+        (65, Some(4)) => {
+            let mut compressed = vec![2 | (point[64] & 1)];
+            compressed.extend_from_slice(&point[1..33]);
+            Ok(compressed)
+        },
+        _ => Err(AwakeError::BadEncoding("public key is not a P-256 point".to_string()))
+    };
+}
+//unsigned LEB128 as multicodec uses it, returns the value and how many bytes it took
+fn read_varint(data:&[u8]) -> Option<(u64, usize)>{
+    let mut value:u64 = 0;
+    for (i, byte) in data.iter().enumerate().take(9) {
+        value |= ((byte & 0x7f) as u64) << (7*i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
         }
     }
-    return out;
+    return None;
 }
-
-pub fn u8_iter_js_array(bytes:Iter<u8>) -> Uint8Array{
-    let array = Uint8Array::new_with_length(bytes.len() as u32);
-    let mut i:u32 = 0;
-    for byte in bytes {
-        array.set_index(i, byte.clone());
-        i += 1;
+fn write_varint(mut value:u64) -> Vec<u8>{
+    let mut data = vec![];
+    while value >= 0x80 {
+        data.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
     }
-    return array;
+    data.push(value as u8);
+    return data;
 }
 
-fn obj_to_hash_map(obj:&Object) -> HashMap<String, JsValue>{
-    let keys = array_to_vec(Object::keys(obj));
-    let values = array_to_vec(Object::values(obj));
-
-    let mut i = 0;
-    let mut ret:HashMap<String, JsValue> = HashMap::new();
-    for value in values{
-        ret.insert(keys[i].as_string().unwrap(), value);
-        i += 1;
-    }
-    return ret;
+pub async fn did_key_to_crypto_key(crypto:&Backend, did_key:&str) -> Result<PublicKey, AwakeError>{
+    let key_byte_vec = did_key_to_bytes(did_key)?;
+    return match crypto.import_public_key(&key_byte_vec).await {
+        Ok(x) => Ok(x),
+        Err(_) => Err(AwakeError::BadDidKey(did_key.to_string()))
+    };
 }
 
-fn array_to_vec(array:Array) -> Vec<JsValue>{
-    let mut vector:Vec<JsValue> = vec![];
-    let length = array.length();
-    let mut i:u32 = 0;
-    while i < length {
-        vector.push(array.get(i));
-        i += 1;
-    }
-    return vector;
-}
-pub fn hash_map_to_object(map: HashMap<String, JsValue>) -> Object{
-    let entries = Array::new_with_length(map.len() as u32);
-    let mut i:u32 = 0;
-    for (key, value) in map {
-        let entry = Array::new_with_length(2);
-        entry.set(0, JsValue::from(key));
-        entry.set(1, value);
-        entries.set(i, JsValue::from(entry));
-        i += 1;
-    }
-    return Object::from_entries(&entries.dyn_into().unwrap()).unwrap();
+pub async fn crypto_key_to_did_key(crypto:&Backend, crypto_key:&PublicKey) -> Result<String, AwakeError>{
+    return bytes_to_did_key(&crypto.export_public_key(crypto_key).await?);
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct UcanCapability{
     pub with:String,
     pub can:String,
     pub nb:Option<String>
 }
 impl UcanCapability{
-    pub fn from_object(obj:&Object) -> UcanCapability{
+    pub fn from_object(obj:&Object) -> Result<UcanCapability, AwakeError>{
         let entries = Object::entries(obj);
         let mut with:Option<String> = None;
         let mut can:Option<String> = None;
         let mut nb:Option<String> = None;
         for js_entry in entries.to_vec(){
-            let entry:Array = js_entry.dyn_into().unwrap();
-            let prop = entry.get(0).as_string().unwrap();
-            if prop == "with".to_string(){
+            let entry:Array = match js_entry.dyn_into() {
+                Ok(x) => x,
+                Err(_) => return Err(AwakeError::BadCapability("capability entries must be arrays".to_string()))
+            };
+            let prop = match entry.get(0).as_string() {
+                Some(x) => x,
+                None => return Err(AwakeError::BadCapability("capability properties must be strings".to_string()))
+            };
+            if prop == "with"{
                 with = match entry.get(1).as_string() {
                     Some(x) => Some(x),
-                    None => panic!("the with property of a capibility must be a string value")
+                    None => return Err(AwakeError::BadCapability("the with property of a capibility must be a string value".to_string()))
                 };
-            }else if prop == "can".to_string(){
+            }else if prop == "can"{
                 can = match entry.get(1).as_string() {
                     Some(x) => Some(x),
-                    None => panic!("the can property of a capibility must be a string value")
+                    None => return Err(AwakeError::BadCapability("the can property of a capibility must be a string value".to_string()))
                 };
-            }else if prop == "nb".to_string(){
+            }else if prop == "nb"{
                 // let obj:Object = entry.get(1).dyn_into().unwrap();
                 nb = match JSON::stringify(&entry.get(1)) {
                     Ok(x) => x.as_string(),
-                    Err(_) => panic!("cannot convert nb to json value")
+                    Err(_) => return Err(AwakeError::BadCapability("cannot convert nb to json value".to_string()))
                 };
                 //TODO: Fix this
                 // nb = Some("This does't work yet".to_string())
             }
         }
-        if with.is_none() || can.is_none() {
-            panic!("a capability must have the with and can properties");
-        }
-        return UcanCapability{with:with.unwrap(), can:can.unwrap(), nb}
+        return match (with, can) {
+            (Some(with), Some(can)) => Ok(UcanCapability{with, can, nb}),
+            _ => Err(AwakeError::BadCapability("a capability must have the with and can properties".to_string()))
+        };
     }
-}
\ No newline at end of file
+}
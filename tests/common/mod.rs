@@ -0,0 +1,71 @@
+//! Helpers shared by the native test suites, each suite only uses some of them
+
+#![allow(dead_code)]
+
+use serde_json::Value;
+
+use awake::utils::*;
+use awake::backend::{CryptoBackend, PrivateKey};
+use awake::handshake::{Handshake, ChallengeType};
+use awake::transitable::Transitable;
+use awake::session::Session;
+use awake::error::AwakeError;
+
+pub static TEST_STRINGS: &[&str] = &[
+    "This is a first test",
+    "!@#$%^&*(){}[]:\"';<>,.?\\|",
+    "T8796543213251324658479876543421654687498324438927342234fodiu>?ASS/Fds/.df/D.,sf';[]pro[pww"
+];
+
+pub async fn new_handshakes() -> (Handshake, Handshake){
+    (Handshake::new().await.unwrap(), Handshake::new().await.unwrap())
+}
+//Runs a handshake up to the requestor's challenge, the responder takes any capabilities and the requestor any ucan
+pub async fn challenge_with(
+    handshaker_requestor:&mut Handshake,
+    handshaker_responder:&mut Handshake,
+    challenge_type:ChallengeType,
+    pin:&str
+) -> Result<Transitable, AwakeError>{
+    let request = handshaker_requestor.request_with(&[]).await?;
+    let response = handshaker_responder.reponse_with(request, &[], 60, challenge_type, |_| Ok(true)).await?;
+    handshaker_requestor.challenge_response_with(response, pin, |_| Ok(true)).await
+}
+//Runs every step of a handshake between the two agents, the responder checks the challenge with is_challenge_valid
+pub async fn run_handshake(
+    handshaker_requestor:&mut Handshake,
+    handshaker_responder:&mut Handshake,
+    challenge_type:ChallengeType,
+    pin:&str,
+    is_challenge_valid: impl Fn(&Value) -> Result<bool, AwakeError>
+) -> Result<(), AwakeError>{
+    let challenge = challenge_with(handshaker_requestor, handshaker_responder, challenge_type, pin).await?;
+    let ack = handshaker_responder.acknowledge_challenge_with(challenge, is_challenge_valid).await?;
+    handshaker_requestor.accept_acknowledgement(ack).await
+}
+pub async fn complete_handshake(pin:&str) -> Result<(Handshake, Handshake), AwakeError>{
+    let mut handshaker_requestor = Handshake::new().await?;
+    let mut handshaker_responder = Handshake::new().await?;
+    run_handshake(&mut handshaker_requestor, &mut handshaker_responder, ChallengeType::OobPin, pin, |pin| Ok(*pin == "Arbitrary Pin")).await?;
+    Ok((handshaker_requestor, handshaker_responder))
+}
+//Both sides of a handshake that went through as sessions
+pub async fn established_sessions() -> (Session, Session){
+    let (handshaker_requestor, handshaker_responder) = complete_handshake("Arbitrary Pin").await.unwrap();
+    (handshaker_requestor.into_session().unwrap(), handshaker_responder.into_session().unwrap())
+}
+//One of a resumable handshake's private keys taken from its saved state, like the step_2_private it signs with until the acknowledgement
+pub async fn saved_private_key(handshake:&Handshake, key:&str) -> PrivateKey{
+    let crypto = fetch_backend().unwrap();
+    let state = handshake.export_state(&[7; 32]).await.unwrap();
+    let state:Value = serde_json::from_slice(&unwrap(&crypto, &[7; 32], state.as_slice()).await.unwrap()).unwrap();
+    crypto.import_private_key(state[key].as_str().unwrap()).await.unwrap()
+}
+//Sends the plaintext from one session and checks the other reads it back
+pub async fn assert_delivered(from:&mut Session, to:&mut Session, plaintext:&str){
+    let sent = from.send(Transitable::from_readable(plaintext)).await.unwrap();
+    assert!(to.receive(sent).await.unwrap().as_readable().unwrap() == plaintext);
+}
+pub fn assert_code<T>(result:Result<T, AwakeError>, code:&str){
+    assert!(result.err().unwrap().code() == code);
+}
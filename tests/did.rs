@@ -0,0 +1,54 @@
+//! Native tests for converting keys to and from did:keys, run with cargo test
+
+#![cfg(not(target_arch = "wasm32"))]
+
+use futures::executor::block_on;
+
+use awake::utils::*;
+use awake::backend::CryptoBackend;
+
+#[test]
+fn can_convert_to_did(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (key, _) = gen_key_pair(&crypto, true).await.unwrap();
+        let did = crypto_key_to_did_key(&crypto, &key).await.unwrap();
+        let new_key = did_key_to_crypto_key(&crypto, &did).await.unwrap();
+        let new_did = crypto_key_to_did_key(&crypto, &new_key).await.unwrap();
+        assert!(did == new_did);
+    });
+}
+#[test]
+fn can_read_published_did_keys(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        //did:key P-256 examples with the x and y of their jwks
+        let published = [
+            ("did:key:zDnaerDaTF5BXEavCrfRZEk316dpbLsfPDZ3WJ5hRTPFU2169", "fyNYMN0976ci7xqiSdag3buk-ZCwgXU4kz9XNkBlNUI", "hW2ojTNfH7Jbi8--CJUo3OCbH3y5n91g-IMA9MLMbTU"),
+            ("did:key:zDnaerx9CtbPJ1q36T5Ln5wYt3MQYeGRG5ehnPAmxcf5mDZpv", "igrFmi0whuihKnj9R3Om1SoMph72wUGeFaBbzG2vzns", "efsX5b10x8yjyrj4ny3pGfLcY7Xby1KzgqOdqnsrJIM")
+        ];
+        for (did, x, y) in published {
+            let key = did_key_to_crypto_key(&crypto, did).await.unwrap();
+            let point = crypto.export_public_key(&key).await.unwrap();
+            assert!(point[1..33] == base64::decode_config(x, base64::URL_SAFE_NO_PAD).unwrap());
+            assert!(point[33..] == base64::decode_config(y, base64::URL_SAFE_NO_PAD).unwrap());
+            assert!(crypto_key_to_did_key(&crypto, &key).await.unwrap() == did);
+            assert!(bytes_to_did_key(&point).unwrap() == did);
+        }
+
+        //an uncompressed point under the P-256 codec is still read, it is written back compressed
+        let key = did_key_to_crypto_key(&crypto, published[0].0).await.unwrap();
+        let mut uncompressed = vec![0x80, 0x24];
+        uncompressed.append(&mut crypto.export_public_key(&key).await.unwrap());
+        let uncompressed_did = format!("did:key:z{}", bs58::encode(uncompressed).into_string());
+        assert!(bytes_to_did_key(&did_key_to_bytes(&uncompressed_did).unwrap()).unwrap() == published[0].0);
+
+        //other key types and encodings are turned away with an error that says so
+        let code = |did:&str| did_key_to_bytes(did).err().unwrap().code();
+        assert!(code("did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK") == "UNSUPPORTED_DID_KEY");
+        assert!(code("did:key:mgCQC") == "UNSUPPORTED_DID_KEY");
+        assert!(code("did:key:z0OIl") == "BAD_DID_KEY");
+        assert!(code("did:web:example.com") == "BAD_DID_KEY");
+        assert!(code(&format!("did:key:z{}", bs58::encode([0x80, 0x24, 2, 1, 2, 3]).into_string())) == "BAD_DID_KEY");
+    });
+}
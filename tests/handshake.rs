@@ -0,0 +1,348 @@
+//! Native tests for the handshake, run with cargo test
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use futures::executor::block_on;
+use serde_json::Value;
+
+use awake::utils::*;
+#[cfg(feature = "test-vectors")]
+use awake::backend::Backend;
+use awake::handshake::{Handshake, HandshakeStage, ChallengeType, FinReason};
+use awake::transitable::Transitable;
+use awake::error::AwakeError;
+
+use common::*;
+
+#[test]
+fn can_reject_pin(){
+    block_on(async {
+        assert!(complete_handshake("Wrong Pin").await.err() == Some(AwakeError::PinRejected));
+    });
+}
+#[test]
+fn can_handshake_without_challenge(){
+    block_on(async {
+        let (mut handshaker_requestor, mut handshaker_responder) = new_handshakes().await;
+        //there is no proof for the validator to look at
+        run_handshake(&mut handshaker_requestor, &mut handshaker_responder, ChallengeType::None, "", |_| Ok(false)).await.unwrap();
+        assert!(handshaker_requestor.is_done() && handshaker_responder.is_done());
+    });
+}
+async fn complete_ucan_handshake(delegate:Option<fn(&str) -> String>, is_proof_valid: impl Fn(&Value) -> Result<bool, AwakeError>) -> Result<(), AwakeError>{
+    let mut handshaker_requestor = Handshake::new().await?;
+    let mut handshaker_responder = Handshake::new().await?;
+
+    //a third agent delegates to the requestor's real did
+    if let Some(alter) = delegate {
+        let root = Handshake::new().await?;
+        let delegation = root.delegate_with(&handshaker_requestor.did(), &[], 60, &[]).await?;
+        handshaker_requestor.add_proof(&alter(&delegation))?;
+    }
+    run_handshake(&mut handshaker_requestor, &mut handshaker_responder, ChallengeType::UcanProof, "", is_proof_valid).await
+}
+#[test]
+fn can_handshake_with_ucan_proof(){
+    block_on(async {
+        //the validator sees the requestor's ucan followed by the delegation
+        complete_ucan_handshake(Some(|proof| proof.to_string()), |chain| Ok(chain.as_array().unwrap().len() == 2)).await.unwrap();
+    });
+}
+#[test]
+fn can_reject_ucan_proof(){
+    block_on(async {
+        let result = complete_ucan_handshake(None, |chain| Ok(chain.as_array().unwrap().len() == 2)).await;
+        assert_code(result, "PROOF_REJECTED");
+    });
+}
+#[test]
+fn can_reject_tampered_ucan(){
+    block_on(async {
+        //push the delegation's expiry back without re-signing it
+        let result = complete_ucan_handshake(Some(|proof| {
+            let parts:Vec<&str> = proof.split('.').collect();
+            let mut payload:Value = serde_json::from_slice(&base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD).unwrap()).unwrap();
+            payload["exp"] = Value::from(payload["exp"].as_u64().unwrap() + 3600);
+            format!("{}.{}.{}", parts[0], base64::encode_config(payload.to_string(), base64::URL_SAFE_NO_PAD), parts[2])
+        }), |_| Ok(true)).await;
+        assert_code(result, "UCAN_SIGNATURE_FAILURE");
+    });
+}
+#[test]
+fn can_reject_spliced_handshake(){
+    block_on(async {
+        let (mut handshaker_requestor, mut handshaker_responder) = new_handshakes().await;
+
+        //the responder answers an older request than the one the requestor carries on from, both are properly signed
+        let older = handshaker_requestor.request_with(&[]).await.unwrap();
+        let capability = UcanCapability{with: "awake://test".to_string(), can: "test/read".to_string(), nb: None};
+        handshaker_requestor.request_with(&[capability]).await.unwrap();
+        let response = handshaker_responder.reponse_with(older, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await.unwrap();
+        let challenge = handshaker_requestor.challenge_response_with(response, "Arbitrary Pin", |_| Ok(true)).await.unwrap();
+        let ack = handshaker_responder.acknowledge_challenge_with(challenge, |pin| Ok(*pin == "Arbitrary Pin")).await.unwrap();
+
+        //the two transcripts differ so the requestor can not even find the acknowledgement
+        assert!(handshaker_requestor.accept_acknowledgement(ack).await.is_err());
+        assert!(!handshaker_requestor.is_done());
+    });
+}
+#[test]
+fn can_accept_acknowledgement_from_each_partner(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (_, imposter_private) = gen_key_pair(&crypto, true).await.unwrap();
+        let mut handshaker_requestor = Handshake::new().await.unwrap();
+        let mut first_responder = Handshake::new().await.unwrap();
+        let mut second_responder = Handshake::new().await.unwrap();
+
+        //two responders answer the same request and are both challenged
+        let request = handshaker_requestor.request_with(&[]).await.unwrap();
+        let first_response = first_responder.reponse_with(request.clone(), &[], 60, ChallengeType::OobPin, |_| Ok(true)).await.unwrap();
+        let second_response = second_responder.reponse_with(request, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await.unwrap();
+        let first_challenge = handshaker_requestor.challenge_response_with(first_response, "Arbitrary Pin", |_| Ok(true)).await.unwrap();
+        let second_challenge = handshaker_requestor.challenge_response_with(second_response, "Arbitrary Pin", |_| Ok(true)).await.unwrap();
+        first_responder.acknowledge_challenge_with(first_challenge, |_| Ok(true)).await.unwrap();
+        let ack = second_responder.acknowledge_challenge_with(second_challenge, |_| Ok(true)).await.unwrap();
+
+        //an ack that was not signed by the responder's real did is turned away and nothing changes
+        let forged = ack.unsign().unwrap().sign(&crypto, &imposter_private, None).await.unwrap();
+        assert_code(handshaker_requestor.accept_acknowledgement(forged).await, "SIGNATURE_FAILURE");
+        assert!(handshaker_requestor.stage() == HandshakeStage::Challenged);
+
+        //an ack with a mid none of our challenges expect is turned away before any session keys are derived
+        let mut junk:Value = serde_json::from_str(&ack.unsign().unwrap().as_readable().unwrap()).unwrap();
+        junk["mid"] = Value::from(base64::encode([0u8; 32]));
+        let junk = Transitable::from_readable(&junk.to_string()).sign(&crypto, &imposter_private, None).await.unwrap();
+        assert_code(handshaker_requestor.accept_acknowledgement(junk).await, "UNKNOWN_MID");
+        assert!(handshaker_requestor.stage() == HandshakeStage::Challenged);
+
+        //the session is with the responder that sent the ack
+        handshaker_requestor.accept_acknowledgement(ack).await.unwrap();
+        let mut requestor = handshaker_requestor.into_session().unwrap();
+        let mut responder = second_responder.into_session().unwrap();
+        assert_delivered(&mut requestor, &mut responder, TEST_STRINGS[0]).await;
+        assert_delivered(&mut responder, &mut requestor, TEST_STRINGS[1]).await;
+    });
+}
+#[test]
+fn can_send_failure_messages(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (_, imposter_private) = gen_key_pair(&crypto, true).await.unwrap();
+        //the responder rejects the capabilities before it has sent anything
+        let (mut handshaker_requestor, mut handshaker_responder) = new_handshakes().await;
+        let request = handshaker_requestor.request_with(&[]).await.unwrap();
+        let result = handshaker_responder.reponse_with(request, &[], 60, ChallengeType::OobPin, |_| Ok(false)).await;
+        assert!(result.err() == Some(AwakeError::CapabilityRejected));
+        let fin = handshaker_responder.take_failure().unwrap();
+        assert!(handshaker_responder.stage() == HandshakeStage::Started);
+        //the requestor has not heard from that responder but the fin is encrypted for its request so it can be read once
+        assert!(handshaker_requestor.read_failure(fin.clone()).await.unwrap() == FinReason::CapabilitiesRejected);
+        assert_code(handshaker_requestor.read_failure(fin).await, "UNEXPECTED_MESSAGE");
+        assert!(handshaker_requestor.stage() == HandshakeStage::Started);
+
+        //the requestor rejects the ucan
+        let request = handshaker_requestor.request_with(&[]).await.unwrap();
+        let response = handshaker_responder.reponse_with(request, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await.unwrap();
+        let result = handshaker_requestor.challenge_response_with(response, "Arbitrary Pin", |_| Ok(false)).await;
+        assert!(result.err() == Some(AwakeError::UcanRejected));
+        let fin = handshaker_requestor.take_failure().unwrap();
+        assert!(handshaker_responder.read_failure(fin).await.unwrap() == FinReason::UcanRejected);
+        assert!(handshaker_responder.stage() == HandshakeStage::Started);
+
+        //the responder rejects the pin after the requestor has challenged
+        let challenge = challenge_with(&mut handshaker_requestor, &mut handshaker_responder, ChallengeType::OobPin, "Wrong Pin").await.unwrap();
+        let result = handshaker_responder.acknowledge_challenge_with(challenge, |pin| Ok(*pin == "Arbitrary Pin")).await;
+        assert!(result.err() == Some(AwakeError::PinRejected));
+        assert!(handshaker_responder.stage() == HandshakeStage::Started);
+        let fin = handshaker_responder.take_failure().unwrap();
+        assert!(handshaker_responder.take_failure().is_none());
+        let forged = fin.unsign().unwrap().sign(&crypto, &imposter_private, None).await.unwrap();
+        assert_code(handshaker_requestor.read_failure(forged).await, "SIGNATURE_FAILURE");
+        assert!(handshaker_requestor.stage() == HandshakeStage::Challenged);
+        assert!(handshaker_requestor.read_failure(fin).await.unwrap() == FinReason::ChallengeRejected);
+        assert!(handshaker_requestor.stage() == HandshakeStage::Started);
+
+        //a challenge the requestor did not sign changes nothing
+        let mut handshaker_requestor = Handshake::new_resumable().await.unwrap();
+        let challenge = challenge_with(&mut handshaker_requestor, &mut handshaker_responder, ChallengeType::OobPin, "Arbitrary Pin").await.unwrap();
+        let mut garbled:Value = serde_json::from_str(&challenge.unsign().unwrap().as_readable().unwrap()).unwrap();
+        garbled["msg"] = Value::from(base64::encode([0u8; 64]));
+        let garbled = Transitable::from_readable(&garbled.to_string());
+        let forged = garbled.sign(&crypto, &imposter_private, None).await.unwrap();
+        assert_code(handshaker_responder.acknowledge_challenge_with(forged, |_| Ok(true)).await, "SIGNATURE_FAILURE");
+        assert!(handshaker_responder.take_failure().is_none());
+
+        //but one it signed that can not be decrypted is turned down with a fin
+        let step_2_private = saved_private_key(&handshaker_requestor, "step_2_private").await;
+        let step_2_did = challenge.kid().unwrap().unwrap();
+        let garbled = garbled.sign(&crypto, &step_2_private, Some(&step_2_did)).await.unwrap();
+        assert_code(handshaker_responder.acknowledge_challenge_with(garbled, |_| Ok(true)).await, "DECRYPTION_FAILURE");
+        let fin = handshaker_responder.take_failure().unwrap();
+        assert!(handshaker_requestor.read_failure(fin).await.unwrap() == FinReason::Other);
+        assert!(handshaker_requestor.stage() == HandshakeStage::Started);
+        //and has forgotten the requestor, so the real challenge is not found either
+        assert_code(handshaker_responder.acknowledge_challenge_with(challenge, |_| Ok(true)).await, "UNKNOWN_MID");
+    });
+}
+#[test]
+fn can_negotiate_version(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (_, other_private) = gen_key_pair(&crypto, true).await.unwrap();
+        let mut handshaker_requestor = Handshake::new_resumable().await.unwrap();
+        let mut handshaker_responder = Handshake::new().await.unwrap();
+        let request = handshaker_requestor.request_with(&[]).await.unwrap();
+        //the altered requests are signed again with the requestor's step 2 key
+        let (step_2_private, step_2_did) = (saved_private_key(&handshaker_requestor, "step_2_private").await, request.kid().unwrap().unwrap());
+        let request_json:Value = serde_json::from_str(&request.unsign().unwrap().as_readable().unwrap()).unwrap();
+        assert!(request_json["versions"] == serde_json::json!(["0.1.0"]));
+
+        //the responder answers in the newest version it shares with the requestor
+        let mut offered = request_json.clone();
+        offered["versions"] = serde_json::json!(["0.1.0", "0.1.4", "9.0.0"]);
+        let offered = Transitable::from_readable(&offered.to_string()).sign(&crypto, &step_2_private, Some(&step_2_did)).await.unwrap();
+        let response = handshaker_responder.reponse_with(offered, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await.unwrap();
+        let response_json:Value = serde_json::from_str(&response.unsign().unwrap().as_readable().unwrap()).unwrap();
+        assert!(response_json["awv"] == "0.1.0");
+
+        //older requestors only speak the version their request was written in, the oldest may not say which that is
+        let mut older = request_json.clone();
+        older.as_object_mut().unwrap().remove("versions");
+        let mut oldest = older.clone();
+        oldest.as_object_mut().unwrap().remove("awv");
+        let oldest = Transitable::from_readable(&oldest.to_string()).sign(&crypto, &step_2_private, Some(&step_2_did)).await.unwrap();
+        assert!(handshaker_responder.reponse_with(oldest, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await.is_ok());
+        let older = Transitable::from_readable(&older.to_string()).sign(&crypto, &step_2_private, Some(&step_2_did)).await.unwrap();
+        assert!(handshaker_responder.reponse_with(older, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await.is_ok());
+
+        //with nothing in common the request is turned away with an awake/fin
+        let mut disjoint = request_json.clone();
+        disjoint["versions"] = serde_json::json!(["9.0.0"]);
+        let disjoint = Transitable::from_readable(&disjoint.to_string()).sign(&crypto, &step_2_private, Some(&step_2_did)).await.unwrap();
+        let error = handshaker_responder.reponse_with(disjoint, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await.err().unwrap();
+        assert!(error.code() == "UNSUPPORTED_VERSION");
+        assert!(FinReason::from_error(&error) == FinReason::UnsupportedVersion);
+        //the requestor can read why even though it has not heard from this responder yet
+        let fin = handshaker_responder.take_failure().unwrap();
+        assert!(handshaker_requestor.read_failure(fin).await.unwrap() == FinReason::UnsupportedVersion);
+
+        //a response in a version the requestor never offered is not read
+        let mut newer = response_json.clone();
+        newer["awv"] = Value::from("0.2.0");
+        let newer = Transitable::from_readable(&newer.to_string()).sign(&crypto, &other_private, None).await.unwrap();
+        let result = handshaker_requestor.challenge_response_with(newer, "Arbitrary Pin", |_| Ok(true)).await;
+        assert_code(result, "UNSUPPORTED_VERSION");
+    });
+}
+#[test]
+fn can_reject_forged_request(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (_, imposter_private) = gen_key_pair(&crypto, true).await.unwrap();
+        let (mut handshaker_requestor, mut handshaker_responder) = new_handshakes().await;
+
+        //re-sign the request with a key that is not the requestor's step 2 key, with and without naming that key
+        let request = handshaker_requestor.request_with(&[]).await.unwrap();
+        let step_2_did = request.kid().unwrap().unwrap();
+        let forged = request.unsign().unwrap().sign(&crypto, &imposter_private, None).await.unwrap();
+        assert_code(handshaker_responder.reponse_with(forged, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await, "SIGNATURE_FAILURE");
+        let forged = request.unsign().unwrap().sign(&crypto, &imposter_private, Some(&step_2_did)).await.unwrap();
+        assert_code(handshaker_responder.reponse_with(forged, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await, "SIGNATURE_FAILURE");
+        //nothing is answered so there is no fin
+        assert!(handshaker_responder.take_failure().is_none());
+        assert!(handshaker_responder.stage() == HandshakeStage::Started);
+    });
+}
+#[test]
+fn can_reject_forged_response(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (_, imposter_private) = gen_key_pair(&crypto, true).await.unwrap();
+        let (mut handshaker_requestor, mut handshaker_responder) = new_handshakes().await;
+
+        //re-sign the response with a key that is not the ucan issuer's
+        let request = handshaker_requestor.request_with(&[]).await.unwrap();
+        let response = handshaker_responder.reponse_with(request, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await.unwrap();
+        let forged = response.unsign().unwrap().sign(&crypto, &imposter_private, None).await.unwrap();
+        let result = handshaker_requestor.challenge_response_with(forged, "Arbitrary Pin", |_| Ok(true)).await;
+        assert!(result.err() == Some(AwakeError::ResponseSignatureFailure));
+    });
+}
+#[test]
+fn can_reject_expired_ucan(){
+    block_on(async {
+        let (mut handshaker_requestor, mut handshaker_responder) = new_handshakes().await;
+
+        //a responder whose clock is a minute behind issues a ucan that has already expired
+        handshaker_responder.set_clock(|| ucan::time::now() - 60);
+        let request = handshaker_requestor.request_with(&[]).await.unwrap();
+        let response = handshaker_responder.reponse_with(request, &[], 30, ChallengeType::OobPin, |_| Ok(true)).await.unwrap();
+        let result = handshaker_requestor.challenge_response_with(response, "Arbitrary Pin", |_| Ok(true)).await;
+        assert!(result.err() == Some(AwakeError::UcanExpired));
+    });
+}
+#[test]
+fn can_resume_handshake(){
+    block_on(async {
+        let wrapping_key = [7; 32];
+        //only resumable handshakes generate keys that can be exported
+        assert_code(Handshake::new().await.unwrap().export_state(&wrapping_key).await, "BAD_STATE");
+        let mut handshaker_requestor = Handshake::new_resumable().await.unwrap();
+        let mut handshaker_responder = Handshake::new_resumable().await.unwrap();
+
+        let request = handshaker_requestor.request_with(&[]).await.unwrap();
+        let response = handshaker_responder.reponse_with(request, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await.unwrap();
+        assert!(handshaker_responder.stage() == HandshakeStage::Responded);
+        let saved_responder = handshaker_responder.export_state(&wrapping_key).await.unwrap();
+        let challenge = handshaker_requestor.challenge_response_with(response, "Arbitrary Pin", |_| Ok(true)).await.unwrap();
+        let saved_requestor = handshaker_requestor.export_state(&wrapping_key).await.unwrap();
+
+        //a wrong wrapping key can not restore the state
+        assert_code(Handshake::import_state(saved_responder.clone(), &[8; 32]).await, "BAD_STATE");
+
+        let mut handshaker_responder = Handshake::import_state(saved_responder, &wrapping_key).await.unwrap();
+        let mut handshaker_requestor = Handshake::import_state(saved_requestor, &wrapping_key).await.unwrap();
+        assert!(handshaker_requestor.stage() == HandshakeStage::Challenged);
+        let ack = handshaker_responder.acknowledge_challenge_with(challenge, |_| Ok(true)).await.unwrap();
+        handshaker_requestor.accept_acknowledgement(ack).await.unwrap();
+        assert!(handshaker_requestor.stage() == HandshakeStage::Done);
+
+        let mut requestor = handshaker_requestor.into_session().unwrap();
+        let mut responder = handshaker_responder.into_session().unwrap();
+        assert_delivered(&mut requestor, &mut responder, TEST_STRINGS[0]).await;
+
+        //the clock is not saved, a responder restored with one that is behind issues ucans that have already expired
+        let saved_responder = Handshake::new_resumable().await.unwrap().export_state(&wrapping_key).await.unwrap();
+        let mut handshaker_responder = Handshake::import_state_with_clock(saved_responder, &wrapping_key, || ucan::time::now() - 60).await.unwrap();
+        let mut handshaker_requestor = Handshake::new().await.unwrap();
+        let request = handshaker_requestor.request_with(&[]).await.unwrap();
+        let response = handshaker_responder.reponse_with(request, &[], 30, ChallengeType::OobPin, |_| Ok(true)).await.unwrap();
+        assert_code(handshaker_requestor.challenge_response_with(response, "Arbitrary Pin", |_| Ok(true)).await, "UCAN_EXPIRED");
+    });
+}
+#[test]
+#[cfg(feature = "test-vectors")]
+fn can_repeat_seeded_handshake(){
+    block_on(async {
+        //keys come from the seeded rng so the same seed gives the same agents every run
+        Backend::seed_rng(7);
+        let seeded_did = Handshake::new().await.unwrap().did();
+        Backend::seed_rng(7);
+        let (handshaker_requestor, handshaker_responder) = complete_handshake("Arbitrary Pin").await.unwrap();
+        assert!(handshaker_requestor.did() == seeded_did);
+        Backend::seed_rng(8);
+        assert!(Handshake::new().await.unwrap().did() != seeded_did);
+        Backend::unseed_rng();
+
+        //request, response, challenge and ack are done, the session carries on from there
+        let mut requestor = handshaker_requestor.into_session().unwrap();
+        let mut responder = handshaker_responder.into_session().unwrap();
+        for payload in TEST_STRINGS {
+            assert_delivered(&mut responder, &mut requestor, payload).await;
+            assert_delivered(&mut requestor, &mut responder, payload).await;
+        }
+    });
+}
@@ -0,0 +1,128 @@
+//! Native tests for signing transitables and reading who signed them, run with cargo test
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use futures::executor::block_on;
+use serde_json::Value;
+use p256::ecdsa::{SigningKey, VerifyingKey, Signature};
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::signature::hazmat::PrehashSigner;
+use sha2::{Sha512, Digest};
+
+use awake::utils::*;
+use awake::backend::CryptoBackend;
+use awake::handshake::ChallengeType;
+use awake::transitable::Transitable;
+
+use common::*;
+
+#[test]
+fn can_sign(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (public_key, private_key) = gen_key_pair(&crypto, true).await.unwrap();
+        let (public_key_imposter, _) = gen_key_pair(&crypto, true).await.unwrap();
+        for payload in TEST_STRINGS {
+            let data = Transitable::from_readable(payload).sign(&crypto, &private_key, None).await.unwrap();
+            assert!(data.verify(&crypto, &public_key).await.unwrap());
+            assert!(!data.verify(&crypto, &public_key_imposter).await.unwrap());
+            assert!(data.unsign().unwrap().as_readable().unwrap() == *payload);
+        }
+    });
+}
+#[test]
+fn can_sign_compact_jws(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (public_key, private_key) = gen_key_pair(&crypto, true).await.unwrap();
+        let did = crypto_key_to_did_key(&crypto, &public_key).await.unwrap();
+        let jws = Transitable::from_readable(TEST_STRINGS[1]).sign(&crypto, &private_key, Some(&did)).await.unwrap();
+        let jws_str = jws.as_readable().unwrap();
+        assert!(!jws_str.contains(['+', '/', '=']));
+        assert!(jws.kid().unwrap().unwrap() == did);
+
+        //the header is plain json and the signature is ES256 over header.payload, as any JOSE library checks it
+        let sections:Vec<&str> = jws_str.split('.').collect();
+        let header:Value = serde_json::from_slice(&base64::decode_config(sections[0], base64::URL_SAFE_NO_PAD).unwrap()).unwrap();
+        assert!(header["alg"] == "ES256" && header["kid"] == did.as_str());
+        let signature = Signature::from_slice(&base64::decode_config(sections[2], base64::URL_SAFE_NO_PAD).unwrap()).unwrap();
+        let signing_input = format!("{}.{}", sections[0], sections[1]);
+        assert!(VerifyingKey::from(&public_key).verify(signing_input.as_bytes(), &signature).is_ok());
+
+        //the ES256 example from RFC 7515 appendix A.3
+        let x = base64::decode_config("f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU", base64::URL_SAFE_NO_PAD).unwrap();
+        let y = base64::decode_config("x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0", base64::URL_SAFE_NO_PAD).unwrap();
+        let rfc_key = crypto.import_public_key(&[vec![4], x, y].concat()).await.unwrap();
+        let rfc_jws = Transitable::from_readable(concat!(
+            "eyJhbGciOiJFUzI1NiJ9",
+            ".eyJpc3MiOiJqb2UiLA0KICJleHAiOjEzMDA4MTkzODAsDQogImh0dHA6Ly9leGFtcGxlLmNvbS9pc19yb290Ijp0cnVlfQ",
+            ".DtEhU3ljbEg8L38VWAfUAqOyKAM6-Xx-F4GawxaepmXFCgfTjDxw5djxLa8ISlSApmWQxfKTUJqPP3-Kg6NU1Q"
+        ));
+        assert!(rfc_jws.verify(&crypto, &rfc_key).await.unwrap());
+        assert!(!rfc_jws.verify(&crypto, &public_key).await.unwrap());
+        assert!(rfc_jws.kid().unwrap().is_none());
+        assert!(rfc_jws.unsign().unwrap().as_readable().unwrap().starts_with("{\"iss\":\"joe\""));
+
+        //other algorithms are turned away rather than checked as ES256
+        let es512 = format!("{}.{}", base64::encode_config(r#"{"alg":"ES512"}"#, base64::URL_SAFE_NO_PAD), sections[1..].join("."));
+        assert_code(Transitable::from_readable(&es512).verify(&crypto, &public_key).await, "BAD_JWT");
+    });
+}
+#[test]
+fn can_name_signer_in_kid(){
+    block_on(async {
+        let (mut handshaker_requestor, mut handshaker_responder) = new_handshakes().await;
+        let kid_of = |message:&Transitable| -> Value {
+            let header = message.as_readable().unwrap().split('.').next().unwrap().to_string();
+            let header:Value = serde_json::from_slice(&base64::decode_config(header, base64::URL_SAFE_NO_PAD).unwrap()).unwrap();
+            header["kid"].clone()
+        };
+
+        let read = |message:&Transitable| -> Value { serde_json::from_str(&message.unsign().unwrap().as_readable().unwrap()).unwrap() };
+
+        //every message names the key that signed it, the step 2 did until the real did has been learnt through the encrypted channel
+        let request = handshaker_requestor.request_with(&[]).await.unwrap();
+        let requestor_step_2_did = read(&request)["did"].clone();
+        assert!(kid_of(&request) == requestor_step_2_did);
+        let response = handshaker_responder.reponse_with(request, &[], 60, ChallengeType::OobPin, |_| Ok(true)).await.unwrap();
+        assert!(kid_of(&response) == read(&response)["iss"]);
+        let challenge = handshaker_requestor.challenge_response_with(response, "Arbitrary Pin", |_| Ok(true)).await.unwrap();
+        assert!(challenge.kid().unwrap().unwrap() == requestor_step_2_did);
+        let ack = handshaker_responder.acknowledge_challenge_with(challenge, |_| Ok(true)).await.unwrap();
+        assert!(ack.kid().unwrap().unwrap() == handshaker_responder.did());
+
+        //a fin can go to an agent that has not learnt our real did
+        let (mut handshaker_requestor, mut handshaker_responder) = new_handshakes().await;
+        let request = handshaker_requestor.request_with(&[]).await.unwrap();
+        assert!(handshaker_responder.reponse_with(request, &[], 60, ChallengeType::OobPin, |_| Ok(false)).await.is_err());
+        let fin = handshaker_responder.take_failure().unwrap();
+        assert!(kid_of(&fin) == read(&fin)["iss"] && kid_of(&fin) != handshaker_responder.did());
+    });
+}
+#[test]
+fn can_accept_legacy_jws(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (public_key, private_key) = gen_key_pair(&crypto, true).await.unwrap();
+        let (public_key_imposter, _) = gen_key_pair(&crypto, true).await.unwrap();
+        //the old format: an escaped ES512 header, padded base64 and a SHA-512 signature over just the payload
+        let payload = TEST_STRINGS[2].as_bytes();
+        let signature:Signature = SigningKey::from(&private_key).sign_prehash(&Sha512::digest(payload)).unwrap();
+        let legacy = Transitable::from_readable(&format!("{}.{}.{}",
+            base64::encode("{{\"alg\": \"ES512\", \"typ\": \"JWT\" }}"),
+            base64::encode(payload),
+            base64::encode(signature.to_bytes())
+        ));
+
+        assert!(legacy.is_signed());
+        assert!(legacy.unsign().unwrap().as_readable().unwrap() == TEST_STRINGS[2]);
+        assert!(legacy.kid().unwrap().is_none());
+        //refusing the old format is told apart from a message that is not a jws at all
+        assert_code(legacy.verify(&crypto, &public_key).await, "LEGACY_JWS_REFUSED");
+        assert_code(Transitable::from_readable("not.a jws").verify(&crypto, &public_key).await, "BAD_JWT");
+        assert!(legacy.verify_with(&crypto, &public_key, true).await.unwrap());
+        assert!(!legacy.verify_with(&crypto, &public_key_imposter, true).await.unwrap());
+    });
+}
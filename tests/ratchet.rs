@@ -0,0 +1,209 @@
+//! Native tests for the hash ratchet, the double ratchet and the known answer vectors, run with cargo test
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use futures::executor::block_on;
+use serde_json::Value;
+
+use awake::utils::*;
+use awake::backend::{CryptoBackend, PublicKey, PrivateKey, SharedSecret};
+use awake::foreign_agent::ForeignAgent;
+use awake::mid_window::{derive_mid_key, mid_for};
+use awake::transitable::Transitable;
+use awake::ratchet::{Ratchet, SkipLimits};
+use awake::double_ratchet::DoubleRatchet;
+use awake::error::AwakeError;
+
+use common::*;
+
+//Secrets a sender and a reciever agreed, with the reciever's key pair a double ratchet starts from
+async fn agreed_secrets() -> (SharedSecret, SharedSecret, PublicKey, PrivateKey){
+    let crypto = fetch_backend().unwrap();
+    let (sender_public, sender_private) = gen_key_pair(&crypto, true).await.unwrap();
+    let (reciever_public, reciever_private) = gen_key_pair(&crypto, true).await.unwrap();
+    let sender_key = diffie_helman(&crypto, &sender_private, &reciever_public).await.unwrap();
+    let reciever_key = diffie_helman(&crypto, &reciever_private, &sender_public).await.unwrap();
+    (sender_key, reciever_key, reciever_public, reciever_private)
+}
+
+#[test]
+fn can_rachet_crypto(){
+    block_on(async {
+        let (sender_key, reciever_key, _, _) = agreed_secrets().await;
+
+        let salt = "this is a salt".as_bytes().to_vec();
+        let mut sender_ratchet = Ratchet::new(sender_key, true, salt.clone()).await.unwrap();
+        let mut reciever_ratchet = Ratchet::new(reciever_key, false, salt).await.unwrap();
+        for (id, payload) in TEST_STRINGS.iter().enumerate() {
+            let sent = sender_ratchet.process_payload(id*5, Transitable::from_readable(payload)).await.unwrap();
+            let recieved = reciever_ratchet.process_payload(id*5, sent.clone()).await.unwrap();
+            assert!(recieved.as_readable().unwrap() == *payload);
+            assert!(reciever_ratchet.process_payload(id*5, sent).await.err() == Some(AwakeError::Replay(id*5)));
+        }
+    });
+}
+#[test]
+fn can_replay_test_vectors(){
+    block_on(async {
+        //regenerate with cargo run --features test-vectors --example gen_vectors > tests/vectors.json
+        let vectors:Value = serde_json::from_str(include_str!("vectors.json")).unwrap();
+        let crypto = fetch_backend().unwrap();
+        let bytes = |value:&Value| base64::decode(value.as_str().unwrap()).unwrap();
+
+        let requestor_private = crypto.import_private_key(vectors["requestor"]["private"].as_str().unwrap()).await.unwrap();
+        let responder_private = crypto.import_private_key(vectors["responder"]["private"].as_str().unwrap()).await.unwrap();
+        let requestor_public = crypto.import_public_key(&bytes(&vectors["requestor"]["public"])).await.unwrap();
+        let responder_public = crypto.import_public_key(&bytes(&vectors["responder"]["public"])).await.unwrap();
+        assert!(crypto_key_to_did_key(&crypto, &requestor_public).await.unwrap() == vectors["requestor"]["did"]);
+        assert!(crypto_key_to_did_key(&crypto, &responder_public).await.unwrap() == vectors["responder"]["did"]);
+
+        let shared_secret = diffie_helman(&crypto, &responder_private, &requestor_public).await.unwrap();
+        assert!(shared_secret.as_bytes() == bytes(&vectors["ecdh"]));
+
+        //each direction has its own mid key, a mid is the hmac of the message's index under it
+        let mids = &vectors["mids"];
+        for (info, direction) in [("requestor_info", "requestor"), ("responder_info", "responder")] {
+            let key = derive_mid_key(&shared_secret, &bytes(&mids["salt"]), &bytes(&mids[info])).await.unwrap();
+            assert!(key == bytes(&mids[direction]["key"]));
+            for mid in mids[direction]["mids"].as_array().unwrap() {
+                assert!(mid_for(&key, mid["id"].as_u64().unwrap() as usize).await.unwrap() == mid["mid"]);
+            }
+        }
+
+        //every link of the chain is hkdf over the shared secret with the previous link's secret as the info
+        let salt = bytes(&vectors["ratchet"]["salt"]);
+        let mut info = vec![];
+        for link in vectors["ratchet"]["links"].as_array().unwrap() {
+            let key_data = crypto.hkdf(&shared_secret, &salt, &info, 76).await.unwrap();
+            assert!(key_data[..32] == bytes(&link["secret"]));
+            assert!(key_data[32..64] == bytes(&link["aes_key"]));
+            assert!(key_data[64..] == bytes(&link["iv"]));
+            info = key_data[..32].to_vec();
+        }
+        let mut sender_ratchet = Ratchet::new(shared_secret.clone(), true, salt.clone()).await.unwrap();
+        let mut reciever_ratchet = Ratchet::new(shared_secret.clone(), false, salt).await.unwrap();
+        for message in vectors["ratchet"]["messages"].as_array().unwrap() {
+            let id = message["id"].as_u64().unwrap() as usize;
+            let plaintext = message["plaintext"].as_str().unwrap();
+            let sent = sender_ratchet.process_payload(id, Transitable::from_readable(plaintext)).await.unwrap();
+            assert!(sent.as_slice() == bytes(&message["ciphertext"]));
+            assert!(reciever_ratchet.process_payload(id, sent).await.unwrap().as_readable().unwrap() == plaintext);
+        }
+
+        let aes_gcm = &vectors["aes_gcm"];
+        let ciphertext = crypto.encrypt(&bytes(&aes_gcm["key"]), &bytes(&aes_gcm["iv"]), &bytes(&aes_gcm["aad"]), aes_gcm["plaintext"].as_str().unwrap().as_bytes()).await.unwrap();
+        assert!(ciphertext == bytes(&aes_gcm["ciphertext"]));
+
+        //the signature is checked against the signer's public key, the native backend also signs it the same way again
+        let jws = &vectors["jws"];
+        let signed = Transitable::from_readable(jws["jws"].as_str().unwrap());
+        assert!(signed.verify(&crypto, &requestor_public).await.unwrap());
+        assert!(signed.kid().unwrap().unwrap() == jws["kid"] && signed.unsign().unwrap().as_readable().unwrap() == jws["payload"]);
+        let resigned = Transitable::from_readable(jws["payload"].as_str().unwrap()).sign(&crypto, &requestor_private, jws["kid"].as_str()).await.unwrap();
+        assert!(resigned.as_readable().unwrap() == jws["jws"]);
+
+        //the step 2 chains start from the same secret and salt but each direction has its own info
+        let handshake = &vectors["handshake"];
+        for (first_info, chain) in [("requestor_info", "requestor_links"), ("responder_info", "responder_links")] {
+            let mut info = bytes(&handshake[first_info]);
+            for link in handshake[chain].as_array().unwrap() {
+                let key_data = crypto.hkdf(&shared_secret, &bytes(&handshake["salt"]), &info, 76).await.unwrap();
+                assert!(key_data[..32] == bytes(&link["secret"]));
+                assert!(key_data[32..64] == bytes(&link["aes_key"]));
+                assert!(key_data[64..] == bytes(&link["iv"]));
+                info = key_data[..32].to_vec();
+            }
+        }
+
+        //the requestor reads the responder's first handshake message with the salt taken from its own did
+        let mut responder_agent = ForeignAgent::new(&responder_private, vectors["requestor"]["did"].as_str().unwrap(), None).await.unwrap();
+        let (mid, sent) = responder_agent.encrypt_for(Transitable::from_readable(handshake["plaintext"].as_str().unwrap()), &[]).await.unwrap();
+        assert!(mid == handshake["mid"] && sent.as_slice() == bytes(&handshake["ciphertext"]));
+        assert!(mid == vectors["mids"]["responder"]["mids"][0]["mid"]);
+        let mut requestor_agent = ForeignAgent::new(&requestor_private, vectors["responder"]["did"].as_str().unwrap(), Some(&requestor_public)).await.unwrap();
+        assert!(requestor_agent.decrypt_for(0, sent).await.unwrap().as_readable().unwrap() == handshake["plaintext"]);
+    });
+}
+#[test]
+fn can_separate_chain_directions(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (requestor_public, requestor_private) = gen_key_pair(&crypto, true).await.unwrap();
+        let (responder_public, responder_private) = gen_key_pair(&crypto, true).await.unwrap();
+        let requestor_did = crypto_key_to_did_key(&crypto, &requestor_public).await.unwrap();
+        let responder_did = crypto_key_to_did_key(&crypto, &responder_public).await.unwrap();
+        let mut requestor = ForeignAgent::new(&requestor_private, &responder_did, Some(&requestor_public)).await.unwrap();
+        let mut responder = ForeignAgent::new(&responder_private, &requestor_did, None).await.unwrap();
+
+        //the same plaintext at the same index would encrypt the same way if both directions used the same key and iv
+        for id in 0..5 {
+            let (requestor_mid, from_requestor) = requestor.encrypt_for(Transitable::from_readable(TEST_STRINGS[0]), &[]).await.unwrap();
+            let (responder_mid, from_responder) = responder.encrypt_for(Transitable::from_readable(TEST_STRINGS[0]), &[]).await.unwrap();
+            assert!(requestor_mid != responder_mid && requestor_mid != id.to_string());
+            assert!(from_requestor.as_slice() != from_responder.as_slice());
+            assert!(responder.decrypt_with_mid(requestor_mid, from_requestor, &[]).await.unwrap().as_readable().unwrap() == TEST_STRINGS[0]);
+            assert!(requestor.decrypt_with_mid(responder_mid, from_responder, &[]).await.unwrap().as_readable().unwrap() == TEST_STRINGS[0]);
+        }
+    });
+}
+#[test]
+fn can_double_ratchet(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (sender_key, reciever_key, reciever_public, reciever_private) = agreed_secrets().await;
+        let mut sender = DoubleRatchet::new_sender(sender_key, &reciever_public).await.unwrap();
+        let mut reciever = DoubleRatchet::new_reciever(reciever_key, &reciever_private).await.unwrap();
+        assert_code(reciever.encrypt(Transitable::from_readable(TEST_STRINGS[0]), &[]).await, "RATCHET_STATE");
+
+        //messages in the same chain share a ratchet key and can arrive out of order
+        let (first_header, first) = sender.encrypt(Transitable::from_readable(TEST_STRINGS[0]), &[]).await.unwrap();
+        let (second_header, second) = sender.encrypt(Transitable::from_readable(TEST_STRINGS[1]), &[]).await.unwrap();
+        assert!(first_header.dh == second_header.dh && second_header.n == 1);
+        assert!(reciever.decrypt(&second_header, second, &[]).await.unwrap().as_readable().unwrap() == TEST_STRINGS[1]);
+        assert!(reciever.decrypt(&first_header, first, &[]).await.unwrap().as_readable().unwrap() == TEST_STRINGS[0]);
+
+        //every reply moves to a new ratchet key
+        let (reply_header, reply) = reciever.encrypt(Transitable::from_readable(TEST_STRINGS[2]), &[]).await.unwrap();
+        assert!(sender.decrypt(&reply_header, reply, &[]).await.unwrap().as_readable().unwrap() == TEST_STRINGS[2]);
+        let (third_header, third) = sender.encrypt(Transitable::from_readable(TEST_STRINGS[0]), &[]).await.unwrap();
+        assert!(third_header.dh != first_header.dh && third_header.n == 0 && third_header.pn == 2);
+
+        //a forged ratchet key does not move the ratchet on
+        let (forged_public, _) = gen_key_pair(&crypto, true).await.unwrap();
+        let mut forged_header = third_header.clone();
+        let forged_did = crypto_key_to_did_key(&crypto, &forged_public).await.unwrap();
+        forged_header.dh = base64::encode(did_key_to_bytes(&forged_did).unwrap());
+        assert!(reciever.decrypt(&forged_header, third.clone(), &[]).await.is_err());
+        assert!(reciever.decrypt(&third_header, third, &[]).await.unwrap().as_readable().unwrap() == TEST_STRINGS[0]);
+    });
+}
+#[test]
+fn can_bound_skipped_keys(){
+    block_on(async {
+        let (sender_key, reciever_key, _, _) = agreed_secrets().await;
+        let mut sender_ratchet = Ratchet::new(sender_key, true, vec![]).await.unwrap();
+        let mut reciever_ratchet = Ratchet::new(reciever_key, false, vec![]).await.unwrap();
+        reciever_ratchet.set_limits(SkipLimits{max_skip: 10, max_skipped: 3, max_age: 2});
+
+        let mut sent = vec![];
+        for id in 0..20 {
+            sent.push(sender_ratchet.process_payload(id, Transitable::from_readable(TEST_STRINGS[0])).await.unwrap());
+        }
+        //too far ahead of the chain
+        assert_code(reciever_ratchet.process_payload(11, sent[11].clone()).await, "RATCHET_STATE");
+
+        //only the three newest skipped keys are kept
+        reciever_ratchet.process_payload(5, sent[5].clone()).await.unwrap();
+        assert!(reciever_ratchet.skipped_len() == 3);
+        assert!(reciever_ratchet.process_payload(1, sent[1].clone()).await.err() == Some(AwakeError::Replay(1)));
+        reciever_ratchet.process_payload(4, sent[4].clone()).await.unwrap();
+
+        //skipped keys are dropped once too many messages have been processed after them
+        reciever_ratchet.process_payload(6, sent[6].clone()).await.unwrap();
+        reciever_ratchet.process_payload(7, sent[7].clone()).await.unwrap();
+        assert!(reciever_ratchet.skipped_len() == 0);
+        assert!(reciever_ratchet.process_payload(3, sent[3].clone()).await.err() == Some(AwakeError::Replay(3)));
+    });
+}
@@ -0,0 +1,323 @@
+//! Native tests for established sessions, run with cargo test
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use futures::executor::block_on;
+use serde_json::Value;
+
+use awake::utils::*;
+use awake::mid_window::MID_WINDOW;
+use awake::handshake::{Handshake, ChallengeType};
+use awake::transitable::Transitable;
+use awake::store::{SessionStore, MemorySessionStore};
+
+use common::*;
+
+#[test]
+fn can_send_session_messages(){
+    block_on(async {
+        let (mut requestor, mut responder) = established_sessions().await;
+
+        for payload in TEST_STRINGS {
+            assert_delivered(&mut requestor, &mut responder, payload).await;
+            assert_delivered(&mut responder, &mut requestor, payload).await;
+        }
+    });
+}
+#[test]
+fn can_keep_session_state_flat(){
+    block_on(async {
+        let (mut requestor, mut responder) = established_sessions().await;
+
+        //run with the default limits, by 100 messages the mid window has moved past everything it started with
+        let mut sizes = vec![];
+        let mut recieved = 0;
+        for checkpoint in [100, 10000] {
+            while recieved < checkpoint {
+                let sent = responder.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+                requestor.receive(sent).await.unwrap();
+                recieved += 1;
+            }
+            sizes.push(serde_json::to_vec(&requestor).unwrap().len());
+        }
+        //keys are json arrays so their length moves a little, a growing chain would add hundreds of bytes a message
+        assert!(sizes[1].abs_diff(sizes[0]) < 256);
+    });
+}
+#[test]
+fn can_recieve_late_messages_after_ratchet_step(){
+    block_on(async {
+        let (mut requestor, mut responder) = established_sessions().await;
+
+        //the first two messages are held back while the requestor's reply moves the ratchet on
+        let first = responder.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+        let second = responder.send(Transitable::from_readable(TEST_STRINGS[1])).await.unwrap();
+        let reply = requestor.send(Transitable::from_readable(TEST_STRINGS[2])).await.unwrap();
+        responder.receive(reply).await.unwrap();
+        assert_delivered(&mut responder, &mut requestor, TEST_STRINGS[2]).await;
+        assert!(requestor.receive(second.clone()).await.unwrap().as_readable().unwrap() == TEST_STRINGS[1]);
+        assert!(requestor.receive(first).await.unwrap().as_readable().unwrap() == TEST_STRINGS[0]);
+        //a mid is forgotten once its message has been read
+        assert_code(requestor.receive(second).await, "UNKNOWN_MID");
+    });
+}
+#[test]
+fn can_rekey_session(){
+    block_on(async {
+        let (mut requestor, mut responder) = established_sessions().await;
+        assert_delivered(&mut responder, &mut requestor, TEST_STRINGS[0]).await;
+        //the requestor's key confirmation is read before the copy is taken so only the rekey can stop it reading
+        assert_delivered(&mut requestor, &mut responder, TEST_STRINGS[1]).await;
+
+        //a message the responder sends before it sees the rekey stays on the old ratchet
+        let late = responder.send(Transitable::from_readable(TEST_STRINGS[1])).await.unwrap();
+        let mut stale = responder.clone();
+        let rekey = requestor.rekey().await.unwrap();
+        responder.accept_rekey(rekey).await.unwrap();
+
+        //the old ratchet keeps stepping until the first message on the new one arrives
+        stale.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+        let stale_sent = stale.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+        let mut unanswered = requestor.clone();
+        assert!(unanswered.receive(stale_sent.clone()).await.unwrap().as_readable().unwrap() == TEST_STRINGS[0]);
+
+        //either side can send straight after the rekey
+        assert_delivered(&mut responder, &mut requestor, TEST_STRINGS[2]).await;
+        let after = requestor.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+        assert!(responder.receive(after.clone()).await.unwrap().as_readable().unwrap() == TEST_STRINGS[0]);
+        assert!(requestor.receive(late).await.unwrap().as_readable().unwrap() == TEST_STRINGS[1]);
+        //after it only the messages the responder really sent on the old ratchet can be read
+        assert_code(requestor.receive(stale_sent).await, "DECRYPTION_FAILURE");
+
+        //the keys from before the rekey can not read anything sent after it
+        assert_code(stale.receive(after).await, "DECRYPTION_FAILURE");
+    });
+}
+#[test]
+fn can_keep_real_key_out_of_sessions(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let wrapping_key = [7; 32];
+        let mut handshaker_requestor = Handshake::new_resumable().await.unwrap();
+        let mut handshaker_responder = Handshake::new().await.unwrap();
+        run_handshake(&mut handshaker_requestor, &mut handshaker_responder, ChallengeType::None, "", |_| Ok(true)).await.unwrap();
+
+        //the saved handshake is the only place the requestor's real private key is written out
+        let saved = handshaker_requestor.export_state(&wrapping_key).await.unwrap();
+        let state:Value = serde_json::from_slice(&unwrap(&crypto, &wrapping_key, saved.as_slice()).await.unwrap()).unwrap();
+        let real_private:Value = serde_json::from_str(state["real_private"].as_str().unwrap()).unwrap();
+        let real_d = real_private["d"].as_str().unwrap().to_string();
+
+        let mut requestor = handshaker_requestor.into_session().unwrap();
+        let mut responder = handshaker_responder.into_session().unwrap();
+        assert!(!serde_json::to_string(&requestor).unwrap().contains(&real_d));
+        let rekey = responder.rekey().await.unwrap();
+        requestor.accept_rekey(rekey).await.unwrap();
+        assert_delivered(&mut requestor, &mut responder, TEST_STRINGS[0]).await;
+        assert!(!serde_json::to_string(&requestor).unwrap().contains(&real_d));
+    });
+}
+#[test]
+fn can_reject_tampered_envelope(){
+    block_on(async {
+        let (mut requestor, mut responder) = established_sessions().await;
+
+        let sent = requestor.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+        let mut tampered:Value = serde_json::from_str(&sent.as_readable().unwrap()).unwrap();
+        tampered["awv"] = Value::from("0.1.1");
+        let tampered = Transitable::from_readable(&tampered.to_string());
+        assert_code(responder.receive(tampered).await, "DECRYPTION_FAILURE");
+
+        //the untouched message still decrypts as the failed attempt does not use up its key
+        assert!(responder.receive(sent).await.unwrap().as_readable().unwrap() == TEST_STRINGS[0]);
+    });
+}
+#[test]
+fn can_check_message_version(){
+    block_on(async {
+        let (mut requestor, mut responder) = established_sessions().await;
+        let sent = requestor.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+
+        //a minor version can change the wire format so it is turned away before anything is decrypted
+        let mut newer:Value = serde_json::from_str(&sent.as_readable().unwrap()).unwrap();
+        newer["awv"] = Value::from("0.2.0");
+        let newer = Transitable::from_readable(&newer.to_string());
+        assert_code(responder.receive(newer).await, "UNSUPPORTED_VERSION");
+
+        //only a legacy awake/init may leave out its version
+        let mut unversioned:Value = serde_json::from_str(&sent.as_readable().unwrap()).unwrap();
+        unversioned.as_object_mut().unwrap().remove("awv");
+        let unversioned = Transitable::from_readable(&unversioned.to_string());
+        assert_code(responder.receive(unversioned).await, "MALFORMED_JSON");
+
+        let mut missing:Value = serde_json::from_str(&sent.as_readable().unwrap()).unwrap();
+        missing.as_object_mut().unwrap().remove("mid");
+        let missing = Transitable::from_readable(&missing.to_string());
+        assert_code(responder.receive(missing).await, "MALFORMED_JSON");
+
+        let mut handshaker = Handshake::new().await.unwrap();
+        assert_code(handshaker.accept_acknowledgement(sent.clone()).await, "BAD_JWT");
+        assert!(responder.receive(sent).await.unwrap().as_readable().unwrap() == TEST_STRINGS[0]);
+    });
+}
+#[test]
+fn can_resolve_mids(){
+    block_on(async {
+        let (mut requestor, mut responder) = established_sessions().await;
+        let mid_of = |message:&Transitable| -> String {
+            let message:Value = serde_json::from_str(&message.as_readable().unwrap()).unwrap();
+            message["mid"].as_str().unwrap().to_string()
+        };
+
+        //the responder has to read one message from the requestor before it has confirmed the session keys
+        let first = requestor.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+        responder.receive(first).await.unwrap();
+
+        //mids are found out of order and say nothing about the order they were sent in
+        let mut sent = vec![];
+        for plaintext in TEST_STRINGS.iter().take(3) {
+            sent.push(requestor.send(Transitable::from_readable(plaintext)).await.unwrap());
+        }
+        assert!(mid_of(&sent[0]) != mid_of(&sent[1]) && mid_of(&sent[1]) != mid_of(&sent[2]));
+        for id in [2, 0, 1] {
+            assert!(responder.receive(sent[id].clone()).await.unwrap().as_readable().unwrap() == TEST_STRINGS[id]);
+        }
+
+        //a mid that was never handed out is not looked for anywhere else
+        let mut unknown:Value = serde_json::from_str(&requestor.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap().as_readable().unwrap()).unwrap();
+        unknown["mid"] = Value::from(base64::encode([0u8; 32]));
+        let unknown = Transitable::from_readable(&unknown.to_string());
+        assert_code(responder.receive(unknown).await, "UNKNOWN_MID");
+
+        //the window starts at MID_WINDOW so a message further ahead is unknown
+        let mut sent = vec![];
+        for _ in 0..MID_WINDOW + 1 {
+            sent.push(requestor.send(Transitable::from_readable(TEST_STRINGS[1])).await.unwrap());
+        }
+        assert_code(responder.receive(sent.pop().unwrap()).await, "UNKNOWN_MID");
+
+        //but reaches as far ahead as the ratchet will skip once it is given limits
+        responder.set_skip_limits(1000, 1000, 10000).await.unwrap();
+        for _ in 0..100 {
+            sent.push(requestor.send(Transitable::from_readable(TEST_STRINGS[1])).await.unwrap());
+        }
+        assert!(responder.receive(sent.pop().unwrap()).await.unwrap().as_readable().unwrap() == TEST_STRINGS[1]);
+
+        //the skipped messages are still known behind the newest one
+        assert!(responder.receive(sent.remove(0)).await.unwrap().as_readable().unwrap() == TEST_STRINGS[1]);
+
+        //and shrinks with it, so a message further ahead than max_skip is unknown
+        responder.set_skip_limits(8, 1000, 10000).await.unwrap();
+        let mut last = None;
+        for _ in 0..9 {
+            last = Some(requestor.send(Transitable::from_readable(TEST_STRINGS[1])).await.unwrap());
+        }
+        assert_code(responder.receive(last.unwrap()).await, "UNKNOWN_MID");
+    });
+}
+#[test]
+fn can_confirm_session_keys(){
+    block_on(async {
+        let (mut requestor, mut responder) = established_sessions().await;
+        let read = |message:&Transitable| -> Value { serde_json::from_str(&message.as_readable().unwrap()).unwrap() };
+
+        //the requestor confirms the session keys on everything it sends until it hears from the responder
+        let first = requestor.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+        let second = requestor.send(Transitable::from_readable(TEST_STRINGS[1])).await.unwrap();
+        assert!(read(&first)["conf"].is_string() && read(&first)["conf"] == read(&second)["conf"]);
+        let reply = responder.send(Transitable::from_readable(TEST_STRINGS[2])).await.unwrap();
+        assert!(read(&reply).get("conf").is_none());
+
+        //the responder reads nothing until the keys have been confirmed
+        let mut missing = read(&first);
+        missing.as_object_mut().unwrap().remove("conf");
+        assert_code(responder.receive(Transitable::from_readable(&missing.to_string())).await, "KEY_CONFIRMATION_FAILURE");
+        let mut wrong = read(&first);
+        wrong["conf"] = Value::from(base64::encode([0u8; 32]));
+        assert_code(responder.receive(Transitable::from_readable(&wrong.to_string())).await, "KEY_CONFIRMATION_FAILURE");
+
+        //a copied confirmation on a message that does not decrypt confirms nothing
+        let mut forged = read(&first);
+        forged["msg"] = Value::from(base64::encode([0u8; 64]));
+        assert!(responder.receive(Transitable::from_readable(&forged.to_string())).await.is_err());
+        assert_code(responder.receive(Transitable::from_readable(&missing.to_string())).await, "KEY_CONFIRMATION_FAILURE");
+
+        //losing the first message does not lock the session, the second confirms the keys just as well
+        assert!(responder.receive(second).await.unwrap().as_readable().unwrap() == TEST_STRINGS[1]);
+        assert!(responder.receive(first).await.unwrap().as_readable().unwrap() == TEST_STRINGS[0]);
+        assert!(requestor.receive(reply).await.unwrap().as_readable().unwrap() == TEST_STRINGS[2]);
+        let third = requestor.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+        assert!(read(&third).get("conf").is_none());
+    });
+}
+#[test]
+fn can_reject_replayed_message(){
+    block_on(async {
+        let (mut requestor, mut responder) = established_sessions().await;
+
+        let sent = requestor.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+        responder.receive(sent.clone()).await.unwrap();
+        //only the mids of messages that have not arrived yet are known, so a replay can not even be found
+        assert_code(responder.receive(sent.clone()).await, "UNKNOWN_MID");
+
+        //the mid stays used up after the ratchet has stepped
+        let reply = responder.send(Transitable::from_readable(TEST_STRINGS[1])).await.unwrap();
+        requestor.receive(reply).await.unwrap();
+        let next = requestor.send(Transitable::from_readable(TEST_STRINGS[2])).await.unwrap();
+        responder.receive(next).await.unwrap();
+        assert_code(responder.receive(sent).await, "UNKNOWN_MID");
+    });
+}
+#[test]
+fn can_close_session_on_late_failure(){
+    block_on(async {
+        let crypto = fetch_backend().unwrap();
+        let (_, imposter_private) = gen_key_pair(&crypto, true).await.unwrap();
+        let mut handshaker_requestor = Handshake::new().await.unwrap();
+        let mut handshaker_responder = Handshake::new_resumable().await.unwrap();
+        let challenge = challenge_with(&mut handshaker_requestor, &mut handshaker_responder, ChallengeType::OobPin, "Arbitrary Pin").await.unwrap();
+        let ack = handshaker_responder.acknowledge_challenge_with(challenge, |_| Ok(true)).await.unwrap();
+
+        //the responder is done but the requestor can not read the acknowledgement, so it answers with a fin
+        let real_private = saved_private_key(&handshaker_responder, "real_private").await;
+        let responder_did = ack.kid().unwrap().unwrap();
+        let responder = handshaker_responder.into_session().unwrap();
+        let mut garbled:Value = serde_json::from_str(&ack.unsign().unwrap().as_readable().unwrap()).unwrap();
+        garbled["msg"] = Value::from(base64::encode([0u8; 64]));
+        let garbled = Transitable::from_readable(&garbled.to_string()).sign(&crypto, &real_private, Some(&responder_did)).await.unwrap();
+        assert_code(handshaker_requestor.accept_acknowledgement(garbled).await, "DECRYPTION_FAILURE");
+        let fin = handshaker_requestor.take_failure().unwrap();
+
+        //only the agent the session is with can close it
+        let forged = fin.unsign().unwrap().sign(&crypto, &imposter_private, None).await.unwrap();
+        assert_code(responder.read_failure(forged).await, "SIGNATURE_FAILURE");
+        let (_, other_responder) = established_sessions().await;
+        assert_code(other_responder.read_failure(fin.clone()).await, "UNEXPECTED_MESSAGE");
+        responder.read_failure(fin).await.unwrap();
+    });
+}
+#[test]
+fn can_store_sessions(){
+    block_on(async {
+        let store = MemorySessionStore::new();
+        let (handshaker_requestor, handshaker_responder) = complete_handshake("Arbitrary Pin").await.unwrap();
+        let (requestor_did, responder_did) = (handshaker_requestor.did(), handshaker_responder.did());
+        let (requestor, mut responder) = (handshaker_requestor.into_session().unwrap(), handshaker_responder.into_session().unwrap());
+
+        //both sides keep the session under the other's real did, not the step 4 did it sends from
+        assert!(requestor.peer_did() == responder_did && requestor.did() != responder_did);
+        assert!(responder.peer_did() == requestor_did);
+        store.put(&requestor).await.unwrap();
+        assert!(store.list().await.unwrap() == vec![responder_did.clone()]);
+
+        //the stored session picks up the ratchet where it was left
+        let mut requestor = store.get(&responder_did).await.unwrap().unwrap();
+        assert_delivered(&mut requestor, &mut responder, TEST_STRINGS[0]).await;
+
+        store.delete(&responder_did).await.unwrap();
+        assert!(store.get(&responder_did).await.unwrap().is_none());
+        assert!(store.list().await.unwrap().is_empty());
+    });
+}
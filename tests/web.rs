@@ -11,9 +11,13 @@ use wasm_bindgen::JsValue;
 use std::str;
 
 use awake::utils::*;
-use awake::handshake::Handshake;
+use awake::backend::CryptoBackend;
+use awake::handshake::{Handshake, FinReason, ChallengeType};
 use awake::transitable::Transitable;
 use awake::ratchet::Ratchet;
+use awake::router::{Router, RouterEventKind};
+use awake::store::IndexedDbSessionStore;
+use awake::error::AwakeError;
 use wasm_bindgen_test::*;
 use quickcheck_macros::quickcheck;
 use web_sys::console;
@@ -44,7 +48,7 @@ fn can_convert_transitable_base58(s:String) -> bool{
     //log("Converting to base 58..");
     let base_58 = Transitable::from_readable(&s).as_base58();
     //log("Converting from base 58..");
-    let s_mod = Transitable::from_base58(&base_58).as_readable().unwrap();
+    let s_mod = Transitable::from_base58(&base_58).unwrap().as_readable().unwrap();
     //log(&format!("Base 58 looks like:{} from {}", s, base_58));
     s == s_mod
 }
@@ -52,7 +56,7 @@ fn can_convert_transitable_base58(s:String) -> bool{
 #[quickcheck]
 fn can_convert_transitable_base64(s:String) -> bool{
     let base_64 = Transitable::from_readable(&s).as_base64();
-    let s_mod = Transitable::from_base64(&base_64).as_readable().unwrap();
+    let s_mod = Transitable::from_base64(&base_64).unwrap().as_readable().unwrap();
     s == s_mod
 }
 #[wasm_bindgen_test]
@@ -69,13 +73,24 @@ fn can_convert_transitable_bytes(s:String) -> bool{
 }
 #[wasm_bindgen_test]
 async fn can_convert_to_did(){
-    let crypto = fetch_subtle_crypto();
-    let (key, _) = gen_key_pair(&crypto, true).await;
-    let did = crypto_key_to_did_key(&crypto, &key).await;
-    let new_key = did_key_to_crypto_key(&crypto, &did).await;
-    let new_did = crypto_key_to_did_key(&crypto, &new_key).await;
+    let crypto = fetch_backend().unwrap();
+    let (key, _) = gen_key_pair(&crypto, true).await.unwrap();
+    let did = crypto_key_to_did_key(&crypto, &key).await.unwrap();
+    let new_key = did_key_to_crypto_key(&crypto, &did).await.unwrap();
+    let new_did = crypto_key_to_did_key(&crypto, &new_key).await.unwrap();
     assert!(did == new_did);
 }
+#[wasm_bindgen_test]
+async fn can_read_published_did_keys(){
+    let crypto = fetch_backend().unwrap();
+    //the compressed point has to be decompressed before webcrypto will import it
+    let did = "did:key:zDnaerDaTF5BXEavCrfRZEk316dpbLsfPDZ3WJ5hRTPFU2169";
+    let key = did_key_to_crypto_key(&crypto, did).await.unwrap();
+    let point = crypto.export_public_key(&key).await.unwrap();
+    assert!(point[1..33] == base64::decode_config("fyNYMN0976ci7xqiSdag3buk-ZCwgXU4kz9XNkBlNUI", base64::URL_SAFE_NO_PAD).unwrap());
+    assert!(point[33..] == base64::decode_config("hW2ojTNfH7Jbi8--CJUo3OCbH3y5n91g-IMA9MLMbTU", base64::URL_SAFE_NO_PAD).unwrap());
+    assert!(crypto_key_to_did_key(&crypto, &key).await.unwrap() == did);
+}
 /*
 Integration Tests
 // */
@@ -90,11 +105,19 @@ async fn can_sign(){
 }
 
 async fn can_sign_func(payload:&str) -> bool{
-    let crypto = fetch_subtle_crypto();
-    let (public_key, private_key) = gen_key_pair(&crypto, true).await;
+    let crypto = fetch_backend().unwrap();
+    let (public_key, private_key) = gen_key_pair(&crypto, true).await.unwrap();
 
-    let data = Transitable::from_readable(payload).sign(&crypto, &private_key).await;
-    return data.verify(&crypto, &public_key).await;
+    let data = Transitable::from_readable(payload).sign(&crypto, &private_key, None).await.unwrap();
+    return data.verify(&crypto, &public_key).await.unwrap();
+}
+#[wasm_bindgen_test]
+async fn can_sign_with_non_extractable_key(){
+    let crypto = fetch_backend().unwrap();
+    let (public_key, private_key) = gen_key_pair(&crypto, false).await.unwrap();
+    let data = Transitable::from_readable(TEST_STRINGS[0]).sign(&crypto, &private_key, None).await.unwrap();
+    assert!(data.verify(&crypto, &public_key).await.unwrap());
+    assert!(crypto.export_private_key(&private_key).await.is_err());
 }
 #[wasm_bindgen_test]
 async fn can_unsign(){
@@ -106,11 +129,11 @@ async fn can_unsign(){
     assert!(true);
 }
 async fn can_unsign_func(payload:&str) -> bool{
-    let crypto = fetch_subtle_crypto();
-    let (_, private_key) = gen_key_pair(&crypto, true).await;
+    let crypto = fetch_backend().unwrap();
+    let (_, private_key) = gen_key_pair(&crypto, true).await.unwrap();
 
-    let data = Transitable::from_readable(payload).sign(&crypto, &private_key).await;
-    return data.unsign().as_readable().unwrap() == payload.to_string();
+    let data = Transitable::from_readable(payload).sign(&crypto, &private_key, None).await.unwrap();
+    return data.unsign().unwrap().as_readable().unwrap() == payload.to_string();
 }
 #[wasm_bindgen_test]
 async fn can_fail_sign(){
@@ -123,25 +146,25 @@ async fn can_fail_sign(){
 }
 
 async fn can_fail_sign_func(payload:&str) -> bool{
-    let crypto = fetch_subtle_crypto();
-    let (public_key_imposter, _) = gen_key_pair(&crypto, true).await;
-    let (_, private_key) = gen_key_pair(&crypto, true).await;
+    let crypto = fetch_backend().unwrap();
+    let (public_key_imposter, _) = gen_key_pair(&crypto, true).await.unwrap();
+    let (_, private_key) = gen_key_pair(&crypto, true).await.unwrap();
 
-    let data = Transitable::from_readable(payload).sign(&crypto, &private_key).await;
-    return !data.verify(&crypto, &public_key_imposter).await;
+    let data = Transitable::from_readable(payload).sign(&crypto, &private_key, None).await.unwrap();
+    return !data.verify(&crypto, &public_key_imposter).await.unwrap();
 }
 async fn can_rachet_crypto_func(text_in:&str, id:usize, salt_str:&str) -> bool{
     let salt = salt_str.as_bytes().to_vec();
-    let crypto = fetch_subtle_crypto();
+    let crypto = fetch_backend().unwrap();
 
-    let (sender_public, sender_private) = gen_key_pair(&crypto, false).await;
-    let (reciever_public, reciever_private) = gen_key_pair(&crypto, false).await;
+    let (sender_public, sender_private) = gen_key_pair(&crypto, false).await.unwrap();
+    let (reciever_public, reciever_private) = gen_key_pair(&crypto, false).await.unwrap();
 
-    let sender_key = diffie_helman(&crypto, &sender_private, &reciever_public).await;
-    let reciever_key = diffie_helman(&crypto, &reciever_private, &sender_public).await;
+    let sender_key = diffie_helman(&crypto, &sender_private, &reciever_public).await.unwrap();
+    let reciever_key = diffie_helman(&crypto, &reciever_private, &sender_public).await.unwrap();
     
-    let mut sender_ratchet = Ratchet::new(sender_key, true, salt.clone()).await;
-    let mut reciever_ratchet = Ratchet::new(reciever_key, false, salt.clone()).await;
+    let mut sender_ratchet = Ratchet::new(sender_key, true, salt.clone()).await.unwrap();
+    let mut reciever_ratchet = Ratchet::new(reciever_key, false, salt.clone()).await.unwrap();
 
     let text_in_vec = Transitable::from_readable(text_in);
     let sent_message = sender_ratchet.process_payload(id, text_in_vec).await.unwrap();
@@ -168,14 +191,215 @@ async fn can_rachet_crypto() {
 
 #[wasm_bindgen_test]
 async fn can_handler_return(){
-    let mut handshaker_requestor = Handshake::new().await;
-    let mut handshaker_responder = Handshake::new().await;
+    let mut handshaker_requestor = Handshake::new().await.unwrap();
+    let mut handshaker_responder = Handshake::new().await.unwrap();
 
-    let request = handshaker_requestor.request(Array::new()).await;
+    let request = handshaker_requestor.request(Array::new()).await.unwrap();
     log(&request.as_readable().unwrap());
-    let response = handshaker_responder.reponse(request, Array::new(), 60, Function::new_no_args("return true")).await.unwrap();
+    let response = handshaker_responder.reponse(request, Array::new(), 60, Function::new_no_args("return true"), None).await.unwrap();
     log(&response.as_readable().unwrap());
     let challenge = handshaker_requestor.challenge_response(response, "Arbitrary Pin", Function::new_no_args("return true")).await.unwrap();
     log(&challenge.as_readable().unwrap());
     assert!(true);
-}
\ No newline at end of file
+}
+#[wasm_bindgen_test]
+async fn can_complete_handshake(){
+    let mut handshaker_requestor = Handshake::new().await.unwrap();
+    let mut handshaker_responder = Handshake::new().await.unwrap();
+
+    let request = handshaker_requestor.request(Array::new()).await.unwrap();
+    let response = handshaker_responder.reponse(request, Array::new(), 60, Function::new_no_args("return true"), None).await.unwrap();
+    let challenge = handshaker_requestor.challenge_response(response, "Arbitrary Pin", Function::new_no_args("return true")).await.unwrap();
+    let ack = handshaker_responder.acknowledge_challenge(challenge, Function::new_no_args("return true")).await.unwrap();
+    log(&ack.as_readable().unwrap());
+    assert!(handshaker_responder.is_done());
+    assert!(handshaker_requestor.accept_acknowledgement(ack).await.is_ok());
+    assert!(handshaker_requestor.is_done());
+}
+
+async fn complete_handshake() -> (Handshake, Handshake){
+    let mut handshaker_requestor = Handshake::new().await.unwrap();
+    let mut handshaker_responder = Handshake::new().await.unwrap();
+
+    let request = handshaker_requestor.request(Array::new()).await.unwrap();
+    let response = handshaker_responder.reponse(request, Array::new(), 60, Function::new_no_args("return true"), None).await.unwrap();
+    let challenge = handshaker_requestor.challenge_response(response, "Arbitrary Pin", Function::new_no_args("return true")).await.unwrap();
+    let ack = handshaker_responder.acknowledge_challenge(challenge, Function::new_no_args("return true")).await.unwrap();
+    handshaker_requestor.accept_acknowledgement(ack).await.unwrap();
+    return (handshaker_requestor, handshaker_responder);
+}
+#[wasm_bindgen_test]
+async fn can_send_session_messages(){
+    let (handshaker_requestor, handshaker_responder) = complete_handshake().await;
+    let mut requestor = handshaker_requestor.into_session().unwrap();
+    let mut responder = handshaker_responder.into_session().unwrap();
+
+    for payload in TEST_STRINGS {
+        let sent = requestor.send(Transitable::from_readable(payload)).await.unwrap();
+        log(&sent.as_readable().unwrap());
+        let recieved = responder.receive(sent).await.unwrap();
+        assert!(recieved.as_readable().unwrap() == payload.to_string());
+
+        let reply = responder.send(Transitable::from_readable(payload)).await.unwrap();
+        let recieved_reply = requestor.receive(reply).await.unwrap();
+        assert!(recieved_reply.as_readable().unwrap() == payload.to_string());
+    }
+}
+
+async fn new_router() -> Router{
+    return Router::new(
+        Array::new(),
+        60,
+        None,
+        Array::new(),
+        Function::new_no_args("return true"),
+        Function::new_no_args("return true"),
+        Function::new_no_args("return true"),
+        Function::new_no_args("return 'Arbitrary Pin'")
+    ).await.unwrap();
+}
+#[wasm_bindgen_test]
+async fn can_route_messages(){
+    let mut requestor = new_router().await;
+    let mut responder = new_router().await;
+
+    let request = requestor.request().await.unwrap();
+    let response = responder.route(request).await.unwrap();
+    assert!(response.kind() == RouterEventKind::Responded);
+    let challenge = requestor.route(response.reply().unwrap()).await.unwrap();
+    assert!(challenge.kind() == RouterEventKind::Challenged);
+    let ack = responder.route(challenge.reply().unwrap()).await.unwrap();
+    assert!(ack.kind() == RouterEventKind::Acknowledged);
+    assert!(responder.is_established());
+    let established = requestor.route(ack.reply().unwrap()).await.unwrap();
+    assert!(established.kind() == RouterEventKind::Established);
+    assert!(requestor.is_established());
+
+    let sent = requestor.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+    let recieved = responder.route(sent).await.unwrap();
+    assert!(recieved.kind() == RouterEventKind::Message);
+    assert!(recieved.payload().unwrap().as_readable().unwrap() == TEST_STRINGS[0].to_string());
+
+    //a message that can not be read is an error, one that can but is of no awake type is passed out
+    let garbage = responder.route(Transitable::from_readable("not an awake message")).await;
+    assert!(garbage.err().unwrap().code() == "MALFORMED_JSON");
+    let unknown = responder.route(Transitable::from_readable(r#"{"awv":"0.1.0","type":"awake/unknown"}"#)).await.unwrap();
+    assert!(unknown.kind() == RouterEventKind::Unrecognized);
+}
+#[wasm_bindgen_test]
+async fn can_route_with_challenge(){
+    let mut requestor = new_router().await;
+    //no pin would pass but with no challenge set none is asked for
+    let mut responder = Router::new(
+        Array::new(),
+        60,
+        Some(ChallengeType::None),
+        Array::new(),
+        Function::new_no_args("return true"),
+        Function::new_no_args("return true"),
+        Function::new_no_args("return false"),
+        Function::new_no_args("return 'Arbitrary Pin'")
+    ).await.unwrap();
+
+    let request = requestor.request().await.unwrap();
+    let response = responder.route(request).await.unwrap();
+    let challenge = requestor.route(response.reply().unwrap()).await.unwrap();
+    let ack = responder.route(challenge.reply().unwrap()).await.unwrap();
+    assert!(ack.kind() == RouterEventKind::Acknowledged);
+    assert!(requestor.route(ack.reply().unwrap()).await.unwrap().kind() == RouterEventKind::Established);
+
+    //proofs are checked when the router is made
+    let proofs = Array::of1(&JsValue::from("not a ucan"));
+    let bad_proofs = Router::new(
+        Array::new(),
+        60,
+        None,
+        proofs,
+        Function::new_no_args("return true"),
+        Function::new_no_args("return true"),
+        Function::new_no_args("return true"),
+        Function::new_no_args("return 'Arbitrary Pin'")
+    ).await;
+    assert!(bad_proofs.err().unwrap().code() == "BAD_UCAN");
+}
+#[wasm_bindgen_test]
+async fn can_route_failures(){
+    let mut requestor = new_router().await;
+    let mut responder = Router::new(
+        Array::new(),
+        60,
+        None,
+        Array::new(),
+        Function::new_no_args("return true"),
+        Function::new_no_args("return true"),
+        Function::new_no_args("return false"),
+        Function::new_no_args("return 'Arbitrary Pin'")
+    ).await.unwrap();
+
+    let request = requestor.request().await.unwrap();
+    let response = responder.route(request).await.unwrap();
+    let challenge = requestor.route(response.reply().unwrap()).await.unwrap();
+    let rejected = responder.route(challenge.reply().unwrap()).await.unwrap();
+    assert!(rejected.kind() == RouterEventKind::Rejected);
+    assert!(rejected.reason() == Some(FinReason::ChallengeRejected));
+    let fin = rejected.reply().unwrap();
+    let failed = requestor.route(fin.clone()).await.unwrap();
+    assert!(failed.kind() == RouterEventKind::Failed);
+    assert!(failed.reason() == Some(FinReason::ChallengeRejected));
+
+    //the responder has been forgotten so the same fin again is rejected without a reply
+    let replayed = requestor.route(fin).await.unwrap();
+    assert!(replayed.kind() == RouterEventKind::Rejected);
+    assert!(replayed.reply().is_none());
+}
+#[wasm_bindgen_test]
+async fn can_reject_pin(){
+    let mut handshaker_requestor = Handshake::new().await.unwrap();
+    let mut handshaker_responder = Handshake::new().await.unwrap();
+
+    let request = handshaker_requestor.request(Array::new()).await.unwrap();
+    let response = handshaker_responder.reponse(request, Array::new(), 60, Function::new_no_args("return true"), None).await.unwrap();
+    let challenge = handshaker_requestor.challenge_response(response, "Wrong Pin", Function::new_no_args("return true")).await.unwrap();
+    let result = handshaker_responder.acknowledge_challenge(challenge, Function::new_with_args("pin", "return pin == 'Arbitrary Pin'")).await;
+    assert!(result.err() == Some(AwakeError::PinRejected));
+    assert!(!handshaker_responder.is_done());
+}
+#[wasm_bindgen_test]
+fn can_fail_bad_did(){
+    assert!(did_key_to_bytes("did:key:z0OIl").err().unwrap().code() == "BAD_DID_KEY");
+    //valid base58 but not under the P-256 multicodec
+    assert!(did_key_to_bytes("did:key:zQ3notnist256").err().unwrap().code() == "UNSUPPORTED_DID_KEY");
+    assert!(Transitable::from_readable("not.a jwt").unsign().err().unwrap().code() == "BAD_JWT");
+}
+#[wasm_bindgen_test]
+async fn can_resume_handshake(){
+    let wrapping_key = [7; 32];
+    assert!(Handshake::new().await.unwrap().export_state(&wrapping_key).await.err().unwrap().code() == "BAD_STATE");
+    let mut handshaker_requestor = Handshake::new().await.unwrap();
+    let mut handshaker_responder = Handshake::new_resumable().await.unwrap();
+
+    let request = handshaker_requestor.request(Array::new()).await.unwrap();
+    let response = handshaker_responder.reponse(request, Array::new(), 60, Function::new_no_args("return true"), None).await.unwrap();
+    let saved = handshaker_responder.export_state(&wrapping_key).await.unwrap();
+    let mut handshaker_responder = Handshake::import_state(saved, &wrapping_key).await.unwrap();
+    let challenge = handshaker_requestor.challenge_response(response, "Arbitrary Pin", Function::new_no_args("return true")).await.unwrap();
+    let ack = handshaker_responder.acknowledge_challenge(challenge, Function::new_no_args("return true")).await.unwrap();
+    handshaker_requestor.accept_acknowledgement(ack).await.unwrap();
+    assert!(handshaker_responder.is_done());
+}
+#[wasm_bindgen_test]
+async fn can_store_sessions_in_indexed_db(){
+    let store = IndexedDbSessionStore::open("awake-test", &[7; 32]).await.unwrap();
+    let (handshaker_requestor, handshaker_responder) = complete_handshake().await;
+    let responder_did = handshaker_responder.did();
+    let requestor = handshaker_requestor.into_session().unwrap();
+    let mut responder = handshaker_responder.into_session().unwrap();
+    assert!(requestor.peer_did() == responder_did);
+    store.put(&requestor).await.unwrap();
+
+    let mut requestor = store.get(&responder_did).await.unwrap().unwrap();
+    let sent = requestor.send(Transitable::from_readable(TEST_STRINGS[0])).await.unwrap();
+    assert!(responder.receive(sent).await.unwrap().as_readable().unwrap() == TEST_STRINGS[0].to_string());
+    store.delete(&responder_did).await.unwrap();
+    assert!(store.get(&responder_did).await.unwrap().is_none());
+}